@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use tempdir::TempDir;
+
+use kivi::core::{config::Config, kv::KiviStore};
+
+fn pairs(n: usize) -> impl Iterator<Item = (String, String)> {
+    (0..n).map(|i| (format!("key{:08}", i), format!("value{:08}", i)))
+}
+
+fn open() -> (KiviStore, TempDir) {
+    let dir = TempDir::new("bench").unwrap();
+    let store = KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build())
+        .unwrap();
+
+    (store, dir)
+}
+
+fn bulk_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+    group.sample_size(10);
+
+    for n in [1_000, 10_000] {
+        group.throughput(Throughput::Elements(n as u64));
+
+        group.bench_with_input(BenchmarkId::new("set", n), &n, |b, &n| {
+            b.iter_batched(
+                open,
+                |(mut store, _dir)| {
+                    for (key, value) in pairs(n) {
+                        store.set(key, value).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+
+        group.bench_with_input(BenchmarkId::new("bulk_load", n), &n, |b, &n| {
+            b.iter_batched(
+                open,
+                |(mut store, _dir)| store.bulk_load(pairs(n)).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bulk_load);
+criterion_main!(benches);
@@ -0,0 +1,77 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::net::TcpListener;
+use tempdir::TempDir;
+
+use kivi::client::Connection;
+use kivi::core::{config::Config, kv::KiviStore};
+use kivi::protocol::Request;
+use kivi::server::{KiviServer, ServerConfig};
+
+const CLIENTS: usize = 16;
+const REQUESTS: usize = 100;
+
+/// The server runs until the bench exits.
+fn start(workers: usize) -> (String, TempDir) {
+    let dir = TempDir::new("bench").unwrap();
+    let store = KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build())
+        .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let mut server = KiviServer::with_store(store);
+    server.set_config(ServerConfig {
+        workers,
+        ..ServerConfig::default()
+    });
+    std::thread::spawn(move || server.run_with_listener(listener));
+
+    (addr, dir)
+}
+
+/// Every client opens a connection and sends its requests one at a time.
+fn run_clients(addr: &str) {
+    let threads: Vec<_> = (0..CLIENTS)
+        .map(|c| {
+            let addr = addr.to_string();
+
+            std::thread::spawn(move || {
+                let mut connection = Connection::open(&addr).unwrap();
+
+                for i in 0..REQUESTS {
+                    let key = format!("c{}:{}", c, i % 10);
+                    connection
+                        .call(&Request::Set {
+                            key: key.clone(),
+                            value: i.to_string(),
+                        })
+                        .unwrap();
+                    connection.call(&Request::Get { key }).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+fn server_pool(c: &mut Criterion) {
+    let mut group = c.benchmark_group("workers");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((CLIENTS * REQUESTS * 2) as u64));
+
+    for workers in [1, 4, 16] {
+        let (addr, _dir) = start(workers);
+
+        group.bench_with_input(BenchmarkId::from_parameter(workers), &addr, |b, addr| {
+            b.iter(|| run_clients(addr))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, server_pool);
+criterion_main!(benches);
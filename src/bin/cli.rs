@@ -1,5 +1,16 @@
-use clap::{Arg, Command};
-use kivi::core::{error::Result, kv::KiviStore};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::fs::File;
+use std::path::PathBuf;
+
+use kivi::core::{
+    backup::{self, BackupManifest},
+    config::Config,
+    crypto::EncryptionKey,
+    error::Result,
+    export::{self, Format, ImportMode},
+    inspect::{self, RecordInfo},
+    kv::KiviStore,
+};
 
 fn initialize_logger() {
     let env = env_logger::Env::default()
@@ -12,12 +23,23 @@ fn initialize_logger() {
 fn main() -> Result<()> {
     initialize_logger();
 
-    let mut ks = match KiviStore::new() {
-        Ok(ks) => ks,
-        Err(e) => return Err(e),
-    };
-
     let m = Command::new("kivi")
+        .args([
+            Arg::new("encryption-key-file")
+                .long("encryption-key-file")
+                .env("KIVI_ENCRYPTION_KEY_FILE")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true)
+                .help("Key the db is encrypted with, 32 bytes or 64 hex characters"),
+            Arg::new("previous-encryption-key-file")
+                .long("previous-encryption-key-file")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .global(true)
+                .help("Key only used to read records until compaction, can be repeated"),
+        ])
         .subcommand(
             Command::new("set")
                 .args([
@@ -31,9 +53,102 @@ fn main() -> Result<()> {
                 .arg(Arg::new("KEY").required(true))
                 .about("Gets a value by key"),
         )
+        .subcommand(
+            Command::new("incr")
+                .arg(Arg::new("KEY").required(true))
+                .about("Increments an integer value by one"),
+        )
+        .subcommand(
+            Command::new("decr")
+                .arg(Arg::new("KEY").required(true))
+                .about("Decrements an integer value by one"),
+        )
+        .subcommand(
+            Command::new("incrby")
+                .args([
+                    Arg::new("KEY").required(true),
+                    Arg::new("DELTA")
+                        .required(true)
+                        .allow_negative_numbers(true)
+                        .value_parser(clap::value_parser!(i64)),
+                ])
+                .about("Adds a delta to an integer value"),
+        )
         .subcommand(Command::new("compact").about("Compacts db"))
+        .subcommand(Command::new("stats").about("Shows key count, file sizes and dead bytes"))
+        .subcommand(
+            Command::new("backup")
+                .arg(
+                    Arg::new("DIR")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .about("Copies the db and a checksum manifest to an empty directory"),
+        )
+        .subcommand(
+            Command::new("restore")
+                .arg(
+                    Arg::new("DIR")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .about("Verifies a backup and replaces the db with it"),
+        )
+        .subcommand(
+            Command::new("export")
+                .args([
+                    Arg::new("format")
+                        .long("format")
+                        .default_value("jsonl")
+                        .value_parser(["jsonl", "csv"]),
+                    Arg::new("prefix").long("prefix").default_value(""),
+                    Arg::new("output")
+                        .long("output")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                ])
+                .about("Writes all key/value pairs as JSON Lines or CSV, to stdout by default"),
+        )
+        .subcommand(
+            Command::new("import")
+                .args([
+                    Arg::new("format")
+                        .long("format")
+                        .default_value("jsonl")
+                        .value_parser(["jsonl", "csv"]),
+                    Arg::new("mode")
+                        .long("mode")
+                        .default_value("overwrite")
+                        .value_parser(["overwrite", "skip-existing"]),
+                    Arg::new("FILE").value_parser(clap::value_parser!(PathBuf)),
+                ])
+                .about("Loads key/value pairs written by export, from stdin by default"),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .args([
+                    Arg::new("FILE")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                    Arg::new("json")
+                        .long("json")
+                        .action(clap::ArgAction::SetTrue),
+                ])
+                .about("Decodes a data file record by record"),
+        )
         .get_matches();
 
+    let config = store_config(&m)?;
+
+    // The db must not be open while it is replaced
+    if let Some(("restore", m)) = m.subcommand() {
+        let dir = m.get_one::<PathBuf>("DIR").unwrap();
+
+        print_manifest(&backup::restore(dir, &config)?);
+        return Ok(());
+    }
+
+    let mut ks = KiviStore::with_config(config)?;
+
     match m.subcommand() {
         Some(("set", m)) => {
             // We can unwrap here as they are both required
@@ -54,11 +169,133 @@ fn main() -> Result<()> {
                 }
             }
         }
+        Some(("incr", m)) => {
+            let key = m.get_one::<String>("KEY").unwrap().to_owned();
+
+            println!("Value: {}", ks.incr(key)?);
+        }
+        Some(("decr", m)) => {
+            let key = m.get_one::<String>("KEY").unwrap().to_owned();
+
+            println!("Value: {}", ks.decr(key)?);
+        }
+        Some(("incrby", m)) => {
+            let key = m.get_one::<String>("KEY").unwrap().to_owned();
+            let delta = *m.get_one::<i64>("DELTA").unwrap();
+
+            println!("Value: {}", ks.incr_by(key, delta)?);
+        }
         Some(("compact", _)) => {
             ks.compact()?;
         }
+        Some(("stats", _)) => {
+            println!("{}", ks.stats()?);
+        }
+        Some(("backup", m)) => {
+            let dir = m.get_one::<PathBuf>("DIR").unwrap();
+
+            print_manifest(&backup::backup(&mut ks, dir)?);
+        }
+        Some(("export", m)) => {
+            let format: Format = m.get_one::<String>("format").unwrap().parse()?;
+            let prefix = m.get_one::<String>("prefix").unwrap();
+
+            let count = match m.get_one::<PathBuf>("output") {
+                Some(path) => export::export(&ks, File::create(path)?, format, prefix)?,
+                None => export::export(&ks, std::io::stdout().lock(), format, prefix)?,
+            };
+
+            log::info!("Exported {} pairs", count);
+        }
+        Some(("import", m)) => {
+            let format: Format = m.get_one::<String>("format").unwrap().parse()?;
+            let mode: ImportMode = m.get_one::<String>("mode").unwrap().parse()?;
+
+            let stats = match m.get_one::<PathBuf>("FILE") {
+                Some(path) => export::import(&mut ks, File::open(path)?, format, mode)?,
+                None => export::import(&mut ks, std::io::stdin().lock(), format, mode)?,
+            };
+
+            println!("Imported: {}, skipped: {}", stats.imported, stats.skipped);
+        }
+        Some(("inspect", m)) => {
+            let path = m.get_one::<PathBuf>("FILE").unwrap();
+            let records = inspect::inspect(&ks, path)?;
+
+            if m.get_flag("json") {
+                println!("{}", serde_json::to_string_pretty(&records)?);
+            } else {
+                print_records(&records);
+            }
+        }
         _ => {}
     }
 
     Ok(())
 }
+
+fn print_records(records: &[RecordInfo]) {
+    println!(
+        "{:>10} {:>8} {:>8}  {:<11} {:<8} {:<8}  RECORD",
+        "OFFSET", "SIZE", "SEQ", "TYPE", "CHECKSUM", "SHADOWED"
+    );
+
+    for r in records {
+        let seq = r.seq.map(|s| s.to_string()).unwrap_or_default();
+        let kind = serde_json::to_value(r.kind).unwrap_or_default();
+        let checksum = if r.checksum_ok { "ok" } else { "bad" };
+        let shadowed = match r.shadowed {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "-",
+        };
+
+        let mut record = match (&r.bucket, &r.key) {
+            (Some(b), Some(k)) if !b.is_empty() => format!("{}/{}", b, k),
+            (_, Some(k)) => k.clone(),
+            (Some(b), None) => b.clone(),
+            (None, None) => String::new(),
+        };
+        if let Some(v) = &r.value_preview {
+            record.push_str(&format!(" = {:?}", v));
+        }
+        if let Some(e) = &r.error {
+            record.push_str(e);
+        }
+
+        println!(
+            "{:>10} {:>8} {:>8}  {:<11} {:<8} {:<8}  {}",
+            r.offset,
+            r.size,
+            seq,
+            kind.as_str().unwrap_or_default(),
+            checksum,
+            shadowed,
+            record
+        );
+    }
+}
+
+fn print_manifest(manifest: &BackupManifest) {
+    println!("Last seq: {}", manifest.last_seq);
+
+    for file in &manifest.files {
+        println!("{} {} {}", file.sha256, file.size, file.name);
+    }
+}
+
+/// Default store configuration, with the encryption keys given as flags.
+fn store_config(m: &ArgMatches) -> Result<Config> {
+    let mut config = Config::new();
+
+    if let Some(path) = m.get_one::<PathBuf>("encryption-key-file") {
+        config.set_encryption_key(EncryptionKey::from_file(path)?);
+    }
+    if let Some(paths) = m.get_many::<PathBuf>("previous-encryption-key-file") {
+        config.set_previous_encryption_keys(
+            paths.map(EncryptionKey::from_file).collect::<Result<_>>()?,
+        );
+    }
+
+    Ok(config.build())
+}
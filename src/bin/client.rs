@@ -1,26 +1,100 @@
-use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::str;
+
+use kivi::client::Credentials;
+use kivi::core::error::Result;
+use kivi::protocol::{codec, Request, Response};
+use kivi::stream::Stream;
+
+const ADDR: &str = "127.0.0.1:7878";
 
 fn main() {
-    let conn = TcpStream::connect("127.0.0.1:7878");
     let args = std::env::args().collect::<Vec<String>>();
 
-    // Skip first argument
-    let s = args[1..].join(" ");
+    // Skip first argument, every other one is a word of the command, so quoted values
+    // can hold spaces
+    let request = match Request::parse(&args[1..]) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Invalid command: {}", e);
+            return;
+        }
+    };
 
-    match conn {
-        Ok(mut stream) => {
+    match TcpStream::connect(ADDR) {
+        Ok(socket) => {
             println!("Connected to server");
 
-            stream.write_all(s.as_bytes()).unwrap();
-
-            let mut buf = Vec::new();
-            stream.read_to_end(&mut buf).expect("failed to read to end");
-            println!("Response: {:?}", str::from_utf8(&buf).unwrap());
+            if let Err(e) = secure(socket).and_then(|mut stream| run(&mut stream, &request)) {
+                println!("Error: {}", e);
+            }
         }
         Err(e) => {
             println!("Could not connect: {}", e);
         }
     }
 }
+
+/// Over TLS when `KIVI_TLS_CA` names the PEM file of the authority that signed the
+/// server certificate. `KIVI_TLS_CERT` and `KIVI_TLS_KEY` add a client certificate.
+fn secure(socket: TcpStream) -> Result<Stream> {
+    #[cfg(feature = "tls")]
+    if let Ok(ca) = std::env::var("KIVI_TLS_CA") {
+        let identity = match (
+            std::env::var("KIVI_TLS_CERT"),
+            std::env::var("KIVI_TLS_KEY"),
+        ) {
+            (Ok(cert), Ok(key)) => Some((cert, key)),
+            _ => None,
+        };
+
+        let tls = kivi::tls::ClientTls::from_pem_files(
+            ca.as_ref(),
+            identity.as_ref().map(|(c, k)| (c.as_ref(), k.as_ref())),
+        )?;
+
+        return tls.connect(ADDR, socket);
+    }
+
+    Ok(Stream::Plain(socket))
+}
+
+fn run(stream: &mut Stream, request: &Request) -> Result<()> {
+    codec::handshake(stream)?;
+
+    if let Some(credentials) = credentials() {
+        codec::write_request(stream, &credentials.request())?;
+
+        match codec::read_response(stream)? {
+            Some(Response::Ok) => {}
+            response => {
+                println!("Could not log in: {:?}", response);
+                return Ok(());
+            }
+        }
+    }
+
+    codec::write_request(stream, request)?;
+
+    // Subscriptions keep answering until the connection is closed
+    while let Some(response) = codec::read_response(stream)? {
+        println!("Response: {:?}", response);
+
+        if !matches!(response, Response::Event(_)) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read from `KIVI_TOKEN`, or `KIVI_USER` and `KIVI_PASSWORD`.
+fn credentials() -> Option<Credentials> {
+    if let Ok(token) = std::env::var("KIVI_TOKEN") {
+        return Some(Credentials::Token(token));
+    }
+
+    match (std::env::var("KIVI_USER"), std::env::var("KIVI_PASSWORD")) {
+        (Ok(user), Ok(password)) => Some(Credentials::Password { user, password }),
+        _ => None,
+    }
+}
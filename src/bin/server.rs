@@ -1,23 +1,364 @@
-use std::net::SocketAddr;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::path::PathBuf;
 
-use kivi::server;
+use kivi::cluster::{ClusterConfig, Member};
+use kivi::core::{
+    config::SyncPolicy,
+    error::{KiviError, Result},
+    kv::KiviStore,
+};
+use kivi::server::{self, ServerHandle, ServerSettings};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-fn initialize_logger() {
+fn initialize_logger(level: &str) {
     let env = env_logger::Env::default()
-        .filter_or("MY_LOG_LEVEL", "trace")
+        .default_filter_or(level)
         .write_style_or("MY_LOG_STYLE", "always");
 
     env_logger::init_from_env(env);
 }
 
 fn main() {
-    initialize_logger();
+    let m = Command::new("kivi-server")
+        .args([
+            Arg::new("config")
+                .long("config")
+                .env("KIVI_CONFIG")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("TOML file with the server settings, flags take precedence"),
+            Arg::new("print-config")
+                .long("print-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the effective settings and exit"),
+            Arg::new("addr")
+                .long("addr")
+                .env("KIVI_ADDR")
+                .help("Address to listen on [default: 0.0.0.0:7878]"),
+            Arg::new("db-path")
+                .long("db-path")
+                .env("KIVI_DB_PATH")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Directory of the store [default: ./db]"),
+            Arg::new("data-extension")
+                .long("data-extension")
+                .env("KIVI_DATA_EXTENSION")
+                .help("Extension of the data files [default: log]"),
+            Arg::new("max-file-size")
+                .long("max-file-size")
+                .env("KIVI_MAX_FILE_SIZE")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64))
+                .help("Rotate the active data file at this size [default: 67108864]"),
+            Arg::new("sync-policy")
+                .long("sync-policy")
+                .env("KIVI_SYNC_POLICY")
+                .value_parser(clap::value_parser!(SyncPolicy))
+                .help("When writes are flushed to disk: never, always or an interval like 1s [default: never]"),
+            Arg::new("log-level")
+                .long("log-level")
+                .env("KIVI_LOG_LEVEL")
+                .help("Filter of the logger, like info or kivi=debug [default: info]"),
+            Arg::new("replica-of")
+                .long("replica-of")
+                .value_name("PRIMARY")
+                .help("Run as a read-only replica of the primary at this address")
+                .conflicts_with("cluster"),
+            Arg::new("cluster")
+                .long("cluster")
+                .value_name("MEMBERS")
+                .requires("node-id")
+                .help("Comma separated CLIENT_ADDR/RAFT_ADDR of every cluster node"),
+            Arg::new("node-id")
+                .long("node-id")
+                .value_parser(clap::value_parser!(usize))
+                .help("Position of this node in --cluster"),
+            Arg::new("workers")
+                .long("workers")
+                .env("KIVI_WORKERS")
+                .value_parser(clap::value_parser!(usize))
+                .help("Number of connections served at the same time [default: 32]"),
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .env("KIVI_IDLE_TIMEOUT")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Close connections idle for this long, 0 to keep them open [default: 300]"),
+            Arg::new("max-request-size")
+                .long("max-request-size")
+                .env("KIVI_MAX_REQUEST_SIZE")
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(usize))
+                .help("Refuse requests larger than this [default: 16777216]"),
+            Arg::new("shutdown-timeout")
+                .long("shutdown-timeout")
+                .env("KIVI_SHUTDOWN_TIMEOUT")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Wait this long for connections to finish on shutdown [default: 30]"),
+            Arg::new("backup-root")
+                .long("backup-root")
+                .env("KIVI_BACKUP_ROOT")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Directory the backup command writes under, backups are refused without it"),
+            Arg::new("encryption-key-file")
+                .long("encryption-key-file")
+                .env("KIVI_ENCRYPTION_KEY_FILE")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Encrypt the store with the key in this file, 32 bytes or 64 hex characters"),
+            Arg::new("previous-encryption-key-file")
+                .long("previous-encryption-key-file")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .help("Key only used to read records until compaction, can be repeated"),
+            Arg::new("tls-cert")
+                .long("tls-cert")
+                .env("KIVI_TLS_CERT")
+                .value_name("PEM")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls-key")
+                .help("Serve over TLS with this certificate chain"),
+            Arg::new("tls-key")
+                .long("tls-key")
+                .env("KIVI_TLS_KEY")
+                .value_name("PEM")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Private key of --tls-cert"),
+            Arg::new("tls-client-ca")
+                .long("tls-client-ca")
+                .env("KIVI_TLS_CLIENT_CA")
+                .value_name("PEM")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Only accept clients with a certificate signed by this authority"),
+        ])
+        .args(async_args())
+        .get_matches();
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 7878));
+    let settings = or_exit(effective_settings(&m));
 
-    let mut s = server::KiviServer::new().unwrap();
+    if m.get_flag("print-config") {
+        print!("{}", settings);
+        return;
+    }
+
+    initialize_logger(&settings.log_level);
+
+    let (mut s, addr) = or_exit(open_server(&m, &settings));
+
+    s.set_config(settings.server_config());
+    or_exit(set_tls(&mut s, &settings));
+    or_exit(handle_signals(s.server_handle(), m.clone(), settings));
 
     log::info!("Server listening at {:?}", addr);
 
-    s.run(addr).unwrap();
+    #[cfg(feature = "async")]
+    if m.get_flag("async") {
+        let runtime = or_exit(tokio::runtime::Runtime::new().map_err(KiviError::from));
+        let server = server::AsyncKiviServer::new(s);
+
+        or_exit(runtime.block_on(server.run(addr)));
+        return;
+    }
+
+    or_exit(s.run(addr));
+}
+
+/// Prints the error and exits, for errors the server cannot start or run with.
+fn or_exit<T>(result: Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Opens the store in the role given by the flags. Returns the server and the address
+/// it listens on.
+fn open_server(m: &ArgMatches, settings: &ServerSettings) -> Result<(server::KiviServer, String)> {
+    if let Some(members) = m.get_one::<String>("cluster") {
+        let members = parse_members(members)?;
+        let id = *m
+            .get_one::<usize>("node-id")
+            .ok_or_else(|| KiviError::Generic("--cluster needs --node-id".to_string()))?;
+
+        // Cluster nodes serve clients on their member address
+        let addr = members
+            .get(id)
+            .ok_or_else(|| {
+                KiviError::Generic(format!(
+                    "--node-id {} is out of range, --cluster has {} members",
+                    id,
+                    members.len()
+                ))
+            })?
+            .client_addr
+            .clone();
+
+        let store = KiviStore::with_config(settings.store_config()?)?;
+        let s = server::KiviServer::cluster(store, ClusterConfig::new(id, members))?;
+
+        return Ok((s, addr));
+    }
+
+    let s = match m.get_one::<String>("replica-of") {
+        Some(primary) => server::KiviServer::replica(primary, settings.store_config()?)?,
+        None => server::KiviServer::with_store(KiviStore::with_config(settings.store_config()?)?),
+    };
+
+    Ok((s, settings.addr.clone()))
+}
+
+/// Defaults, then the config file, then environment variables and flags.
+fn effective_settings(m: &ArgMatches) -> Result<ServerSettings> {
+    let mut s = match m.get_one::<PathBuf>("config") {
+        Some(path) => ServerSettings::load(path)
+            .map_err(|e| KiviError::Generic(format!("could not load {}: {}", path.display(), e)))?,
+        None => ServerSettings::default(),
+    };
+
+    if let Some(addr) = m.get_one::<String>("addr") {
+        s.addr = addr.clone();
+    }
+    if let Some(db_path) = m.get_one::<PathBuf>("db-path") {
+        s.db_path = db_path.clone();
+    }
+    if let Some(ext) = m.get_one::<String>("data-extension") {
+        s.data_extension = ext.clone();
+    }
+    if let Some(size) = m.get_one::<u64>("max-file-size") {
+        s.max_file_size = *size;
+    }
+    if let Some(policy) = m.get_one::<SyncPolicy>("sync-policy") {
+        s.sync_policy = *policy;
+    }
+    if let Some(level) = m.get_one::<String>("log-level") {
+        s.log_level = level.clone();
+    }
+    if let Some(workers) = m.get_one::<usize>("workers") {
+        s.workers = *workers;
+    }
+    if let Some(timeout) = m.get_one::<u64>("idle-timeout") {
+        s.idle_timeout = *timeout;
+    }
+    if let Some(size) = m.get_one::<usize>("max-request-size") {
+        s.max_request_size = *size;
+    }
+    if let Some(timeout) = m.get_one::<u64>("shutdown-timeout") {
+        s.shutdown_timeout = *timeout;
+    }
+    if let Some(root) = m.get_one::<PathBuf>("backup-root") {
+        s.backup_root = Some(root.clone());
+    }
+    if let Some(key) = m.get_one::<PathBuf>("encryption-key-file") {
+        s.encryption_key_file = Some(key.clone());
+    }
+    if let Some(keys) = m.get_many::<PathBuf>("previous-encryption-key-file") {
+        s.previous_encryption_key_files = keys.cloned().collect();
+    }
+    if let Some(cert) = m.get_one::<PathBuf>("tls-cert") {
+        s.tls_cert = Some(cert.clone());
+    }
+    if let Some(key) = m.get_one::<PathBuf>("tls-key") {
+        s.tls_key = Some(key.clone());
+    }
+    if let Some(ca) = m.get_one::<PathBuf>("tls-client-ca") {
+        s.tls_client_ca = Some(ca.clone());
+    }
+
+    Ok(s)
+}
+
+/// SIGINT and SIGTERM shut the server down once its connections are drained, SIGHUP
+/// reloads the settings.
+fn handle_signals(handle: ServerHandle, m: ArgMatches, settings: ServerSettings) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+
+    std::thread::spawn(move || {
+        let mut settings = settings;
+
+        for signal in signals.forever() {
+            if signal != SIGHUP {
+                log::info!("Received signal {}, shutting down", signal);
+                handle.shutdown();
+                continue;
+            }
+
+            match reload(&handle, &m, &settings) {
+                Ok(reloaded) => settings = reloaded,
+                Err(e) => log::error!("Error: could not reload the settings: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Reads the settings again and applies the ones that can change while running.
+fn reload(handle: &ServerHandle, m: &ArgMatches, old: &ServerSettings) -> Result<ServerSettings> {
+    let new = effective_settings(m)?;
+
+    for name in old.restart_only_changes(&new) {
+        log::warn!(
+            "Changing {} needs a restart, keeping the current value",
+            name
+        );
+    }
+
+    // Restart-only settings keep their value, so later reloads still warn about them
+    let reloaded = old.reloaded(&new);
+    handle.reload(&reloaded)?;
+    log::info!("Settings reloaded");
+
+    Ok(reloaded)
+}
+
+/// Only available when built with the `tls` feature.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn set_tls(s: &mut server::KiviServer, settings: &ServerSettings) -> Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = settings.server_tls()? {
+        s.set_tls(tls);
+    }
+
+    #[cfg(not(feature = "tls"))]
+    if settings.tls_cert.is_some() || settings.tls_key.is_some() || settings.tls_client_ca.is_some()
+    {
+        return Err(KiviError::Generic(
+            "TLS is not enabled, rebuild with the tls feature".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Only offered when built with the `async` feature.
+fn async_args() -> Vec<Arg> {
+    #[cfg(feature = "async")]
+    return vec![Arg::new("async")
+        .long("async")
+        .action(ArgAction::SetTrue)
+        .help("Serve connections on a tokio runtime instead of worker threads")];
+
+    #[cfg(not(feature = "async"))]
+    Vec::new()
+}
+
+fn parse_members(s: &str) -> Result<Vec<Member>> {
+    s.split(',')
+        .map(|m| match m.split_once('/') {
+            Some((client, raft)) if !client.is_empty() && !raft.is_empty() => {
+                Ok(Member::new(client, raft))
+            }
+            _ => Err(KiviError::Generic(format!(
+                "invalid cluster member {}, expected CLIENT_ADDR/RAFT_ADDR",
+                m
+            ))),
+        })
+        .collect()
 }
@@ -0,0 +1,126 @@
+use futures::{SinkExt, StreamExt};
+use std::io::ErrorKind;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use super::{expect_ok, unexpected, Credentials};
+use crate::core::{
+    error::{KiviError, Result},
+    kv::KeyValue,
+};
+use crate::protocol::{
+    framed::{self, ClientCodec},
+    Request, Response,
+};
+
+/// Client of the binary protocol for tokio, over a single connection. Requests are
+/// answered in order, see [`AsyncKiviClient::pipeline`].
+pub struct AsyncKiviClient {
+    connection: Framed<TcpStream, ClientCodec>,
+}
+
+impl AsyncKiviClient {
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        framed::handshake(&mut stream).await?;
+
+        Ok(Self {
+            connection: Framed::new(stream, ClientCodec),
+        })
+    }
+
+    /// Connects and logs in.
+    pub async fn connect_as(addr: &str, credentials: &Credentials) -> Result<Self> {
+        let mut client = Self::connect(addr).await?;
+
+        client
+            .call(&credentials.request())
+            .await
+            .and_then(expect_ok)?;
+
+        Ok(client)
+    }
+
+    /// Sends a request. Errors and redirects sent by the server are turned into
+    /// `KiviError`s.
+    pub async fn call(&mut self, request: &Request) -> Result<Response> {
+        match self
+            .pipeline(std::slice::from_ref(request))
+            .await?
+            .remove(0)
+        {
+            Response::Redirect(leader) => Err(KiviError::NotLeader(Some(leader))),
+            Response::Error(message) => Err(KiviError::Generic(message)),
+            Response::Unauthorized(message) => Err(KiviError::Unauthorized(message)),
+            response => Ok(response),
+        }
+    }
+
+    /// Sends every request at once, then reads their responses. Errors sent by the
+    /// server are returned as responses, so one failed request does not hide the
+    /// others.
+    pub async fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        for request in requests {
+            self.connection.feed(request).await?;
+        }
+        self.connection.flush().await?;
+
+        let mut responses = Vec::with_capacity(requests.len());
+        for _ in requests {
+            match self.connection.next().await {
+                Some(response) => responses.push(response?),
+                None => return Err(KiviError::Io(ErrorKind::UnexpectedEof.into())),
+            }
+        }
+
+        Ok(responses)
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self
+            .call(&Request::Get {
+                key: key.to_string(),
+            })
+            .await?
+        {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let request = Request::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+
+        self.call(&request).await.and_then(expect_ok)
+    }
+
+    pub async fn delete(&mut self, key: &str) -> Result<()> {
+        match self
+            .call(&Request::Delete {
+                key: key.to_string(),
+            })
+            .await?
+        {
+            Response::NotFound => Err(KiviError::KeyNotFound(key.to_string())),
+            response => expect_ok(response),
+        }
+    }
+
+    /// All keys starting with `prefix`, sorted by key.
+    pub async fn scan(&mut self, prefix: &str) -> Result<Vec<KeyValue>> {
+        match self
+            .call(&Request::Scan {
+                prefix: prefix.to_string(),
+            })
+            .await?
+        {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+}
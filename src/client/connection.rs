@@ -0,0 +1,105 @@
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::net::TcpStream;
+
+use super::{unexpected, Credentials};
+use crate::core::error::{KiviError, Result};
+use crate::protocol::{codec, Request, Response};
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::tls::ClientTls;
+
+/// An open connection of the binary protocol. Requests are answered in order, so
+/// several of them can be sent before reading any response.
+pub struct Connection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+}
+
+impl Connection {
+    pub fn open(addr: &str) -> Result<Self> {
+        Self::start(Stream::Plain(connect(addr)?))
+    }
+
+    /// Opens a connection over TLS, see [`ClientTls::connect`].
+    #[cfg(feature = "tls")]
+    pub fn open_tls(addr: &str, tls: &ClientTls) -> Result<Self> {
+        Self::start(tls.connect(addr, connect(addr)?)?)
+    }
+
+    /// Opens a connection and logs in.
+    pub fn open_as(addr: &str, credentials: &Credentials) -> Result<Self> {
+        let mut connection = Self::open(addr)?;
+        connection.login(credentials)?;
+
+        Ok(connection)
+    }
+
+    fn start(mut stream: Stream) -> Result<Self> {
+        codec::handshake(&mut stream)?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    pub fn login(&mut self, credentials: &Credentials) -> Result<()> {
+        match self.call(&credentials.request())? {
+            Response::Ok => Ok(()),
+            Response::Unauthorized(message) => Err(KiviError::Unauthorized(message)),
+            Response::Error(message) => Err(KiviError::Generic(message)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// True if the server closed the connection, like it does with idle ones, or sent
+    /// anything while no request was waiting. The server never writes unasked, so such
+    /// a connection cannot be used any more.
+    pub fn is_stale(&self) -> bool {
+        if !self.reader.buffer().is_empty() {
+            return true;
+        }
+
+        let socket = self.reader.get_ref().socket();
+        if socket.set_nonblocking(true).is_err() {
+            return true;
+        }
+
+        let idle = matches!(socket.peek(&mut [0]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+
+        socket.set_nonblocking(false).is_err() || !idle
+    }
+
+    pub fn call(&mut self, request: &Request) -> Result<Response> {
+        let mut responses = self.pipeline(std::slice::from_ref(request))?;
+
+        Ok(responses.remove(0))
+    }
+
+    /// Sends every request at once, then reads their responses. Errors sent by the
+    /// server are returned as responses, so one failed request does not hide the
+    /// others.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        for request in requests {
+            codec::write_request(&mut self.writer, request)?;
+        }
+        self.writer.flush()?;
+
+        let mut responses = Vec::with_capacity(requests.len());
+        for _ in requests {
+            match codec::read_response(&mut self.reader)? {
+                Some(response) => responses.push(response),
+                None => return Err(KiviError::Io(ErrorKind::UnexpectedEof.into())),
+            }
+        }
+
+        Ok(responses)
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let socket = TcpStream::connect(addr)?;
+    socket.set_nodelay(true)?;
+
+    Ok(socket)
+}
@@ -0,0 +1,828 @@
+//! Client side of the TCP protocols spoken by `KiviServer`.
+//!
+//! Typed requests use the framed binary protocol of `protocol::codec`, like
+//! `src/bin/client.rs` does, over a connection kept open between requests. Raw
+//! commands use the text protocol and a connection each. With the `async` feature,
+//! `AsyncKiviClient` speaks the binary protocol from tokio tasks.
+
+#[cfg(feature = "async")]
+mod async_client;
+mod connection;
+mod sharding;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncKiviClient;
+pub use connection::Connection;
+pub use sharding::{HashRing, ShardedClient, DEFAULT_VIRTUAL_NODES};
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use crate::core::{
+    error::{KiviError, Result},
+    kv::KeyValue,
+};
+use crate::protocol::{Request, Response};
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::tls::ClientTls;
+
+/// How a client logs in to a server that has users configured.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    Password { user: String, password: String },
+    Token(String),
+}
+
+impl Credentials {
+    pub fn request(&self) -> Request {
+        match self {
+            Credentials::Password { user, password } => Request::Auth {
+                user: Some(user.clone()),
+                secret: password.clone(),
+            },
+            Credentials::Token(token) => Request::Auth {
+                user: None,
+                secret: token.clone(),
+            },
+        }
+    }
+}
+
+/// Client of a single server. Clones share the same connection.
+#[derive(Clone)]
+pub struct KiviClient {
+    addr: String,
+
+    /// Sent on every new connection
+    credentials: Option<Credentials>,
+
+    /// Connections are opened over TLS once set
+    #[cfg(feature = "tls")]
+    tls: Option<ClientTls>,
+
+    /// Opened by the first request, None again after an error
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl std::fmt::Debug for KiviClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KiviClient")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl PartialEq for KiviClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
+    }
+}
+
+impl KiviClient {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Client logging in on every connection it opens. Raw text commands cannot log in.
+    pub fn with_credentials(addr: &str, credentials: Credentials) -> Self {
+        Self {
+            credentials: Some(credentials),
+            ..Self::new(addr)
+        }
+    }
+
+    /// Opens every connection over TLS, raw text commands included. Connections
+    /// already open are kept.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: ClientTls) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Sends a request over the binary protocol. Errors and redirects sent by the
+    /// server are turned into `KiviError`s.
+    pub fn call(&self, request: &Request) -> Result<Response> {
+        match self.pipeline(std::slice::from_ref(request))?.remove(0) {
+            Response::Redirect(leader) => Err(KiviError::NotLeader(Some(leader))),
+            Response::Error(message) => Err(KiviError::Generic(message)),
+            Response::Unauthorized(message) => Err(KiviError::Unauthorized(message)),
+            response => Ok(response),
+        }
+    }
+
+    /// Sends every request before reading the responses, see [`Connection::pipeline`].
+    pub fn pipeline(&self, requests: &[Request]) -> Result<Vec<Response>> {
+        let mut cached = self
+            .connection
+            .lock()
+            .map_err(|_| KiviError::Generic("connection lock poisoned".to_string()))?;
+
+        // A kept connection may have been closed by the server while idle, it is
+        // replaced before sending anything. Once requests are sent they are never sent
+        // again, the server may have applied some of them before failing.
+        let mut connection = match cached.take() {
+            Some(connection) if !connection.is_stale() => connection,
+            _ => {
+                let mut connection = self.open()?;
+                if let Some(credentials) = &self.credentials {
+                    connection.login(credentials)?;
+                }
+                connection
+            }
+        };
+
+        let responses = connection.pipeline(requests)?;
+
+        // The server closes the connection after some errors, like a request over the
+        // size limit, the next requests get a new one
+        if !responses.iter().any(|r| matches!(r, Response::Error(_))) {
+            *cached = Some(connection);
+        }
+
+        Ok(responses)
+    }
+
+    /// Sends a raw command of the text protocol and returns the raw response. Errors
+    /// and redirects sent by the server are turned into `KiviError`s.
+    pub fn request(&self, command: &str) -> Result<String> {
+        let mut stream = self.connect()?;
+        stream.write_all(command.as_bytes())?;
+        stream.flush()?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf)?;
+
+        let response = String::from_utf8(buf)
+            .map_err(|_| KiviError::Generic("response is not valid UTF-8".to_string()))?;
+
+        if let Some(leader) = response.strip_prefix("Redirect: ") {
+            return Err(KiviError::NotLeader(Some(leader.to_string())));
+        }
+
+        if let Some(message) = response.strip_prefix("Error: Unauthorized: ") {
+            return Err(KiviError::Unauthorized(message.to_string()));
+        }
+
+        if let Some(message) = response.strip_prefix("Error: ") {
+            return Err(KiviError::Generic(message.to_string()));
+        }
+
+        Ok(response)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return Connection::open_tls(&self.addr, tls);
+        }
+
+        Connection::open(&self.addr)
+    }
+
+    fn connect(&self) -> Result<Stream> {
+        let socket = TcpStream::connect(&self.addr)?;
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.connect(&self.addr, socket);
+        }
+
+        Ok(Stream::Plain(socket))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.call(&Request::Get {
+            key: key.to_string(),
+        })? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        let request = Request::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+
+        self.call(&request).and_then(expect_ok)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        match self.call(&Request::Delete {
+            key: key.to_string(),
+        })? {
+            Response::NotFound => Err(KiviError::KeyNotFound(key.to_string())),
+            response => expect_ok(response),
+        }
+    }
+
+    /// Deletes `key` only if it still holds `expected`. Returns whether it was deleted.
+    pub fn delete_if_equals(&self, key: &str, expected: &str) -> Result<bool> {
+        match self.call(&Request::DeleteIfEquals {
+            key: key.to_string(),
+            expected: expected.to_string(),
+        })? {
+            Response::Applied(applied) => Ok(applied),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// All keys starting with `prefix`, sorted by key.
+    pub fn scan(&self, prefix: &str) -> Result<Vec<KeyValue>> {
+        match self.call(&Request::Scan {
+            prefix: prefix.to_string(),
+        })? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn expect_ok(response: Response) -> Result<()> {
+    match response {
+        Response::Ok => Ok(()),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: Response) -> KiviError {
+    KiviError::Generic(format!("unexpected response: {:?}", response))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::core::{config::Config, kv::KiviStore};
+    use crate::server::{KiviServer, ServerConfig, ServerHandle, ServerSettings, User};
+    use sha2::{Digest, Sha256};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+    use tempdir::TempDir;
+
+    /// Starts a server on a random port, the directory has to outlive it.
+    pub(crate) fn start_server() -> (KiviClient, TempDir) {
+        start_server_with(ServerConfig::default())
+    }
+
+    pub(crate) fn start_server_with(config: ServerConfig) -> (KiviClient, TempDir) {
+        let (client, dir, _) = start_server_with_handle(config);
+
+        (client, dir)
+    }
+
+    fn start_server_with_handle(config: ServerConfig) -> (KiviClient, TempDir, ServerHandle) {
+        let dir = TempDir::new("client").unwrap();
+        let store =
+            KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build())
+                .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let mut server = KiviServer::with_store(store);
+        server.set_config(config);
+        let handle = server.server_handle();
+        std::thread::spawn(move || server.run_with_listener(listener));
+
+        (KiviClient::new(&addr), dir, handle)
+    }
+
+    #[test]
+    fn test_client_operations() {
+        let (client, _dir) = start_server();
+
+        assert_eq!(client.get("a").unwrap(), None);
+
+        client.set("a", "1").unwrap();
+        client.set("ab", "2").unwrap();
+        client.set("b", "3").unwrap();
+        assert_eq!(client.get("a").unwrap().as_deref(), Some("1"));
+
+        let keys: Vec<String> = client
+            .scan("a")
+            .unwrap()
+            .into_iter()
+            .map(|kv| kv.key)
+            .collect();
+        assert_eq!(keys, vec!["a", "ab"]);
+        assert_eq!(client.scan("").unwrap().len(), 3);
+
+        client.delete("a").unwrap();
+        assert_eq!(client.get("a").unwrap(), None);
+        assert!(matches!(client.delete("a"), Err(KiviError::KeyNotFound(_))));
+
+        let info = client.request("INFO").unwrap();
+        assert!(info.starts_with("key_count: 2\ndata_files: 1\n"));
+        assert!(info.contains("compactions: 0\nlast_compaction: never"));
+    }
+
+    #[test]
+    fn test_binary_safe_values() {
+        let (client, _dir) = start_server();
+
+        let spaces = "a value with spaces\nand a newline";
+        let large = "x".repeat(64 * 1024);
+        client.set("key with spaces", spaces).unwrap();
+        client.set("large", &large).unwrap();
+
+        assert_eq!(
+            client.get("key with spaces").unwrap().as_deref(),
+            Some(spaces)
+        );
+        assert_eq!(client.get("large").unwrap(), Some(large));
+
+        // Both protocols are served on the same port
+        assert_eq!(
+            client
+                .call(&Request::IncrBy {
+                    key: "n".to_string(),
+                    delta: 5
+                })
+                .unwrap(),
+            Response::Integer(5)
+        );
+        assert_eq!(client.request("incr n").unwrap(), "Value: 6");
+        assert!(matches!(
+            client.call(&Request::IncrBy {
+                key: "large".to_string(),
+                delta: 1
+            }),
+            Err(KiviError::Generic(_))
+        ));
+    }
+
+    #[test]
+    fn test_text_responses() {
+        let (client, _dir) = start_server();
+
+        client.set("a", "1").unwrap();
+        client.set("a", "2").unwrap();
+        assert_eq!(client.request("get a").unwrap(), "Key: a, Value: 2");
+
+        // Every command is answered, failed ones with an error
+        for (command, error) in [
+            ("get missing", "Key not found: missing"),
+            ("del missing", "Key not found: missing"),
+            (
+                "get a b",
+                "Protocol error: wrong number of arguments for get",
+            ),
+            ("frob a", "Protocol error: unknown command frob"),
+            ("incrby a x", "Protocol error: invalid delta for incrby"),
+        ] {
+            match client.request(command) {
+                Err(KiviError::Generic(message)) => assert_eq!(message, error),
+                res => panic!("unexpected {:?} for {}", res, command),
+            }
+        }
+
+        let mut stream = TcpStream::connect(client.addr()).unwrap();
+        stream.write_all(&[b'g', 0xff, 0xfe]).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(
+            response,
+            "Error: Protocol error: command is not valid UTF-8"
+        );
+
+        assert_eq!(client.request("compact").unwrap(), "OK");
+        assert_eq!(client.call(&Request::Compact).unwrap(), Response::Ok);
+        assert_eq!(client.get("a").unwrap().as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let (client, _dir) = start_server();
+        let mut connection = Connection::open(client.addr()).unwrap();
+
+        let mut requests = Vec::new();
+        for i in 0..100 {
+            requests.push(Request::IncrBy {
+                key: "n".to_string(),
+                delta: 1,
+            });
+            requests.push(Request::Set {
+                key: format!("k{}", i),
+                value: i.to_string(),
+            });
+        }
+        requests.push(Request::Get {
+            key: "k42".to_string(),
+        });
+        requests.push(Request::Delete {
+            key: "missing".to_string(),
+        });
+
+        let responses = connection.pipeline(&requests).unwrap();
+        assert_eq!(responses.len(), requests.len());
+        for i in 0..100 {
+            assert_eq!(responses[2 * i], Response::Integer(i as i64 + 1));
+            assert_eq!(responses[2 * i + 1], Response::Ok);
+        }
+        assert_eq!(responses[200], Response::Value("42".to_string()));
+        assert_eq!(responses[201], Response::NotFound);
+
+        // Other clients are served while the connection stays open
+        assert_eq!(client.get("k1").unwrap().as_deref(), Some("1"));
+        assert_eq!(
+            connection
+                .call(&Request::Get {
+                    key: "n".to_string()
+                })
+                .unwrap(),
+            Response::Value("100".to_string())
+        );
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let (client, _dir) = start_server_with(ServerConfig {
+            idle_timeout: Duration::from_millis(100),
+            max_request_size: 1024,
+            ..ServerConfig::default()
+        });
+
+        client.set("a", "1").unwrap();
+        let mut connection = Connection::open(client.addr()).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+
+        // Closed by the server, the client reconnects on its own
+        let request = Request::Get {
+            key: "a".to_string(),
+        };
+        assert!(connection.call(&request).is_err());
+        assert_eq!(client.get("a").unwrap().as_deref(), Some("1"));
+
+        match client.set("b", &"x".repeat(2048)) {
+            Err(KiviError::Generic(message)) => assert!(message.contains("over the limit")),
+            res => panic!("unexpected {:?}", res),
+        }
+        client.set("b", "small").unwrap();
+    }
+
+    #[test]
+    fn test_watcher_leaving_is_unregistered() {
+        let (client, _dir) = start_server();
+        let write_hooks = || match client.call(&Request::Info).unwrap() {
+            Response::Text(info) => info
+                .lines()
+                .find_map(|l| l.strip_prefix("write_hooks: "))
+                .unwrap()
+                .to_string(),
+            response => panic!("unexpected {:?}", response),
+        };
+
+        let mut watcher = TcpStream::connect(client.addr()).unwrap();
+        watcher.write_all(b"watch never:*").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while write_hooks() != "1" && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(write_hooks(), "1");
+
+        // No key it watches is ever written
+        drop(watcher);
+        client.set("other", "1").unwrap();
+
+        while write_hooks() != "0" && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(write_hooks(), "0");
+    }
+
+    #[test]
+    fn test_backup_root() {
+        let backup = |dir: &str| Request::Backup {
+            dir: dir.to_string(),
+        };
+
+        let (disabled, _dir) = start_server();
+        assert!(matches!(
+            disabled.call(&backup("b")),
+            Err(KiviError::Generic(message)) if message.contains("disabled")
+        ));
+
+        let root = TempDir::new("backups").unwrap();
+        let (client, _dir) = start_server_with(ServerConfig {
+            backup_root: Some(root.path().to_path_buf()),
+            ..ServerConfig::default()
+        });
+        client.set("a", "1").unwrap();
+
+        for dir in ["/tmp/escape", "../escape", "a/../../escape", "./a", ""] {
+            assert!(client.call(&backup(dir)).is_err(), "{}", dir);
+        }
+
+        assert!(matches!(
+            client.call(&backup("nightly/1")).unwrap(),
+            Response::Text(_)
+        ));
+        assert!(root
+            .path()
+            .join("nightly/1")
+            .join(crate::core::backup::MANIFEST_FILE)
+            .exists());
+    }
+
+    /// Forwards connections to `upstream`. The first one is cut once its first response
+    /// was forwarded and the server answered everything.
+    fn start_cutting_proxy(upstream: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            for (i, client) in listener.incoming().enumerate() {
+                let mut client = client.unwrap();
+                let mut server = TcpStream::connect(&upstream).unwrap();
+
+                let (mut from_client, mut to_server) =
+                    (client.try_clone().unwrap(), server.try_clone().unwrap());
+                std::thread::spawn(move || std::io::copy(&mut from_client, &mut to_server));
+
+                if i > 0 {
+                    std::thread::spawn(move || std::io::copy(&mut server, &mut client));
+                    continue;
+                }
+
+                let mut greeting = [0; 5];
+                server.read_exact(&mut greeting).unwrap();
+                client.write_all(&greeting).unwrap();
+
+                // Responses are length-prefixed frames
+                let mut len = [0; 4];
+                server.read_exact(&mut len).unwrap();
+                let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+                server.read_exact(&mut frame).unwrap();
+                client.write_all(&len).unwrap();
+                client.write_all(&frame).unwrap();
+
+                // The second response, the server applied both requests
+                server.read_exact(&mut len).unwrap();
+                client.shutdown(std::net::Shutdown::Both).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_pipeline_not_resent() {
+        let (direct, _dir) = start_server();
+        let client = KiviClient::new(&start_cutting_proxy(direct.addr().to_string()));
+
+        let incr = Request::IncrBy {
+            key: "n".to_string(),
+            delta: 1,
+        };
+        assert!(client.pipeline(&[incr.clone(), incr.clone()]).is_err());
+        assert_eq!(direct.get("n").unwrap().as_deref(), Some("2"));
+
+        // The next request opens a new connection
+        assert_eq!(client.call(&incr).unwrap(), Response::Integer(3));
+    }
+
+    #[test]
+    fn test_parallel_clients() {
+        let (client, _dir) = start_server_with(ServerConfig {
+            workers: 4,
+            ..ServerConfig::default()
+        });
+
+        let threads: Vec<_> = (0..16)
+            .map(|t| {
+                // Separate connections, clones would share one
+                let client = KiviClient::new(client.addr());
+
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        client.set(&format!("t{}:{}", t, i), "x").unwrap();
+                        client
+                            .call(&Request::IncrBy {
+                                key: "counter".to_string(),
+                                delta: 1,
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(client.get("counter").unwrap().as_deref(), Some("800"));
+        assert_eq!(client.scan("t").unwrap().len(), 800);
+    }
+
+    #[test]
+    fn test_slow_client_does_not_block_others() {
+        let (client, _dir) = start_server_with(ServerConfig {
+            workers: 2,
+            ..ServerConfig::default()
+        });
+
+        // Connected, but never sends anything
+        let _silent = TcpStream::connect(client.addr()).unwrap();
+
+        // Served by the other worker, on a connection of its own
+        client.set("a", "1").unwrap();
+        assert_eq!(client.get("a").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_idle_connections_do_not_hold_workers() {
+        let (client, _dir) = start_server_with(ServerConfig {
+            workers: 2,
+            ..ServerConfig::default()
+        });
+        client.set("a", "1").unwrap();
+
+        // More idle connections than workers, each served once
+        let request = Request::Get {
+            key: "a".to_string(),
+        };
+        let mut idle = (0..3)
+            .map(|_| {
+                let mut connection = Connection::open(client.addr()).unwrap();
+                connection.call(&request).unwrap();
+                connection
+            })
+            .collect::<Vec<_>>();
+        let _silent = TcpStream::connect(client.addr()).unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let addr = client.addr().to_string();
+        std::thread::spawn(move || {
+            let client = KiviClient::new(&addr);
+            client.set("b", "2").unwrap();
+            sender.send(client.get("b").unwrap()).unwrap();
+        });
+
+        let served = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(served.as_deref(), Some("2"));
+
+        // The idle connections are served again when they send a request
+        for connection in &mut idle {
+            assert_eq!(
+                connection.call(&request).unwrap(),
+                Response::Value("1".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let dir = TempDir::new("client").unwrap();
+        let config = || Config::new().set_db_path(dir.path().to_path_buf()).build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let mut server = KiviServer::with_store(KiviStore::with_config(config()).unwrap());
+        let handle = server.server_handle();
+        let running = std::thread::spawn(move || server.run_with_listener(listener));
+
+        let client = KiviClient::new(&addr);
+        client.set("a", "1").unwrap();
+
+        // An idle connection does not hold the shutdown up until the deadline
+        let mut connection = Connection::open(&addr).unwrap();
+        let started = Instant::now();
+        handle.shutdown();
+        running.join().unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let request = Request::Get {
+            key: "a".to_string(),
+        };
+        assert!(connection.call(&request).is_err());
+        assert!(TcpStream::connect(&addr).is_err());
+
+        // The server let go of the store, which can be opened again
+        let store = KiviStore::with_config(config()).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap().value, "1");
+    }
+
+    #[test]
+    fn test_reload_settings() {
+        let (client, _dir, handle) = start_server_with_handle(ServerConfig::default());
+
+        client.set("a", &"x".repeat(2048)).unwrap();
+
+        handle
+            .reload(&ServerSettings {
+                max_request_size: 1024,
+                ..ServerSettings::default()
+            })
+            .unwrap();
+
+        // The open connection already waits with the previous limit
+        let client = KiviClient::new(client.addr());
+        match client.set("b", &"x".repeat(2048)) {
+            Err(KiviError::Generic(message)) => assert!(message.contains("over the limit")),
+            res => panic!("unexpected {:?}", res),
+        }
+        client.set("b", "small").unwrap();
+    }
+
+    pub(crate) fn test_users() -> Vec<User> {
+        let sha256 = |secret: &str| Some(hex::encode(Sha256::digest(secret)));
+
+        vec![
+            User {
+                name: "admin".to_string(),
+                password_sha256: sha256("admin secret"),
+                ..Default::default()
+            },
+            User {
+                name: "reports".to_string(),
+                token_sha256: sha256("reports token"),
+                read_only: true,
+                denied_commands: vec!["compact".to_string()],
+                key_prefixes: vec!["report:".to_string()],
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_authentication() {
+        let (anonymous, _dir) = start_server_with(ServerConfig {
+            users: test_users(),
+            ..ServerConfig::default()
+        });
+        let addr = anonymous.addr().to_string();
+
+        assert!(matches!(
+            anonymous.get("a"),
+            Err(KiviError::Unauthorized(message)) if message == "authentication required"
+        ));
+        assert!(matches!(
+            anonymous.request("get a"),
+            Err(KiviError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            Connection::open_as(&addr, &Credentials::Token("wrong".to_string())),
+            Err(KiviError::Unauthorized(_))
+        ));
+
+        let admin = KiviClient::with_credentials(
+            &addr,
+            Credentials::Password {
+                user: "admin".to_string(),
+                password: "admin secret".to_string(),
+            },
+        );
+        admin.set("report:1", "x").unwrap();
+        admin.set("secret", "y").unwrap();
+        assert_eq!(admin.call(&Request::Compact).unwrap(), Response::Ok);
+
+        let reports =
+            KiviClient::with_credentials(&addr, Credentials::Token("reports token".to_string()));
+        assert_eq!(reports.get("report:1").unwrap().as_deref(), Some("x"));
+
+        // Only the keys under its prefixes are visible
+        let keys: Vec<String> = reports
+            .scan("")
+            .unwrap()
+            .into_iter()
+            .map(|kv| kv.key)
+            .collect();
+        assert_eq!(keys, vec!["report:1"]);
+
+        for request in [
+            Request::Get {
+                key: "secret".to_string(),
+            },
+            Request::Set {
+                key: "report:2".to_string(),
+                value: "z".to_string(),
+            },
+            Request::Compact,
+            Request::Subscribe { from_seq: None },
+        ] {
+            assert!(
+                matches!(reports.call(&request), Err(KiviError::Unauthorized(_))),
+                "{:?} was allowed",
+                request
+            );
+        }
+        assert_eq!(admin.get("report:2").unwrap(), None);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::client::KiviClient;
+use crate::core::{
+    error::{KiviError, Result},
+    kv::KeyValue,
+};
+
+/// Points every node gets on the ring. More points spread keys more evenly.
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// Consistent hash ring. Every node is hashed onto the ring several times, a key
+/// belongs to the first node point at or after its own hash. Adding or removing a node
+/// only moves the keys between that node and its neighbours.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+    virtual_nodes: usize,
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        Self {
+            ring: BTreeMap::new(),
+            virtual_nodes: virtual_nodes.max(1),
+        }
+    }
+
+    pub fn add_node(&mut self, node: &str) {
+        for i in 0..self.virtual_nodes {
+            self.ring
+                .insert(hash(&format!("{}#{}", node, i)), node.to_string());
+        }
+    }
+
+    pub fn remove_node(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    /// Node owning `key`, None if the ring is empty.
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let h = hash(key);
+
+        self.ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        let mut nodes: Vec<String> = self.ring.values().cloned().collect();
+        nodes.sort();
+        nodes.dedup();
+
+        nodes
+    }
+}
+
+/// Client spreading the keys over several servers with a `HashRing`.
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: HashMap<String, KiviClient>,
+}
+
+impl ShardedClient {
+    pub fn new(addrs: &[&str]) -> Self {
+        Self::with_virtual_nodes(addrs, DEFAULT_VIRTUAL_NODES)
+    }
+
+    pub fn with_virtual_nodes(addrs: &[&str], virtual_nodes: usize) -> Self {
+        let mut client = Self {
+            ring: HashRing::new(virtual_nodes),
+            clients: HashMap::new(),
+        };
+
+        for addr in addrs {
+            client.ring.add_node(addr);
+            client
+                .clients
+                .insert(addr.to_string(), KiviClient::new(addr));
+        }
+
+        client
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Client of the server owning `key`.
+    pub fn client_for(&self, key: &str) -> Result<&KiviClient> {
+        self.ring
+            .node_for(key)
+            .and_then(|node| self.clients.get(node))
+            .ok_or_else(|| KiviError::Generic("no servers to route to".to_string()))
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.client_for(key)?.get(key)
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.client_for(key)?.set(key, value)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.client_for(key)?.delete(key)
+    }
+
+    /// Keys starting with `prefix` on every server, sorted by key.
+    pub fn scan(&self, prefix: &str) -> Result<Vec<KeyValue>> {
+        let mut res = Vec::new();
+
+        for client in self.clients.values() {
+            res.extend(client.scan(prefix)?);
+        }
+
+        res.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(res)
+    }
+
+    /// Adds a server and moves the keys it now owns over to it. Returns the number of
+    /// moved keys.
+    ///
+    /// If a move fails, the keys already moved are moved back and the server is not
+    /// added. Should moving them back fail as well, the server stays on the ring so its
+    /// keys can still be read, and the error is returned.
+    pub fn add_node(&mut self, addr: &str) -> Result<usize> {
+        if self.clients.contains_key(addr) {
+            return Ok(0);
+        }
+
+        self.ring.add_node(addr);
+        self.clients.insert(addr.to_string(), KiviClient::new(addr));
+
+        let mut moved = 0;
+        let res = self
+            .ring
+            .nodes()
+            .iter()
+            .filter(|node| *node != addr)
+            .try_for_each(|node| self.migrate_keys(&self.clients[node], &mut moved));
+
+        let Err(e) = res else {
+            return Ok(moved);
+        };
+
+        self.ring.remove_node(addr);
+        let client = self.clients.remove(addr).expect("added above");
+
+        if moved > 0 {
+            self.migrate_from(&client).inspect_err(|_| {
+                self.ring.add_node(addr);
+                self.clients.insert(addr.to_string(), client.clone());
+            })?;
+        }
+
+        Err(e)
+    }
+
+    /// Removes a server after moving all of its keys to the remaining ones. Returns
+    /// the number of moved keys.
+    pub fn remove_node(&mut self, addr: &str) -> Result<usize> {
+        let Some(client) = self.clients.remove(addr) else {
+            return Ok(0);
+        };
+
+        self.ring.remove_node(addr);
+
+        self.migrate_from(&client).inspect_err(|_| {
+            // Keep routing to the server, it still holds the keys that were not moved
+            self.ring.add_node(addr);
+            self.clients.insert(addr.to_string(), client.clone());
+        })
+    }
+
+    /// Moves every key stored on `source` that the ring assigns to another server.
+    /// Keys are written to their new owner before being deleted from `source`, so an
+    /// interrupted migration can simply be run again.
+    pub fn migrate_from(&self, source: &KiviClient) -> Result<usize> {
+        let mut moved = 0;
+        self.migrate_keys(source, &mut moved)?;
+
+        Ok(moved)
+    }
+
+    /// Like `migrate_from`, counting the moved keys in `moved` so the count is known
+    /// when a move fails.
+    ///
+    /// A key is only deleted from `source` if it still holds the copied value. A client
+    /// that has not seen the new ring yet may write it in between, then the newer value
+    /// is copied again.
+    fn migrate_keys(&self, source: &KiviClient, moved: &mut usize) -> Result<()> {
+        for kv in source.scan("")? {
+            let target = self.client_for(&kv.key)?;
+            if target.addr() == source.addr() {
+                continue;
+            }
+
+            let mut value = kv.value;
+            loop {
+                target.set(&kv.key, &value)?;
+
+                if source.delete_if_equals(&kv.key, &value)? {
+                    *moved += 1;
+                    break;
+                }
+
+                match source.get(&kv.key)? {
+                    Some(newer) => value = newer,
+                    // Deleted on the source meanwhile, unless the target changed it
+                    None => {
+                        target.delete_if_equals(&kv.key, &value)?;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// 64 bit FNV-1a, followed by a finalizer so similar keys land far apart.
+fn hash(s: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for b in s.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{start_server, start_server_with};
+    use crate::server::ServerConfig;
+
+    #[test]
+    fn test_ring_moves_few_keys() {
+        let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+        ["a:1", "b:1", "c:1"].iter().for_each(|n| ring.add_node(n));
+
+        let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+        let before: Vec<String> = keys
+            .iter()
+            .map(|k| ring.node_for(k).unwrap().to_string())
+            .collect();
+
+        // Every node gets a fair share
+        for node in ring.nodes() {
+            let count = before.iter().filter(|n| **n == node).count();
+            assert!(count > 600, "{} only owns {} keys", node, count);
+        }
+
+        ring.add_node("d:1");
+
+        // Keys only move to the new node
+        let mut moved = 0;
+        for (key, old) in keys.iter().zip(&before) {
+            let new = ring.node_for(key).unwrap();
+            if new != old {
+                assert_eq!(new, "d:1");
+                moved += 1;
+            }
+        }
+        assert!(moved > 300 && moved < 1200, "moved {} keys", moved);
+
+        ring.remove_node("d:1");
+        for (key, old) in keys.iter().zip(&before) {
+            assert_eq!(ring.node_for(key).unwrap(), old);
+        }
+    }
+
+    #[test]
+    fn test_add_and_remove_node() {
+        let servers: Vec<_> = (0..3).map(|_| start_server()).collect();
+        let addrs: Vec<&str> = servers.iter().map(|(c, _)| c.addr()).collect();
+
+        let mut client = ShardedClient::new(&addrs[..2]);
+        for i in 0..100 {
+            client.set(&format!("key{}", i), &i.to_string()).unwrap();
+        }
+
+        let moved = client.add_node(addrs[2]).unwrap();
+        assert!(moved > 0);
+        assert_eq!(servers[2].0.scan("").unwrap().len(), moved);
+
+        let moved_back = client.remove_node(addrs[0]).unwrap();
+        assert!(moved_back > 0);
+        assert!(servers[0].0.scan("").unwrap().is_empty());
+
+        // Every key is still reachable, from the server owning it
+        assert_eq!(client.scan("").unwrap().len(), 100);
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            assert_eq!(client.get(&key).unwrap(), Some(i.to_string()));
+            assert_ne!(client.client_for(&key).unwrap().addr(), addrs[0]);
+        }
+    }
+
+    #[test]
+    fn test_failed_add_node_is_rolled_back() {
+        let servers: Vec<_> = (0..2).map(|_| start_server()).collect();
+        let addrs: Vec<&str> = servers.iter().map(|(c, _)| c.addr()).collect();
+
+        // Refuses the request moving the large value
+        let (new_server, _dir) = start_server_with(ServerConfig {
+            max_request_size: 512,
+            ..Default::default()
+        });
+        let new_addr = new_server.addr();
+
+        let mut client = ShardedClient::new(&addrs);
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            client.set(key, "small").unwrap();
+        }
+
+        // The last key moved from the last source, so earlier ones moved already
+        let mut ring = client.ring().clone();
+        ring.add_node(new_addr);
+        let last_source = ring.nodes().into_iter().rfind(|n| n != new_addr);
+        let large = keys
+            .iter()
+            .filter(|k| ring.node_for(k) == Some(new_addr))
+            .filter(|k| client.ring().node_for(k) == last_source.as_deref())
+            .max()
+            .unwrap();
+        client.set(large, &"x".repeat(1024)).unwrap();
+
+        assert!(client.add_node(new_addr).is_err());
+
+        assert_eq!(client.ring().nodes().len(), 2);
+        assert!(new_server.scan("").unwrap().is_empty());
+        assert_eq!(client.scan("").unwrap().len(), 100);
+        for key in &keys {
+            assert!(client.get(key).unwrap().is_some());
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Replicated cluster mode.
+//!
+//! Every node of a cluster runs a `RaftNode` next to its `KiviStore`. The nodes elect a
+//! leader, which is the only one accepting writes: each write is appended to the Raft
+//! log as a `KiviCommand`, copied to the other nodes and applied to every store once a
+//! majority of the cluster has it. Clients talking to a follower are redirected to the
+//! leader.
+//!
+//! Only sets and deletes are replicated, conditional writes, increments and expiries
+//! are refused. Reads are served by the leader once a majority confirms it still leads,
+//! so a deposed leader cannot answer with stale data.
+//!
+//! Once enough entries are applied, a node drops them from its log and compacts its
+//! store. The store then is the snapshot: followers that fell behind the log receive
+//! every live key of the leader instead of the missing entries.
+
+mod raft;
+mod rpc;
+
+pub use raft::{LogEntry, NodeRole, NodeStatus, RaftNode};
+
+use std::time::Duration;
+
+/// Addresses of a single cluster node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    /// Where the node serves clients
+    pub client_addr: String,
+
+    /// Where the node talks to the other nodes
+    pub raft_addr: String,
+}
+
+impl Member {
+    pub fn new(client_addr: &str, raft_addr: &str) -> Self {
+        Self {
+            client_addr: client_addr.to_string(),
+            raft_addr: raft_addr.to_string(),
+        }
+    }
+}
+
+/// Membership and timing of a cluster. Every node gets the same `members`, in the same
+/// order, and `id` is the position of the node itself.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    pub id: usize,
+    pub members: Vec<Member>,
+
+    /// How often the leader contacts the followers
+    pub heartbeat_interval: Duration,
+
+    /// Followers wait a random time in this range without hearing from a leader before
+    /// starting an election
+    pub election_timeout: (Duration, Duration),
+
+    /// Number of applied log entries that triggers a snapshot
+    pub snapshot_threshold: u64,
+}
+
+impl ClusterConfig {
+    pub fn new(id: usize, members: Vec<Member>) -> Self {
+        Self {
+            id,
+            members,
+            heartbeat_interval: Duration::from_millis(50),
+            election_timeout: (Duration::from_millis(300), Duration::from_millis(600)),
+            snapshot_threshold: 1000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{config::Config, kv::KiviStore};
+    use crate::server::KiviServer;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Instant;
+    use tempdir::TempDir;
+
+    fn request(addr: &str, command: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(command.as_bytes()).unwrap();
+
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+
+        buf
+    }
+
+    #[test]
+    fn test_clients_are_redirected_to_the_leader() {
+        let listeners: Vec<(TcpListener, TcpListener)> = (0..3)
+            .map(|_| {
+                (
+                    TcpListener::bind("127.0.0.1:0").unwrap(),
+                    TcpListener::bind("127.0.0.1:0").unwrap(),
+                )
+            })
+            .collect();
+        let members: Vec<Member> = listeners
+            .iter()
+            .map(|(client, raft)| {
+                Member::new(
+                    &client.local_addr().unwrap().to_string(),
+                    &raft.local_addr().unwrap().to_string(),
+                )
+            })
+            .collect();
+
+        let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new("cluster").unwrap()).collect();
+
+        for (id, (listener, raft_listener)) in listeners.into_iter().enumerate() {
+            let store = KiviStore::with_config(
+                Config::new()
+                    .set_db_path(dirs[id].path().to_path_buf())
+                    .build(),
+            )
+            .unwrap();
+            let mut server = KiviServer::cluster_with_listener(
+                store,
+                ClusterConfig::new(id, members.clone()),
+                raft_listener,
+            )
+            .unwrap();
+
+            std::thread::spawn(move || server.run_with_listener(listener));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let leader = loop {
+            let leader = members
+                .iter()
+                .find(|m| request(&m.client_addr, "role").contains("leader of term"));
+
+            if let Some(leader) = leader {
+                break leader.client_addr.clone();
+            }
+
+            assert!(Instant::now() < deadline, "no leader elected");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let follower = &members
+            .iter()
+            .find(|m| m.client_addr != leader)
+            .unwrap()
+            .client_addr;
+
+        assert_eq!(request(&leader, "set a 1"), "OK");
+        assert_eq!(request(&leader, "get a"), "Key: a, Value: 1");
+
+        // The follower might not have heard from the leader yet
+        let redirect = format!("Redirect: {}", leader);
+        while request(follower, "get a") != redirect {
+            assert!(
+                Instant::now() < deadline,
+                "follower does not know the leader"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(request(follower, "set b 2"), redirect);
+        assert_eq!(request(follower, "ttl a"), redirect);
+        assert_eq!(
+            request(&leader, "incr a"),
+            "Error: not supported in cluster mode"
+        );
+        assert_eq!(
+            request(&leader, "setnx c 3"),
+            "Error: not supported in cluster mode"
+        );
+    }
+}
@@ -0,0 +1,1255 @@
+use serde::{Deserialize, Serialize};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cluster::{
+    rpc::{self, Request, Response},
+    ClusterConfig,
+};
+use crate::core::{
+    error::{KiviError, Result},
+    kv::{KiviCommand, KiviStore, DEFAULT_BUCKET, EXPIRY_BUCKET},
+};
+use crate::server::{lock_engine, Engine};
+
+/// How often a node checks its election timer and applies committed entries.
+const TICK: Duration = Duration::from_millis(10);
+
+/// Timeout of every step of a vote or append request.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Snapshots carry the whole store, so they get more time.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long `propose` waits for its entry to be applied.
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `read_index` tries to confirm that this node still leads.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most entries sent to a follower at once.
+const MAX_BATCH: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+
+    /// Empty for the entry a new leader appends to commit the entries of older terms
+    pub command: Option<KiviCommand>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Point in time view of a node, for monitoring and tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeStatus {
+    pub id: usize,
+    pub role: NodeRole,
+    pub term: u64,
+    pub leader: Option<usize>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_index: u64,
+}
+
+/// State that has to survive restarts, kept in `raft.json` next to the data directory.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PersistentState {
+    current_term: u64,
+    voted_for: Option<usize>,
+
+    /// Last entry dropped from the log, its effects live in the store
+    snapshot_index: u64,
+    snapshot_term: u64,
+
+    /// Entries following `snapshot_index`
+    log: Vec<LogEntry>,
+}
+
+struct RaftState {
+    persistent: PersistentState,
+    role: NodeRole,
+    leader: Option<usize>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    votes: usize,
+
+    // Only meaningful on the leader, indexed by node id
+    next_index: Vec<u64>,
+    match_index: Vec<u64>,
+
+    /// When the last request a follower answered in the current term was sent
+    acked_at: Vec<Option<Instant>>,
+}
+
+struct Shared {
+    config: ClusterConfig,
+    engine: Engine,
+    state_path: PathBuf,
+    state: Mutex<RaftState>,
+
+    /// Notified whenever the log grows or entries are applied
+    changed: Condvar,
+    stopped: AtomicBool,
+}
+
+/// A running cluster node. Cloning gives another handle to the same node.
+///
+/// Locks are always taken in the same order: the Raft state first, then the engine.
+#[derive(Clone)]
+pub struct RaftNode {
+    shared: Arc<Shared>,
+}
+
+impl RaftNode {
+    /// Loads the persisted Raft state of the store behind `engine`, starts listening on
+    /// the Raft address of this node and starts the background threads.
+    pub fn start(config: ClusterConfig, engine: Engine) -> Result<Self> {
+        let member = config.members.get(config.id).ok_or_else(|| {
+            KiviError::Generic(format!("node {} is not a cluster member", config.id))
+        })?;
+        let listener = TcpListener::bind(&member.raft_addr)?;
+
+        Self::start_with_listener(config, engine, listener)
+    }
+
+    /// Same as `start`, with a listener already bound to the Raft address of this node.
+    pub fn start_with_listener(
+        config: ClusterConfig,
+        engine: Engine,
+        listener: TcpListener,
+    ) -> Result<Self> {
+        if config.id >= config.members.len() {
+            return Err(KiviError::Generic(format!(
+                "node {} is not a cluster member",
+                config.id
+            )));
+        }
+
+        let state_path = lock_engine(&engine)?
+            .get_config()
+            .get_db_path()
+            .join("raft.json");
+        let persistent = load_state(&state_path)?;
+
+        let members = config.members.len();
+        let state = RaftState {
+            // The store already holds everything up to the snapshot. Entries after it
+            // might have been applied before a restart, applying them again is
+            // harmless as every command overwrites what it touches.
+            commit_index: persistent.snapshot_index,
+            last_applied: persistent.snapshot_index,
+            persistent,
+            role: NodeRole::Follower,
+            leader: None,
+            election_deadline: Instant::now() + election_timeout(&config),
+            votes: 0,
+            next_index: vec![1; members],
+            match_index: vec![0; members],
+            acked_at: vec![None; members],
+        };
+
+        let node = Self {
+            shared: Arc::new(Shared {
+                config,
+                engine,
+                state_path,
+                state: Mutex::new(state),
+                changed: Condvar::new(),
+                stopped: AtomicBool::new(false),
+            }),
+        };
+
+        log::info!(
+            "Cluster node {} listening at {}",
+            node.id(),
+            node.shared.config.members[node.id()].raft_addr
+        );
+
+        let n = node.clone();
+        std::thread::spawn(move || n.listen(listener));
+
+        let n = node.clone();
+        std::thread::spawn(move || n.run_timers());
+
+        for peer in 0..members {
+            if peer != node.id() {
+                let n = node.clone();
+                std::thread::spawn(move || n.run_replicator(peer));
+            }
+        }
+
+        Ok(node)
+    }
+
+    pub fn id(&self) -> usize {
+        self.shared.config.id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock_state()
+            .map(|s| s.role == NodeRole::Leader)
+            .unwrap_or(false)
+    }
+
+    /// Client address of the current leader, if one is known.
+    pub fn leader_addr(&self) -> Result<Option<String>> {
+        let state = self.lock_state()?;
+
+        Ok(self.leader_client_addr(&state))
+    }
+
+    pub fn status(&self) -> Result<NodeStatus> {
+        let state = self.lock_state()?;
+
+        Ok(NodeStatus {
+            id: self.id(),
+            role: state.role,
+            term: state.persistent.current_term,
+            leader: state.leader,
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+            snapshot_index: state.persistent.snapshot_index,
+        })
+    }
+
+    /// Replicates a write through the cluster and waits until it is applied to the
+    /// local store. Fails with `NotLeader` on followers, and when leadership is lost
+    /// before the write is known to be committed.
+    pub fn propose(&self, command: KiviCommand) -> Result<()> {
+        let mut state = self.lock_state()?;
+
+        if state.role != NodeRole::Leader {
+            return Err(KiviError::NotLeader(self.leader_client_addr(&state)));
+        }
+
+        let term = state.persistent.current_term;
+        state.persistent.log.push(LogEntry {
+            term,
+            command: Some(command),
+        });
+        let index = state.last_index();
+
+        self.persist(&state)?;
+        self.advance_commit(&mut state);
+        self.shared.changed.notify_all();
+
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+
+        loop {
+            if state.last_applied >= index {
+                // A compacted entry was applied, so it can only be ours if we still lead
+                let ours = match state.term_at(index) {
+                    Some(t) => t == term,
+                    None => state.persistent.current_term == term,
+                };
+
+                if ours {
+                    return Ok(());
+                }
+            }
+
+            if state.persistent.current_term != term || self.is_stopped() {
+                return Err(KiviError::NotLeader(self.leader_client_addr(&state)));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KiviError::Generic(
+                    "timed out waiting for the cluster to commit".to_string(),
+                ));
+            }
+
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .map_err(|_| poisoned())?
+                .0;
+        }
+    }
+
+    /// Waits until a read of the local store is linearizable: this node still leads a
+    /// majority, confirmed by requests sent after the call, and has applied every entry
+    /// committed at that point. Fails with `NotLeader` when that cannot be confirmed, a
+    /// deposed leader would otherwise serve stale data.
+    pub fn read_index(&self) -> Result<()> {
+        let mut state = self.lock_state()?;
+        let start = Instant::now();
+        let deadline = start + READ_TIMEOUT;
+        let term = state.persistent.current_term;
+        let members = self.shared.config.members.len();
+        let mut read_index = None;
+
+        // Heartbeat the followers now rather than at the next interval
+        self.shared.changed.notify_all();
+
+        loop {
+            if state.role != NodeRole::Leader
+                || state.persistent.current_term != term
+                || self.is_stopped()
+            {
+                return Err(KiviError::NotLeader(self.leader_client_addr(&state)));
+            }
+
+            // The commit index is only known to be up to date once the leader has
+            // committed an entry of its own term
+            if read_index.is_none() && state.term_at(state.commit_index) == Some(term) {
+                read_index = Some(state.commit_index);
+            }
+
+            let acks = state
+                .acked_at
+                .iter()
+                .filter(|sent| sent.is_some_and(|sent| sent >= start))
+                .count();
+
+            if let Some(index) = read_index {
+                if (1 + acks) * 2 > members && state.last_applied >= index {
+                    return Ok(());
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KiviError::NotLeader(None));
+            }
+
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, TICK.min(deadline - now))
+                .map_err(|_| poisoned())?
+                .0;
+        }
+    }
+
+    /// Stops the background threads and closes the Raft listener. The store stays
+    /// usable through its engine.
+    pub fn shutdown(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        self.shared.changed.notify_all();
+
+        // Wake the listener up so it notices
+        let _ = TcpStream::connect(&self.shared.config.members[self.id()].raft_addr);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.shared.stopped.load(Ordering::SeqCst)
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, RaftState>> {
+        self.shared.state.lock().map_err(|_| poisoned())
+    }
+
+    fn leader_client_addr(&self, state: &RaftState) -> Option<String> {
+        state
+            .leader
+            .map(|id| self.shared.config.members[id].client_addr.clone())
+    }
+
+    fn persist(&self, state: &RaftState) -> Result<()> {
+        let temp_path = self.shared.state_path.with_extension("json.tmp");
+
+        std::fs::write(&temp_path, serde_json::to_vec(&state.persistent)?)?;
+        std::fs::rename(temp_path, &self.shared.state_path)?;
+
+        Ok(())
+    }
+
+    fn listen(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if self.is_stopped() {
+                break;
+            }
+
+            let res = stream
+                .map_err(KiviError::from)
+                .and_then(|s| rpc::serve(s, |request| self.handle(request)));
+
+            if let Err(e) = res {
+                log::debug!("Node {}: bad request: {}", self.id(), e);
+            }
+        }
+    }
+
+    fn run_timers(&self) {
+        while !self.is_stopped() {
+            if let Err(e) = self.tick() {
+                log::error!("Node {}: {}", self.id(), e);
+            }
+
+            std::thread::sleep(TICK);
+        }
+    }
+
+    fn tick(&self) -> Result<()> {
+        let mut state = self.lock_state()?;
+
+        if state.role != NodeRole::Leader && Instant::now() >= state.election_deadline {
+            self.start_election(&mut state)?;
+        }
+
+        self.apply_committed(&mut state)?;
+        self.maybe_snapshot(&mut state)
+    }
+
+    fn start_election(&self, state: &mut RaftState) -> Result<()> {
+        state.persistent.current_term += 1;
+        state.persistent.voted_for = Some(self.id());
+        state.role = NodeRole::Candidate;
+        state.leader = None;
+        state.votes = 1;
+        state.election_deadline = Instant::now() + election_timeout(&self.shared.config);
+        self.persist(state)?;
+
+        let term = state.persistent.current_term;
+        log::info!("Node {} starts an election for term {}", self.id(), term);
+
+        if state.votes * 2 > self.shared.config.members.len() {
+            return self.become_leader(state);
+        }
+
+        let request = Request::Vote {
+            term,
+            candidate_id: self.id(),
+            last_log_index: state.last_index(),
+            last_log_term: state.last_term(),
+        };
+
+        for (peer, member) in self.shared.config.members.iter().enumerate() {
+            if peer == self.id() {
+                continue;
+            }
+
+            let node = self.clone();
+            let addr = member.raft_addr.clone();
+            let request = request.clone();
+
+            std::thread::spawn(move || {
+                if let Ok(response) = rpc::call(&addr, &request, RPC_TIMEOUT) {
+                    if let Err(e) = node.on_vote(term, response) {
+                        log::error!("Node {}: {}", node.id(), e);
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn on_vote(&self, term: u64, response: Response) -> Result<()> {
+        let mut state = self.lock_state()?;
+
+        if let Response::Vote {
+            term: voter_term,
+            granted,
+        } = response
+        {
+            if voter_term > state.persistent.current_term {
+                state.step_down(voter_term);
+                self.persist(&state)?;
+            } else if granted
+                && state.role == NodeRole::Candidate
+                && state.persistent.current_term == term
+            {
+                state.votes += 1;
+
+                if state.votes * 2 > self.shared.config.members.len() {
+                    self.become_leader(&mut state)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn become_leader(&self, state: &mut RaftState) -> Result<()> {
+        let term = state.persistent.current_term;
+        log::info!("Node {} is the leader of term {}", self.id(), term);
+
+        state.role = NodeRole::Leader;
+        state.leader = Some(self.id());
+
+        let next = state.last_index() + 1;
+        state.next_index.iter_mut().for_each(|i| *i = next);
+        state.match_index.iter_mut().for_each(|i| *i = 0);
+        state.acked_at.iter_mut().for_each(|a| *a = None);
+
+        // Entries of older terms only count as committed once an entry of the current
+        // term is
+        state.persistent.log.push(LogEntry {
+            term,
+            command: None,
+        });
+        self.persist(state)?;
+
+        self.advance_commit(state);
+        self.shared.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Commits the highest entry of the current term stored on a majority of nodes.
+    fn advance_commit(&self, state: &mut RaftState) {
+        let members = self.shared.config.members.len();
+        let term = state.persistent.current_term;
+
+        for index in (state.commit_index + 1..=state.last_index()).rev() {
+            if state.term_at(index) != Some(term) {
+                continue;
+            }
+
+            // The leader itself has every entry, its own match index is never updated
+            let copies = 1 + state.match_index.iter().filter(|m| **m >= index).count();
+
+            if copies * 2 > members {
+                state.commit_index = index;
+                self.shared.changed.notify_all();
+                break;
+            }
+        }
+    }
+
+    /// Applies committed entries to the store. Runs with the Raft state locked, so the
+    /// store always reflects exactly `last_applied` while the lock is held.
+    fn apply_committed(&self, state: &mut RaftState) -> Result<()> {
+        if state.last_applied >= state.commit_index {
+            return Ok(());
+        }
+
+        let mut engine = lock_engine(&self.shared.engine)?;
+
+        while state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+
+            if let Some(command) = state.entry(index).and_then(|e| e.command.clone()) {
+                match engine.execute(command) {
+                    // Deletes of missing keys change nothing
+                    Err(KiviError::KeyNotFound(_)) => {}
+                    res => res?,
+                }
+            }
+
+            state.last_applied = index;
+        }
+
+        self.shared.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Drops the applied entries from the log once there are enough of them, and
+    /// compacts the store that now holds them.
+    fn maybe_snapshot(&self, state: &mut RaftState) -> Result<()> {
+        let applied = state.last_applied - state.persistent.snapshot_index;
+
+        if applied < self.shared.config.snapshot_threshold {
+            return Ok(());
+        }
+
+        let term = state.term_at(state.last_applied).unwrap_or_default();
+
+        lock_engine(&self.shared.engine)?.compact()?;
+
+        state.persistent.log.drain(..applied as usize);
+        state.persistent.snapshot_index = state.last_applied;
+        state.persistent.snapshot_term = term;
+        self.persist(state)?;
+
+        log::info!(
+            "Node {} took a snapshot at index {}",
+            self.id(),
+            state.last_applied
+        );
+
+        Ok(())
+    }
+
+    fn run_replicator(&self, peer: usize) {
+        while !self.is_stopped() {
+            let more = match self.replicate(peer) {
+                Ok(more) => more,
+                Err(e) => {
+                    log::debug!("Node {}: could not reach node {}: {}", self.id(), peer, e);
+                    false
+                }
+            };
+
+            if more {
+                continue;
+            }
+
+            // Heartbeat interval, or earlier when new entries show up
+            let Ok(state) = self.lock_state() else { break };
+            let _ = self
+                .shared
+                .changed
+                .wait_timeout(state, self.shared.config.heartbeat_interval);
+        }
+    }
+
+    /// Sends the next batch of entries, or a snapshot, to a follower. Returns true if
+    /// the follower is still missing entries.
+    fn replicate(&self, peer: usize) -> Result<bool> {
+        let (request, term) = {
+            let state = self.lock_state()?;
+
+            if state.role != NodeRole::Leader {
+                return Ok(false);
+            }
+
+            (
+                self.replication_request(&state, peer)?,
+                state.persistent.current_term,
+            )
+        };
+
+        let timeout = match request {
+            Request::Snapshot { .. } => SNAPSHOT_TIMEOUT,
+            _ => RPC_TIMEOUT,
+        };
+        let addr = &self.shared.config.members[peer].raft_addr;
+        let sent_at = Instant::now();
+        let response = rpc::call(addr, &request, timeout)?;
+
+        let mut state = self.lock_state()?;
+
+        if response.term() > state.persistent.current_term {
+            state.step_down(response.term());
+            self.persist(&state)?;
+            return Ok(false);
+        }
+
+        if state.role != NodeRole::Leader || state.persistent.current_term != term {
+            return Ok(false);
+        }
+
+        // Any answer in our term means the follower still takes us for the leader
+        state.acked_at[peer] = Some(sent_at);
+
+        match (request, response) {
+            (
+                Request::Append { .. },
+                Response::Append {
+                    success: true,
+                    match_index,
+                    ..
+                },
+            ) => {
+                state.match_index[peer] = state.match_index[peer].max(match_index);
+                state.next_index[peer] = state.match_index[peer] + 1;
+                self.advance_commit(&mut state);
+            }
+            (
+                Request::Append { .. },
+                Response::Append {
+                    success: false,
+                    match_index,
+                    ..
+                },
+            ) => {
+                let next = state.next_index[peer];
+                state.next_index[peer] = (next - 1).min(match_index + 1).max(1);
+            }
+            (
+                Request::Snapshot {
+                    last_included_index,
+                    ..
+                },
+                Response::Snapshot { .. },
+            ) => {
+                state.match_index[peer] = last_included_index;
+                state.next_index[peer] = last_included_index + 1;
+                self.advance_commit(&mut state);
+            }
+            (_, response) => {
+                return Err(KiviError::Generic(format!(
+                    "unexpected response: {:?}",
+                    response
+                )))
+            }
+        }
+
+        Ok(state.next_index[peer] <= state.last_index())
+    }
+
+    fn replication_request(&self, state: &RaftState, peer: usize) -> Result<Request> {
+        let next = state.next_index[peer];
+        let term = state.persistent.current_term;
+
+        if next <= state.persistent.snapshot_index {
+            // The entries are gone, send the store as it is at `last_applied` instead
+            let data = lock_engine(&self.shared.engine)?.live_commands()?;
+
+            return Ok(Request::Snapshot {
+                term,
+                leader_id: self.id(),
+                last_included_index: state.last_applied,
+                last_included_term: state.term_at(state.last_applied).unwrap_or_default(),
+                data,
+            });
+        }
+
+        Ok(Request::Append {
+            term,
+            leader_id: self.id(),
+            prev_log_index: next - 1,
+            prev_log_term: state.term_at(next - 1).unwrap_or_default(),
+            entries: state.entries_from(next),
+            leader_commit: state.commit_index,
+        })
+    }
+
+    /// Answers a request from another node.
+    fn handle(&self, request: Request) -> Result<Response> {
+        let mut state = self.lock_state()?;
+
+        if self.is_stopped() {
+            return Err(KiviError::Generic("node is shutting down".to_string()));
+        }
+
+        let response = match request {
+            Request::Vote {
+                term,
+                candidate_id,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > state.persistent.current_term {
+                    state.step_down(term);
+                }
+
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (state.last_term(), state.last_index());
+                let granted = term == state.persistent.current_term
+                    && up_to_date
+                    && state.persistent.voted_for.unwrap_or(candidate_id) == candidate_id;
+
+                if granted {
+                    state.persistent.voted_for = Some(candidate_id);
+                    state.election_deadline =
+                        Instant::now() + election_timeout(&self.shared.config);
+                }
+
+                self.persist(&state)?;
+
+                Response::Vote {
+                    term: state.persistent.current_term,
+                    granted,
+                }
+            }
+            Request::Append {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < state.persistent.current_term {
+                    return Ok(Response::Append {
+                        term: state.persistent.current_term,
+                        success: false,
+                        match_index: 0,
+                    });
+                }
+
+                self.follow(&mut state, term, leader_id);
+                self.append_entries(
+                    &mut state,
+                    prev_log_index,
+                    prev_log_term,
+                    entries,
+                    leader_commit,
+                )?
+            }
+            Request::Snapshot {
+                term,
+                leader_id,
+                last_included_index,
+                last_included_term,
+                data,
+            } => {
+                if term >= state.persistent.current_term {
+                    self.follow(&mut state, term, leader_id);
+                    self.install_snapshot(
+                        &mut state,
+                        last_included_index,
+                        last_included_term,
+                        data,
+                    )?;
+                }
+
+                Response::Snapshot {
+                    term: state.persistent.current_term,
+                }
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Accepts `leader_id` as the leader of `term`.
+    fn follow(&self, state: &mut RaftState, term: u64, leader_id: usize) {
+        if term > state.persistent.current_term || state.role != NodeRole::Follower {
+            state.step_down(term);
+        }
+
+        state.leader = Some(leader_id);
+        state.election_deadline = Instant::now() + election_timeout(&self.shared.config);
+    }
+
+    fn append_entries(
+        &self,
+        state: &mut RaftState,
+        mut prev_log_index: u64,
+        mut prev_log_term: u64,
+        mut entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) -> Result<Response> {
+        let term = state.persistent.current_term;
+        let snapshot_index = state.persistent.snapshot_index;
+
+        let failure = |match_index| Response::Append {
+            term,
+            success: false,
+            match_index,
+        };
+
+        if prev_log_index > state.last_index() {
+            return Ok(failure(state.last_index()));
+        }
+
+        // Entries covered by our snapshot are committed, so they match the leader's
+        if prev_log_index < snapshot_index {
+            let covered = (snapshot_index - prev_log_index) as usize;
+            entries.drain(..covered.min(entries.len()));
+            prev_log_index = snapshot_index;
+            prev_log_term = state.persistent.snapshot_term;
+        }
+
+        if state.term_at(prev_log_index) != Some(prev_log_term) {
+            return Ok(failure(prev_log_index - 1));
+        }
+
+        let match_index = prev_log_index + entries.len() as u64;
+        let mut changed = false;
+
+        for (i, entry) in entries.into_iter().enumerate() {
+            let index = prev_log_index + 1 + i as u64;
+
+            match state.term_at(index) {
+                Some(t) if t == entry.term => continue,
+                Some(_) => state.truncate_from(index),
+                None => {}
+            }
+
+            state.persistent.log.push(entry);
+            changed = true;
+        }
+
+        if changed {
+            self.persist(state)?;
+        }
+
+        if leader_commit > state.commit_index {
+            state.commit_index = leader_commit.min(match_index).max(state.commit_index);
+        }
+
+        Ok(Response::Append {
+            term,
+            success: true,
+            match_index,
+        })
+    }
+
+    /// Replaces the content of the store with the leader's.
+    fn install_snapshot(
+        &self,
+        state: &mut RaftState,
+        last_included_index: u64,
+        last_included_term: u64,
+        data: Vec<KiviCommand>,
+    ) -> Result<()> {
+        if last_included_index <= state.last_applied {
+            return Ok(());
+        }
+
+        // Entries following the snapshot are still good if the logs agree on it
+        if state.term_at(last_included_index) == Some(last_included_term) {
+            let covered = last_included_index - state.persistent.snapshot_index;
+            state.persistent.log.drain(..covered as usize);
+        } else {
+            state.persistent.log.clear();
+        }
+
+        {
+            let mut engine = lock_engine(&self.shared.engine)?;
+            clear_store(&mut engine)?;
+
+            for command in data {
+                engine.execute(command)?;
+            }
+
+            engine.compact()?;
+        }
+
+        state.persistent.snapshot_index = last_included_index;
+        state.persistent.snapshot_term = last_included_term;
+        state.commit_index = state.commit_index.max(last_included_index);
+        state.last_applied = last_included_index;
+        self.persist(state)?;
+
+        log::info!(
+            "Node {} installed a snapshot at index {}",
+            self.id(),
+            last_included_index
+        );
+
+        Ok(())
+    }
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.persistent.snapshot_index + self.persistent.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or_default()
+    }
+
+    /// Term of the entry at `index`, None if it is unknown or compacted away.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.persistent.snapshot_index {
+            return Some(self.persistent.snapshot_term);
+        }
+
+        self.entry(index).map(|e| e.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.persistent.snapshot_index {
+            return None;
+        }
+
+        let pos = (index - self.persistent.snapshot_index - 1) as usize;
+        self.persistent.log.get(pos)
+    }
+
+    fn entries_from(&self, index: u64) -> Vec<LogEntry> {
+        let pos = (index - self.persistent.snapshot_index - 1) as usize;
+
+        self.persistent
+            .log
+            .iter()
+            .skip(pos)
+            .take(MAX_BATCH)
+            .cloned()
+            .collect()
+    }
+
+    /// Removes the entry at `index` and everything after it.
+    fn truncate_from(&mut self, index: u64) {
+        let pos = (index - self.persistent.snapshot_index - 1) as usize;
+        self.persistent.log.truncate(pos);
+    }
+
+    fn step_down(&mut self, term: u64) {
+        if term > self.persistent.current_term {
+            self.persistent.current_term = term;
+            self.persistent.voted_for = None;
+            self.leader = None;
+        }
+
+        self.role = NodeRole::Follower;
+        self.votes = 0;
+    }
+}
+
+impl Response {
+    fn term(&self) -> u64 {
+        match self {
+            Response::Vote { term, .. }
+            | Response::Append { term, .. }
+            | Response::Snapshot { term } => *term,
+        }
+    }
+}
+
+fn load_state(path: &Path) -> Result<PersistentState> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(PersistentState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn clear_store(engine: &mut KiviStore) -> Result<()> {
+    for bucket in engine.buckets() {
+        engine.drop_bucket(&bucket)?;
+    }
+
+    engine.drop_bucket_in(DEFAULT_BUCKET)?;
+    engine.drop_bucket_in(EXPIRY_BUCKET)
+}
+
+/// Random duration in the configured election timeout range, so nodes rarely time out
+/// together.
+fn election_timeout(config: &ClusterConfig) -> Duration {
+    let (min, max) = config.election_timeout;
+
+    // Clock jitter is random enough here
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or_default();
+    let mut x = nanos ^ ((config.id as u64 + 1) << 32) ^ 0x9e37_79b9_7f4a_7c15;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+
+    let span = (max.saturating_sub(min)).as_millis().max(1) as u64;
+
+    min + Duration::from_millis(x % span)
+}
+
+fn poisoned() -> KiviError {
+    KiviError::Generic("raft state lock poisoned".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::Member;
+    use crate::core::config::Config;
+    use std::net::TcpListener;
+    use tempdir::TempDir;
+
+    struct TestCluster {
+        members: Vec<Member>,
+        listeners: Vec<Option<TcpListener>>,
+        dirs: Vec<TempDir>,
+        nodes: Vec<Option<(RaftNode, Engine)>>,
+    }
+
+    impl TestCluster {
+        fn new(size: usize) -> Self {
+            // Bound for the whole test, so no other test can take the port in between
+            let listeners: Vec<TcpListener> = (0..size)
+                .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+                .collect();
+            let members = listeners
+                .iter()
+                .map(|l| Member::new(&free_addr(), &l.local_addr().unwrap().to_string()))
+                .collect();
+            let dirs = (0..size)
+                .map(|i| TempDir::new(&format!("node{}", i)).unwrap())
+                .collect();
+
+            Self {
+                members,
+                listeners: listeners.into_iter().map(Some).collect(),
+                dirs,
+                nodes: (0..size).map(|_| None).collect(),
+            }
+        }
+
+        fn start(&mut self, id: usize, snapshot_threshold: u64) {
+            let store = KiviStore::with_config(
+                Config::new()
+                    .set_db_path(self.dirs[id].path().to_path_buf())
+                    .build(),
+            )
+            .unwrap();
+            let engine = Arc::new(Mutex::new(store));
+
+            let mut config = ClusterConfig::new(id, self.members.clone());
+            config.snapshot_threshold = snapshot_threshold;
+
+            let listener = self.listeners[id].take().unwrap();
+            let node = RaftNode::start_with_listener(config, engine.clone(), listener).unwrap();
+            self.nodes[id] = Some((node, engine));
+        }
+
+        fn stop(&mut self, id: usize) {
+            if let Some((node, _)) = self.nodes[id].take() {
+                node.shutdown();
+            }
+        }
+
+        fn node(&self, id: usize) -> &RaftNode {
+            &self.nodes[id].as_ref().unwrap().0
+        }
+
+        fn get(&self, id: usize, key: &str) -> Option<String> {
+            let engine = &self.nodes[id].as_ref().unwrap().1;
+            lock_engine(engine)
+                .unwrap()
+                .get(key.to_string())
+                .map(|kv| kv.value)
+        }
+
+        fn wait_for_leader(&self) -> usize {
+            wait_until(|| {
+                let leaders: Vec<usize> = self
+                    .nodes
+                    .iter()
+                    .flatten()
+                    .filter(|(node, _)| node.is_leader())
+                    .map(|(node, _)| node.id())
+                    .collect();
+
+                (leaders.len() == 1).then(|| leaders[0])
+            })
+        }
+
+        fn wait_for_value(&self, id: usize, key: &str, value: &str) {
+            wait_until(|| (self.get(id, key).as_deref() == Some(value)).then_some(()));
+        }
+    }
+
+    impl Drop for TestCluster {
+        fn drop(&mut self) {
+            for id in 0..self.nodes.len() {
+                self.stop(id);
+            }
+        }
+    }
+
+    fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn wait_until<T>(mut f: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        loop {
+            if let Some(res) = f() {
+                return res;
+            }
+
+            assert!(Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn set(key: &str, value: &str) -> KiviCommand {
+        KiviCommand::Set {
+            bucket: DEFAULT_BUCKET.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_replicates_to_every_node() {
+        let mut cluster = TestCluster::new(3);
+        (0..3).for_each(|id| cluster.start(id, 1000));
+
+        let leader = cluster.wait_for_leader();
+        let follower = (leader + 1) % 3;
+
+        // Followers point to the leader
+        wait_until(|| cluster.node(follower).leader_addr().unwrap());
+        let err = cluster.node(follower).propose(set("a", "1")).unwrap_err();
+        assert!(
+            matches!(err, KiviError::NotLeader(Some(addr)) if addr == cluster.members[leader].client_addr)
+        );
+
+        cluster.node(leader).propose(set("a", "1")).unwrap();
+        assert_eq!(cluster.get(leader, "a").as_deref(), Some("1"));
+
+        for id in 0..3 {
+            cluster.wait_for_value(id, "a", "1");
+        }
+    }
+
+    #[test]
+    fn test_isolated_leader_refuses_reads() {
+        let mut cluster = TestCluster::new(3);
+        (0..3).for_each(|id| cluster.start(id, 1000));
+
+        let leader = cluster.wait_for_leader();
+        cluster.node(leader).propose(set("a", "1")).unwrap();
+        cluster.node(leader).read_index().unwrap();
+
+        // Without a majority it can no longer tell whether another node took over
+        (0..3)
+            .filter(|id| *id != leader)
+            .for_each(|id| cluster.stop(id));
+
+        let err = cluster.node(leader).read_index().unwrap_err();
+        assert!(matches!(err, KiviError::NotLeader(_)));
+    }
+
+    #[test]
+    fn test_new_leader_after_failure() {
+        let mut cluster = TestCluster::new(3);
+        (0..3).for_each(|id| cluster.start(id, 1000));
+
+        let old_leader = cluster.wait_for_leader();
+        cluster.node(old_leader).propose(set("a", "1")).unwrap();
+
+        cluster.stop(old_leader);
+
+        let leader = cluster.wait_for_leader();
+        assert_ne!(leader, old_leader);
+        assert!(cluster.node(leader).status().unwrap().term > 1);
+
+        // Committed writes survive the failover, and the cluster keeps accepting new ones
+        cluster.wait_for_value(leader, "a", "1");
+        cluster.node(leader).propose(set("b", "2")).unwrap();
+
+        let other = (0..3)
+            .find(|id| *id != leader && *id != old_leader)
+            .unwrap();
+        cluster.wait_for_value(other, "b", "2");
+    }
+
+    #[test]
+    fn test_lagging_node_receives_snapshot() {
+        let mut cluster = TestCluster::new(3);
+        cluster.start(0, 5);
+        cluster.start(1, 5);
+
+        let leader = cluster.wait_for_leader();
+        for i in 0..20 {
+            cluster
+                .node(leader)
+                .propose(set(&format!("k{}", i), &i.to_string()))
+                .unwrap();
+        }
+        cluster
+            .node(leader)
+            .propose(KiviCommand::Delete {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: "k0".to_string(),
+            })
+            .unwrap();
+
+        wait_until(|| (cluster.node(leader).status().unwrap().snapshot_index > 0).then_some(()));
+
+        // Node 2 was never up, the entries it misses are only in the snapshot now
+        cluster.start(2, 5);
+        cluster.wait_for_value(2, "k19", "19");
+        assert_eq!(cluster.get(2, "k0"), None);
+
+        cluster
+            .node(leader)
+            .propose(set("after", "snapshot"))
+            .unwrap();
+        cluster.wait_for_value(2, "after", "snapshot");
+    }
+}
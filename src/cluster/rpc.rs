@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::cluster::raft::LogEntry;
+use crate::core::{
+    error::{KiviError, Result},
+    kv::KiviCommand,
+};
+
+/// Messages exchanged between cluster nodes. Every connection carries a single request
+/// line and a single response line, both JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) enum Request {
+    Vote {
+        term: u64,
+        candidate_id: usize,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Append {
+        term: u64,
+        leader_id: usize,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    Snapshot {
+        term: u64,
+        leader_id: usize,
+        last_included_index: u64,
+        last_included_term: u64,
+        data: Vec<KiviCommand>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) enum Response {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// On failure `match_index` is a hint of where the follower's log ends.
+    Append {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    Snapshot {
+        term: u64,
+    },
+}
+
+/// Sends a request to the node listening at `addr` and waits at most `timeout` for
+/// every step of the exchange.
+pub(crate) fn call(addr: &str, request: &Request, timeout: Duration) -> Result<Response> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| KiviError::Generic(format!("could not resolve {}", addr)))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    writeln!(stream, "{}", serde_json::to_string(request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Reads one request from `stream`, lets `handle` answer it and writes the answer back.
+pub(crate) fn serve(
+    stream: TcpStream,
+    handle: impl FnOnce(Request) -> Result<Response>,
+) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+
+    let response = handle(serde_json::from_str(&line)?)?;
+
+    let mut out = stream;
+    writeln!(out, "{}", serde_json::to_string(&response)?)?;
+
+    Ok(())
+}
@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::core::{
+    config::Config,
+    error::{KiviError, Result},
+    hint,
+    kv::KiviStore,
+    meta::StoreMeta,
+};
+
+/// Name of the manifest written at the root of every backup.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Describes a backup: the files it is made of and the checksums they had when it was
+/// taken.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupManifest {
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+
+    /// Sequence number of the last write included in the backup
+    pub last_seq: u64,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+
+    /// Hex encoded SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Files of a backup pinned in a staging directory next to the data directory, so
+/// compaction cannot remove them while they are copied. The staging directory is
+/// removed on drop.
+pub struct PreparedBackup {
+    staging: PathBuf,
+    last_seq: u64,
+    files: Vec<String>,
+}
+
+/// First half of a backup, needs exclusive access to the store but is quick: the
+/// active file is sealed, then the sealed data files and their hints are hard linked
+/// into a staging directory. Falls back to copying where hard links are not supported.
+pub fn prepare(store: &mut KiviStore) -> Result<PreparedBackup> {
+    store.seal_active_file()?;
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let staging =
+        store
+            .get_config()
+            .get_db_path()
+            .join(format!("backup-{}-{}", std::process::id(), nanos));
+    std::fs::create_dir_all(&staging)?;
+
+    let mut prepared = PreparedBackup {
+        staging,
+        last_seq: store.last_seq(),
+        files: Vec::new(),
+    };
+
+    for data_file in store.sealed_files() {
+        for path in [data_file.clone(), hint::hint_path(data_file)] {
+            if !path.exists() {
+                continue;
+            }
+
+            let name = file_name(&path)?;
+            let dest = prepared.staging.join(&name);
+
+            if std::fs::hard_link(&path, &dest).is_err() {
+                std::fs::copy(&path, &dest)?;
+            }
+
+            prepared.files.push(name);
+        }
+    }
+
+    Ok(prepared)
+}
+
+impl PreparedBackup {
+    /// Second half of a backup, which does not need the store: copies the pinned files
+    /// to `dir` and writes the manifest. `dir` must be missing or empty.
+    pub fn write_to(self, dir: &Path) -> Result<BackupManifest> {
+        std::fs::create_dir_all(dir)?;
+        if std::fs::read_dir(dir)?.next().is_some() {
+            return Err(KiviError::Generic(format!(
+                "backup directory {} is not empty",
+                dir.display()
+            )));
+        }
+
+        let mut files = Vec::new();
+        for name in &self.files {
+            let size = std::fs::copy(self.staging.join(name), dir.join(name))?;
+
+            files.push(BackupFile {
+                name: name.clone(),
+                size,
+                sha256: sha256_file(&dir.join(name))?,
+            });
+        }
+
+        let manifest = BackupManifest {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            last_seq: self.last_seq,
+            files,
+        };
+
+        // Written last, a backup without a manifest is incomplete
+        std::fs::write(
+            dir.join(MANIFEST_FILE),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+
+        Ok(manifest)
+    }
+}
+
+impl Drop for PreparedBackup {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.staging) {
+            log::error!(
+                "Could not remove backup staging directory {}: {}",
+                self.staging.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Takes a consistent backup of the store into `dir`.
+pub fn backup(store: &mut KiviStore, dir: &Path) -> Result<BackupManifest> {
+    prepare(store)?.write_to(dir)
+}
+
+/// Checks that every file listed in the manifest of the backup in `dir` is present
+/// with the right size and checksum.
+pub fn verify(dir: &Path) -> Result<BackupManifest> {
+    let manifest: BackupManifest =
+        serde_json::from_slice(&std::fs::read(dir.join(MANIFEST_FILE))?)?;
+
+    for file in &manifest.files {
+        // Never read outside of the backup directory
+        if Path::new(&file.name).file_name().and_then(|n| n.to_str()) != Some(&file.name) {
+            return Err(KiviError::Corrupted(format!(
+                "invalid file name in manifest: {}",
+                file.name
+            )));
+        }
+
+        let path = dir.join(&file.name);
+        let size = std::fs::metadata(&path)?.len();
+
+        if size != file.size || sha256_file(&path)? != file.sha256 {
+            return Err(KiviError::Corrupted(format!(
+                "backup file {} does not match the manifest",
+                file.name
+            )));
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// Verifies the backup in `dir`, then replaces the data directory of `config` with it.
+/// The store must not be open while restoring.
+pub fn restore(dir: &Path, config: &Config) -> Result<BackupManifest> {
+    let manifest = verify(dir)?;
+
+    let data_dir = PathBuf::from(config.get_full_path());
+    let restore_dir = data_dir.with_extension("restore");
+
+    // Copy next to the data directory first, so a failure leaves the store untouched
+    if restore_dir.exists() {
+        std::fs::remove_dir_all(&restore_dir)?;
+    }
+    std::fs::create_dir_all(&restore_dir)?;
+
+    for file in &manifest.files {
+        std::fs::copy(dir.join(&file.name), restore_dir.join(&file.name))?;
+    }
+
+    if data_dir.exists() {
+        std::fs::remove_dir_all(&data_dir)?;
+    }
+    std::fs::rename(&restore_dir, &data_dir)?;
+
+    StoreMeta {
+        last_seq: manifest.last_seq,
+        ..StoreMeta::default()
+    }
+    .save(config)?;
+
+    Ok(manifest)
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or_else(|| KiviError::Generic(format!("invalid data file: {}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+    use tempdir::TempDir;
+
+    fn open(dir: &Path) -> KiviStore {
+        KiviStore::with_config(Config::new().set_db_path(dir.to_path_buf()).build()).unwrap()
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let db = TempDir::new("db").unwrap();
+        let backups = TempDir::new("backups").unwrap();
+        let backup_dir = backups.path().join("b1");
+
+        let mut store = open(db.path());
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+
+        // Pinned files survive a compaction happening during the copy
+        let prepared = prepare(&mut store).unwrap();
+        store.delete("a".to_string()).unwrap();
+        store.compact().unwrap();
+        store.set("c".to_string(), "3".to_string()).unwrap();
+
+        let manifest = prepared.write_to(&backup_dir).unwrap();
+        assert_eq!(manifest.last_seq, 2);
+        assert!(manifest.files.iter().any(|f| f.name.ends_with(".hint")));
+        assert!(!db.path().read_dir().unwrap().any(|e| e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with("backup-")));
+
+        drop(store);
+        restore(
+            &backup_dir,
+            &Config::new().set_db_path(db.path().to_path_buf()).build(),
+        )
+        .unwrap();
+
+        let mut store = open(db.path());
+        assert_eq!(store.get("a".to_string()).unwrap().value, "1");
+        assert_eq!(store.get("b".to_string()).unwrap().value, "2");
+        assert_eq!(store.get("c".to_string()), None);
+
+        // Sequence numbers continue after the backup
+        store.set("d".to_string(), "4".to_string()).unwrap();
+        assert_eq!(store.last_seq(), 3);
+    }
+
+    #[test]
+    fn test_backup_command() {
+        let backups = TempDir::new("backups").unwrap();
+        let backup_dir = backups.path().join("b1");
+        let (client, _db) = crate::client::tests::start_server_with(ServerConfig {
+            backup_root: Some(backups.path().to_path_buf()),
+            ..ServerConfig::default()
+        });
+
+        client.set("a", "1").unwrap();
+
+        let response = client.request("backup b1").unwrap();
+        assert_eq!(response, "Backup: 2 files, last seq 1");
+        assert_eq!(verify(&backup_dir).unwrap().last_seq, 1);
+
+        // The server keeps accepting writes
+        client.set("b", "2").unwrap();
+        assert!(client.request("backup b1").is_err());
+    }
+
+    #[test]
+    fn test_restore_rejects_corrupted_backup() {
+        let db = TempDir::new("db").unwrap();
+        let backups = TempDir::new("backups").unwrap();
+        let backup_dir = backups.path().join("b1");
+
+        let mut store = open(db.path());
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        backup(&mut store, &backup_dir).unwrap();
+
+        // A non empty directory is never overwritten
+        assert!(backup(&mut store, &backup_dir).is_err());
+
+        store.set("a".to_string(), "2".to_string()).unwrap();
+        drop(store);
+
+        let data_file = backup_dir.join("1.log");
+        let mut bytes = std::fs::read(&data_file).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&data_file, bytes).unwrap();
+
+        let config = Config::new().set_db_path(db.path().to_path_buf()).build();
+        assert!(matches!(
+            restore(&backup_dir, &config),
+            Err(KiviError::Corrupted(_))
+        ));
+
+        // The store was left alone
+        assert_eq!(open(db.path()).get("a".to_string()).unwrap().value, "2");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::core::{
+    error::{KiviError, Result},
+    kv::{self, KeyValue, KiviStore},
+};
+
+/// Handle to a named bucket. All operations only see the keys of this bucket, so the
+/// same key can exist independently in several buckets.
+pub struct Bucket<'a> {
+    store: &'a mut KiviStore,
+    name: String,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct BucketStats {
+    /// Number of live keys
+    pub key_count: usize,
+
+    /// Size on disk of the live records
+    pub live_bytes: u64,
+}
+
+impl<'a> Bucket<'a> {
+    pub(crate) fn new(store: &'a mut KiviStore, name: &str) -> Result<Self> {
+        if kv::is_reserved_bucket(name) {
+            return Err(KiviError::ReservedBucket(name.to_string()));
+        }
+
+        Ok(Self {
+            store,
+            name: name.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: String) -> Option<KeyValue> {
+        self.store.get_in(&self.name, key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.set_in(&self.name, key, value)
+    }
+
+    pub fn delete(&mut self, key: String) -> Result<()> {
+        self.store.delete_in(&self.name, key)
+    }
+
+    /// Loads a large number of pairs, much faster than calling `set` for each of them.
+    /// Data and hint files are written directly at the configured rotation size, then
+    /// added to the store at once, so a crash in the middle leaves the store as it was.
+    /// When a key appears several times the last value wins. Returns the number of
+    /// written records.
+    pub fn bulk_load<I>(&mut self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.store.bulk_load_in(&self.name, pairs)
+    }
+
+    /// Returns all pairs of this bucket whose key starts with `prefix`.
+    pub fn scan(&self, prefix: &str) -> Result<Vec<KeyValue>> {
+        self.store.scan_in(&self.name, prefix)
+    }
+
+    pub fn stats(&self) -> BucketStats {
+        self.store.bucket_stats(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::Config;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_buckets_are_isolated() {
+        let tempdir = TempDir::new("buckets_isolated").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("a".to_string(), "default".to_string()).unwrap();
+        kv.bucket("users")
+            .unwrap()
+            .set("a".to_string(), "users".to_string())
+            .unwrap();
+
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "default");
+        assert_eq!(
+            kv.bucket("users")
+                .unwrap()
+                .get("a".to_string())
+                .unwrap()
+                .value,
+            "users"
+        );
+        assert_eq!(kv.bucket("videos").unwrap().get("a".to_string()), None);
+
+        kv.bucket("users").unwrap().delete("a".to_string()).unwrap();
+
+        assert_eq!(kv.bucket("users").unwrap().get("a".to_string()), None);
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "default");
+    }
+
+    #[test]
+    fn test_bucket_scan_and_stats() {
+        let tempdir = TempDir::new("bucket_scan").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        let mut users = kv.bucket("users").unwrap();
+        users.set("a1".to_string(), "x".to_string()).unwrap();
+        users.set("a2".to_string(), "y".to_string()).unwrap();
+        users.set("b1".to_string(), "z".to_string()).unwrap();
+
+        assert_eq!(users.scan("a").unwrap().len(), 2);
+        assert_eq!(users.stats().key_count, 3);
+        assert!(users.stats().live_bytes > 0);
+
+        kv.set("a3".to_string(), "w".to_string()).unwrap();
+        assert_eq!(kv.bucket("users").unwrap().scan("a").unwrap().len(), 2);
+
+        kv.drop_bucket("users").unwrap();
+
+        assert_eq!(kv.bucket("users").unwrap().stats(), BucketStats::default());
+        assert_eq!(kv.get("a3".to_string()).unwrap().value, "w");
+    }
+
+    #[test]
+    fn test_reserved_buckets() {
+        let tempdir = TempDir::new("bucket_reserved").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("a".to_string(), "1".to_string()).unwrap();
+        kv.expire("a".to_string(), Duration::from_secs(60)).unwrap();
+
+        assert!(matches!(
+            kv.bucket(kv::EXPIRY_BUCKET),
+            Err(KiviError::ReservedBucket(_))
+        ));
+        assert!(matches!(
+            kv.drop_bucket(kv::EXPIRY_BUCKET),
+            Err(KiviError::ReservedBucket(_))
+        ));
+        assert!(kv.ttl("a").unwrap().is_some());
+
+        // The default bucket is not a bucket users can drop either
+        assert!(matches!(
+            kv.drop_bucket(kv::DEFAULT_BUCKET),
+            Err(KiviError::ReservedBucket(_))
+        ));
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "1");
+    }
+}
@@ -0,0 +1,358 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::core::{
+    config::Config,
+    error::Result,
+    hint::{self, Hint, HintEntry},
+    kv::KiviCommand,
+    record,
+};
+
+/// Directory next to the data directory where a bulk load writes its files.
+const STAGING_DIR: &str = "bulk";
+
+/// Lists the files a bulk load is moving into the data directory. Only exists while
+/// they are being moved.
+const JOURNAL_FILE: &str = "bulk.json";
+
+/// Rough size of the record header and JSON framing, so files can be cut close to the
+/// rotation size without encoding records twice.
+const RECORD_OVERHEAD: u64 = 48;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Journal {
+    files: Vec<String>,
+}
+
+/// Data files of a bulk load, with their hints, waiting in the staging directory. The
+/// staging directory is removed on drop.
+pub(crate) struct StagedLoad {
+    dir: PathBuf,
+    first_index: usize,
+
+    /// Index of every written data file and its hint
+    pub(crate) files: Vec<(usize, Hint)>,
+
+    /// Sequence number following the last written record
+    pub(crate) next_seq: u64,
+}
+
+/// Writes `pairs` into sorted data files numbered from `first_index`, cutting a new
+/// file whenever the configured rotation size is reached. Within a file the last value
+/// of a key wins, and later files win over earlier ones when read in order.
+pub(crate) fn stage<I>(
+    config: &Config,
+    bucket: &str,
+    pairs: I,
+    first_index: usize,
+    first_seq: u64,
+) -> Result<StagedLoad>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let dir = config.get_db_path().join(STAGING_DIR);
+
+    // Left over by an interrupted load
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+
+    let mut staged = StagedLoad {
+        dir,
+        first_index,
+        files: Vec::new(),
+        next_seq: first_seq,
+    };
+
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+
+    for (key, value) in pairs {
+        chunk_size += (bucket.len() + key.len() + value.len()) as u64 + RECORD_OVERHEAD;
+        chunk.push((key, value));
+
+        if chunk_size >= config.get_max_file_size() {
+            staged.write_file(config, bucket, std::mem::take(&mut chunk))?;
+            chunk_size = 0;
+        }
+    }
+
+    if !chunk.is_empty() {
+        staged.write_file(config, bucket, chunk)?;
+    }
+
+    Ok(staged)
+}
+
+impl StagedLoad {
+    fn write_file(
+        &mut self,
+        config: &Config,
+        bucket: &str,
+        mut pairs: Vec<(String, String)>,
+    ) -> Result<()> {
+        // Sorted by key, keeping the last value of every key
+        pairs.reverse();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        pairs.dedup_by(|a, b| a.0 == b.0);
+
+        let index = self.first_index + self.files.len();
+        let path = self.dir.join(file_name(config, index));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(&record::file_header())?;
+
+        let mut entries = Vec::with_capacity(pairs.len());
+        let mut pos = record::FILE_HEADER_SIZE as i32;
+
+        for (key, value) in pairs {
+            let encoded = record::encode(
+                &KiviCommand::Set {
+                    bucket: bucket.to_string(),
+                    key: key.clone(),
+                    value,
+                },
+                self.next_seq,
+                config.get_codec(),
+                config.get_keyring(),
+            )?;
+            writer.write_all(&encoded)?;
+
+            entries.push(HintEntry::Set {
+                bucket: bucket.to_string(),
+                key,
+                pos,
+                size: encoded.len() as i32,
+            });
+
+            self.next_seq += 1;
+            pos += encoded.len() as i32;
+        }
+
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        let hint = Hint {
+            file_size: pos as u64,
+            last_seq: self.next_seq - 1,
+            entries,
+        };
+        hint.save(&path, config.get_keyring())?;
+
+        self.files.push((index, hint));
+
+        Ok(())
+    }
+
+    /// Moves the staged files into the data directory and returns their new paths. A
+    /// journal is kept while the files are moved, so if this is interrupted the next
+    /// open removes the ones that were already moved and the load is either entirely
+    /// visible or not at all.
+    pub(crate) fn commit(&self, config: &Config) -> Result<Vec<PathBuf>> {
+        let data_dir = PathBuf::from(config.get_full_path());
+        let journal = Journal {
+            files: self
+                .files
+                .iter()
+                .map(|(index, _)| file_name(config, *index))
+                .collect(),
+        };
+
+        let journal_path = config.get_db_path().join(JOURNAL_FILE);
+        let temp_path = journal_path.with_extension("json.tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(&journal)?)?;
+        std::fs::rename(temp_path, &journal_path)?;
+
+        let mut paths = Vec::with_capacity(journal.files.len());
+        for name in &journal.files {
+            let from = self.dir.join(name);
+            let to = data_dir.join(name);
+
+            if let Err(e) = move_with_hint(&from, &to) {
+                rollback(config)?;
+                return Err(e);
+            }
+
+            paths.push(to);
+        }
+
+        // The moves must be durable before the journal goes away
+        File::open(&data_dir)?.sync_all()?;
+        std::fs::remove_file(journal_path)?;
+
+        Ok(paths)
+    }
+}
+
+impl Drop for StagedLoad {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => log::error!(
+                "Could not remove bulk load staging directory {}: {}",
+                self.dir.display(),
+                e
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// Undoes a bulk load that was interrupted while its files were moved into the data
+/// directory. Must run before the data files are read.
+pub(crate) fn recover(config: &Config) -> Result<()> {
+    let staging = config.get_db_path().join(STAGING_DIR);
+    if staging.exists() {
+        std::fs::remove_dir_all(staging)?;
+    }
+
+    if config.get_db_path().join(JOURNAL_FILE).exists() {
+        log::warn!("Rolling back an interrupted bulk load");
+        rollback(config)?;
+    }
+
+    Ok(())
+}
+
+fn rollback(config: &Config) -> Result<()> {
+    let journal_path = config.get_db_path().join(JOURNAL_FILE);
+    let journal: Journal = serde_json::from_slice(&std::fs::read(&journal_path)?)?;
+    let data_dir = PathBuf::from(config.get_full_path());
+
+    for name in &journal.files {
+        let path = data_dir.join(name);
+
+        for path in [hint::hint_path(&path), path] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+
+    std::fs::remove_file(journal_path)?;
+
+    Ok(())
+}
+
+/// The hint goes first, so a data file in the data directory always has its hint.
+fn move_with_hint(from: &Path, to: &Path) -> Result<()> {
+    std::fs::rename(hint::hint_path(from), hint::hint_path(to))?;
+    std::fs::rename(from, to)?;
+
+    Ok(())
+}
+
+fn file_name(config: &Config, index: usize) -> String {
+    format!("{}.{}", index, config.get_data_extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{crypto::Keyring, kv::KiviStore};
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn config(dir: &TempDir) -> Config {
+        Config::new()
+            .set_db_path(dir.path().to_path_buf())
+            .set_max_file_size(1024)
+            .build()
+    }
+
+    fn pairs(n: usize) -> impl Iterator<Item = (String, String)> {
+        (0..n).map(move |i| (format!("key{:04}", n - i), i.to_string()))
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = TempDir::new("bulk").unwrap();
+        let mut store = KiviStore::with_config(config(&dir)).unwrap();
+        store.set("key0001".to_string(), "old".to_string()).unwrap();
+        store.set("other".to_string(), "x".to_string()).unwrap();
+        let subscription = store.subscribe();
+
+        let loaded = store
+            .bulk_load(
+                pairs(100)
+                    .chain([("dup".to_string(), "1".to_string())])
+                    .chain([("dup".to_string(), "2".to_string())]),
+            )
+            .unwrap();
+        assert_eq!(loaded, 101);
+        assert_eq!(
+            std::iter::from_fn(|| subscription.next_timeout(Duration::ZERO)).count(),
+            101
+        );
+
+        // Cut at the rotation size, each file sorted by key and with its hint
+        let files = store.sealed_files().to_vec();
+        assert!(files.len() > 3);
+        for file in &files[1..] {
+            let hint = Hint::load(file, &Keyring::default()).unwrap();
+            let keys: Vec<&String> = hint
+                .entries
+                .iter()
+                .map(|e| match e {
+                    HintEntry::Set { key, .. } => key,
+                    _ => panic!("unexpected entry {:?}", e),
+                })
+                .collect();
+            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        }
+
+        assert_eq!(store.get("key0001".to_string()).unwrap().value, "99");
+        assert_eq!(store.get("dup".to_string()).unwrap().value, "2");
+        assert_eq!(store.get("other".to_string()).unwrap().value, "x");
+        assert_eq!(store.last_seq(), 103);
+
+        store.set("after".to_string(), "y".to_string()).unwrap();
+        drop(store);
+
+        let store = KiviStore::with_config(config(&dir)).unwrap();
+        assert_eq!(store.get("key0100".to_string()).unwrap().value, "0");
+        assert_eq!(store.get("after".to_string()).unwrap().value, "y");
+        assert_eq!(store.last_seq(), 104);
+        assert!(!dir.path().join(STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn test_interrupted_load_is_rolled_back() {
+        let dir = TempDir::new("bulk").unwrap();
+        drop(KiviStore::with_config(config(&dir)).unwrap());
+
+        // Crash after the files were moved, before the journal was removed
+        let staged = stage(&config(&dir), "", pairs(100), 2, 1).unwrap();
+        let paths = staged.commit(&config(&dir)).unwrap();
+        assert!(paths.len() > 1);
+        let journal = Journal {
+            files: paths
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+                .collect(),
+        };
+        std::fs::write(
+            dir.path().join(JOURNAL_FILE),
+            serde_json::to_vec(&journal).unwrap(),
+        )
+        .unwrap();
+
+        let mut store = KiviStore::with_config(config(&dir)).unwrap();
+        assert_eq!(store.get("key0001".to_string()), None);
+        assert!(paths.iter().all(|p| !hint::hint_path(p).exists()));
+        // The first one is the new active file
+        assert_eq!(
+            std::fs::metadata(&paths[0]).unwrap().len(),
+            record::FILE_HEADER_SIZE
+        );
+        assert!(paths[1..].iter().all(|p| !p.exists()));
+        assert!(!dir.path().join(JOURNAL_FILE).exists());
+
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        let subscription = store.subscribe_from(0).unwrap();
+        assert_eq!(subscription.next_timeout(Duration::ZERO).unwrap().seq, 1);
+    }
+}
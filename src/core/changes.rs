@@ -0,0 +1,293 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::core::kv::{self, KiviCommand, DEFAULT_BUCKET};
+
+/// Events a subscription holds before its reader is considered gone. The writer never
+/// waits for a subscriber, it drops the ones that fall this far behind.
+pub const SUBSCRIPTION_CAPACITY: usize = 10_000;
+
+/// A committed write, as it was appended to the data files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    pub command: KiviCommand,
+}
+
+/// Callback that runs after every committed write. Hooks run on the writing thread,
+/// so they should hand the event off instead of doing slow work.
+pub trait WriteHook: Send {
+    /// Returns false once the hook is no longer interested, which unregisters it.
+    fn on_write(&mut self, event: &ChangeEvent) -> bool;
+
+    /// Checked before every event, even the ones the hook would ignore, so a hook
+    /// that is done does not wait for an event it cares about to be unregistered.
+    fn is_active(&self) -> bool {
+        true
+    }
+}
+
+impl<F> WriteHook for F
+where
+    F: FnMut(&ChangeEvent) -> bool + Send,
+{
+    fn on_write(&mut self, event: &ChangeEvent) -> bool {
+        self(event)
+    }
+}
+
+/// Keys of the default bucket a watcher is interested in. Parsed from either an exact
+/// key or a prefix followed by `*`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl KeyPattern {
+    pub fn parse(s: &str) -> Self {
+        match s.strip_suffix('*') {
+            Some(prefix) => KeyPattern::Prefix(prefix.to_string()),
+            None => KeyPattern::Exact(s.to_string()),
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Exact(k) => k == key,
+            KeyPattern::Prefix(p) => key.starts_with(p.as_str()),
+        }
+    }
+
+    /// Returns true if the event changes a key matched by this pattern.
+    pub fn matches_event(&self, event: &ChangeEvent) -> bool {
+        match &event.command {
+            KiviCommand::Set { bucket, key, .. } | KiviCommand::Delete { bucket, key } => {
+                bucket == DEFAULT_BUCKET && self.matches(key)
+            }
+            KiviCommand::DropBucket { bucket } => bucket == DEFAULT_BUCKET,
+        }
+    }
+}
+
+/// Ordered stream of committed writes returned by `KiviStore::subscribe`. Iterating
+/// blocks until the next event arrives and ends when the store is dropped, or when
+/// more than `SUBSCRIPTION_CAPACITY` events are waiting to be read.
+pub struct Subscription {
+    receiver: Receiver<ChangeEvent>,
+
+    /// Set once dropped, so the store forgets the hook at its next write
+    closed: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    /// Creates a subscription that first yields the `backlog` events, together with
+    /// the hook that feeds it. Only events matching `pattern` are forwarded, and writes
+    /// to reserved buckets only if `reserved` is set.
+    pub(crate) fn new(
+        backlog: Vec<ChangeEvent>,
+        pattern: Option<KeyPattern>,
+        reserved: bool,
+    ) -> (Self, Box<dyn WriteHook>) {
+        let (sender, receiver) = sync_channel(SUBSCRIPTION_CAPACITY + backlog.len());
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let hook = ChannelHook {
+            sender,
+            pattern,
+            reserved,
+            closed: closed.clone(),
+        };
+
+        for event in backlog.into_iter().filter(|e| hook.wants(e)) {
+            // Cannot fail, we hold the receiver and there is room for the backlog
+            let _ = hook.sender.try_send(event);
+        }
+
+        (Self { receiver, closed }, Box::new(hook))
+    }
+
+    /// Waits at most `timeout` for the next event.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Like `next_timeout`, telling a timeout from the end of the stream.
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<ChangeEvent, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Forwards events to a subscription without ever blocking the writer. A full channel
+/// ends the subscription.
+struct ChannelHook {
+    sender: SyncSender<ChangeEvent>,
+    pattern: Option<KeyPattern>,
+    reserved: bool,
+    closed: Arc<AtomicBool>,
+}
+
+impl ChannelHook {
+    fn wants(&self, event: &ChangeEvent) -> bool {
+        if !self.reserved && kv::is_reserved_bucket(event.command.bucket()) {
+            return false;
+        }
+
+        match &self.pattern {
+            Some(p) => p.matches_event(event),
+            None => true,
+        }
+    }
+}
+
+impl WriteHook for ChannelHook {
+    fn on_write(&mut self, event: &ChangeEvent) -> bool {
+        match self.wants(event) {
+            false => true,
+            true => match self.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Dropping a subscriber that fell behind");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        !self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// All hooks registered on a store.
+#[derive(Default)]
+pub(crate) struct WriteHooks {
+    hooks: Vec<Box<dyn WriteHook>>,
+}
+
+impl WriteHooks {
+    pub(crate) fn add(&mut self, hook: Box<dyn WriteHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Hooks that did not stop yet.
+    pub(crate) fn active(&self) -> usize {
+        self.hooks.iter().filter(|hook| hook.is_active()).count()
+    }
+
+    /// Runs every hook, forgetting the ones that are done.
+    pub(crate) fn run(&mut self, event: &ChangeEvent) {
+        self.hooks
+            .retain_mut(|hook| hook.is_active() && hook.on_write(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_event(bucket: &str, key: &str) -> ChangeEvent {
+        ChangeEvent {
+            seq: 1,
+            command: KiviCommand::Set {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                value: "v".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_key_pattern() {
+        let exact = KeyPattern::parse("user");
+        let prefix = KeyPattern::parse("user*");
+
+        assert_eq!(prefix, KeyPattern::Prefix("user".to_string()));
+        assert!(exact.matches("user"));
+        assert!(!exact.matches("user:1"));
+        assert!(prefix.matches("user:1"));
+        assert!(!prefix.matches("use"));
+
+        assert!(KeyPattern::parse("*").matches_event(&set_event(DEFAULT_BUCKET, "x")));
+        assert!(!prefix.matches_event(&set_event("other", "user")));
+    }
+
+    #[test]
+    fn test_hooks_unregister() {
+        let mut hooks = WriteHooks::default();
+        let mut calls = 0;
+
+        let (sub, hook) = Subscription::new(Vec::new(), None, false);
+        hooks.add(hook);
+        hooks.add(Box::new(move |_: &ChangeEvent| {
+            calls += 1;
+            calls < 2
+        }));
+
+        hooks.run(&set_event(DEFAULT_BUCKET, "a"));
+        hooks.run(&set_event(DEFAULT_BUCKET, "a"));
+        assert_eq!(hooks.hooks.len(), 1);
+
+        drop(sub);
+        hooks.run(&set_event(DEFAULT_BUCKET, "a"));
+        assert!(hooks.hooks.is_empty());
+    }
+
+    #[test]
+    fn test_lagging_subscription_is_dropped() {
+        let mut hooks = WriteHooks::default();
+
+        let (sub, hook) = Subscription::new(vec![set_event(DEFAULT_BUCKET, "old")], None, false);
+        hooks.add(hook);
+
+        for _ in 0..SUBSCRIPTION_CAPACITY {
+            hooks.run(&set_event(DEFAULT_BUCKET, "a"));
+        }
+        assert_eq!(hooks.active(), 1);
+
+        // One event too many, the writer goes on without it
+        hooks.run(&set_event(DEFAULT_BUCKET, "a"));
+        assert!(hooks.hooks.is_empty());
+
+        // What was queued is still delivered, then the stream ends
+        assert_eq!(sub.count(), SUBSCRIPTION_CAPACITY + 1);
+    }
+
+    #[test]
+    fn test_dropped_watch_unregisters_on_any_write() {
+        let mut hooks = WriteHooks::default();
+
+        let (sub, hook) = Subscription::new(Vec::new(), Some(KeyPattern::parse("never")), false);
+        hooks.add(hook);
+        assert_eq!(hooks.active(), 1);
+
+        drop(sub);
+        assert_eq!(hooks.active(), 0);
+
+        // The write does not match the pattern, the hook still goes
+        hooks.run(&set_event(DEFAULT_BUCKET, "a"));
+        assert!(hooks.hooks.is_empty());
+    }
+}
@@ -1,5 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::core::{
+    crypto::{EncryptionKey, Keyring},
+    error::KiviError,
+    record::Codec,
+};
+
+/// When writes to the active file are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SyncPolicy {
+    /// Left to the operating system, a crash of the machine can lose recent writes
+    #[default]
+    Never,
+
+    /// After every write, which is much slower
+    Always,
+
+    /// With the first write after this much time passed since the last flush
+    Every(Duration),
+}
+
+/// Parses `never`, `always`, or an interval like `1s` or `200ms`.
+impl FromStr for SyncPolicy {
+    type Err = KiviError;
+
+    fn from_str(s: &str) -> Result<Self, KiviError> {
+        let interval = |n: &str| {
+            n.parse::<u64>()
+                .map_err(|_| KiviError::Generic(format!("invalid sync policy {}", s)))
+        };
+
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            s => match s.strip_suffix("ms") {
+                Some(ms) => Ok(SyncPolicy::Every(Duration::from_millis(interval(ms)?))),
+                None => match s.strip_suffix('s') {
+                    Some(secs) => Ok(SyncPolicy::Every(Duration::from_secs(interval(secs)?))),
+                    None => Err(KiviError::Generic(format!("invalid sync policy {}", s))),
+                },
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for SyncPolicy {
+    type Error = KiviError;
+
+    fn try_from(s: String) -> Result<Self, KiviError> {
+        s.parse()
+    }
+}
+
+impl From<SyncPolicy> for String {
+    fn from(p: SyncPolicy) -> String {
+        p.to_string()
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Every(interval) if interval.subsec_millis() == 0 => {
+                write!(f, "{}s", interval.as_secs())
+            }
+            SyncPolicy::Every(interval) => write!(f, "{}ms", interval.as_millis()),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     /// Main Database directory that contains data and hints files
     db_path: PathBuf,
@@ -12,6 +89,18 @@ pub struct Config {
 
     /// Temporary data directory that is used for data compaction
     temp_data_dir: String,
+
+    /// Codec used to compress newly written records
+    codec: Codec,
+
+    /// Keys used to encrypt new records and decrypt existing ones
+    keyring: Keyring,
+
+    /// Size in bytes after which the active file is sealed and a new one is started
+    max_file_size: u64,
+
+    /// When writes are flushed to disk
+    sync_policy: SyncPolicy,
 }
 
 pub struct ConfigBuilder {
@@ -19,6 +108,11 @@ pub struct ConfigBuilder {
     data_dir: String,
     data_extension: String,
     temp_data_dir: String,
+    codec: Codec,
+    encryption_key: Option<EncryptionKey>,
+    previous_encryption_keys: Vec<EncryptionKey>,
+    max_file_size: u64,
+    sync_policy: SyncPolicy,
 }
 
 impl ConfigBuilder {
@@ -42,12 +136,51 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn set_codec(&mut self, c: Codec) -> &mut Self {
+        self.codec = c;
+        self
+    }
+
+    /// Enables encryption at rest. New records are encrypted with this key, and records
+    /// that are not encrypted are refused, so it can't be set on a store written
+    /// without encryption. Export the data and import it into a new store instead.
+    pub fn set_encryption_key(&mut self, k: EncryptionKey) -> &mut Self {
+        self.encryption_key = Some(k);
+        self
+    }
+
+    /// Keys that are only used to read existing records. Compaction re-encrypts them
+    /// with the current key, which is how keys are rotated.
+    pub fn set_previous_encryption_keys(&mut self, ks: Vec<EncryptionKey>) -> &mut Self {
+        self.previous_encryption_keys = ks;
+        self
+    }
+
+    /// Data files are rotated once they reach this size. Records are addressed with 32
+    /// bit offsets, so it is capped at 2 GiB.
+    pub fn set_max_file_size(&mut self, s: u64) -> &mut Self {
+        self.max_file_size = s;
+        self
+    }
+
+    pub fn set_sync_policy(&mut self, p: SyncPolicy) -> &mut Self {
+        self.sync_policy = p;
+        self
+    }
+
     pub fn build(&mut self) -> Config {
         Config {
             db_path: self.db_path.clone(),
             data_dir: self.data_dir.clone(),
             data_extension: self.data_extension.clone(),
             temp_data_dir: self.temp_data_dir.clone(),
+            codec: self.codec,
+            keyring: Keyring::new(
+                self.encryption_key.clone(),
+                self.previous_encryption_keys.clone(),
+            ),
+            max_file_size: self.max_file_size.clamp(1, i32::MAX as u64),
+            sync_policy: self.sync_policy,
         }
     }
 }
@@ -64,6 +197,11 @@ impl Default for ConfigBuilder {
             data_dir,
             data_extension,
             temp_data_dir,
+            codec: Codec::None,
+            encryption_key: None,
+            previous_encryption_keys: Vec::new(),
+            max_file_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::Never,
         }
     }
 }
@@ -115,6 +253,27 @@ impl Config {
         &self.temp_data_dir
     }
 
+    pub fn get_codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn get_keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    pub fn get_max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    pub fn get_sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// The only setting that can change on an open store.
+    pub fn set_sync_policy(&mut self, p: SyncPolicy) {
+        self.sync_policy = p;
+    }
+
     pub fn get_full_path(&self) -> String {
         format!("{}/{}", &self.db_path.to_str().unwrap(), self.data_dir)
     }
@@ -156,6 +315,10 @@ mod tests {
             .build();
 
         assert_eq!(c.temp_data_dir, String::from("temp"));
+        assert_eq!(c.get_codec(), Codec::None);
+        assert_eq!(c.get_max_file_size(), 64 * 1024 * 1024);
+        assert_eq!(c.get_sync_policy(), SyncPolicy::Never);
+        assert!(!c.get_keyring().is_enabled());
         assert_eq!(
             c.get_glob_pattern(),
             String::from("/var/folders/h_/abc/ddd/[0-9]*.filez")
@@ -166,4 +329,20 @@ mod tests {
         );
         assert_eq!(c.get_full_path(), String::from("/var/folders/h_/abc/ddd"))
     }
+
+    #[test]
+    fn test_sync_policy() {
+        for (s, policy) in [
+            ("never", SyncPolicy::Never),
+            ("always", SyncPolicy::Always),
+            ("200ms", SyncPolicy::Every(Duration::from_millis(200))),
+            ("1s", SyncPolicy::Every(Duration::from_secs(1))),
+        ] {
+            assert_eq!(s.parse::<SyncPolicy>().unwrap(), policy);
+            assert_eq!(policy.to_string().parse::<SyncPolicy>().unwrap(), policy);
+        }
+
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+        assert!("xs".parse::<SyncPolicy>().is_err());
+    }
 }
@@ -0,0 +1,222 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use std::path::Path;
+
+use crate::core::error::{KiviError, Result};
+
+/// Size of the key in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// Size of the nonce that is prepended to every encrypted payload.
+const NONCE_SIZE: usize = 12;
+
+/// 256-bit key used for ChaCha20-Poly1305 authenticated encryption of record payloads.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parses a key from 64 hex characters.
+    pub fn from_hex(s: &str) -> Result<Self> {
+        let bytes = hex::decode(s.trim())
+            .map_err(|e| KiviError::InvalidKey(format!("key is not valid hex: {}", e)))?;
+
+        Self::from_slice(&bytes)
+    }
+
+    /// Loads a key from a file containing either 32 raw bytes or 64 hex characters.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+
+        if bytes.len() == KEY_SIZE {
+            return Self::from_slice(&bytes);
+        }
+
+        match std::str::from_utf8(&bytes) {
+            Ok(s) => Self::from_hex(s),
+            Err(_) => Err(KiviError::InvalidKey(format!(
+                "key file must contain {} bytes or {} hex characters",
+                KEY_SIZE,
+                KEY_SIZE * 2
+            ))),
+        }
+    }
+
+    /// Loads a hex encoded key from an environment variable.
+    pub fn from_env(var: &str) -> Result<Self> {
+        let value = std::env::var(var).map_err(|_| {
+            KiviError::InvalidKey(format!("environment variable {} is not set", var))
+        })?;
+
+        Self::from_hex(&value)
+    }
+
+    fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let key: [u8; KEY_SIZE] = bytes.try_into().map_err(|_| {
+            KiviError::InvalidKey(format!(
+                "key must be {} bytes, got {}",
+                KEY_SIZE,
+                bytes.len()
+            ))
+        })?;
+
+        Ok(Self(key))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new((&self.0).into())
+    }
+}
+
+// Never print key material
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// The key new records are encrypted with, plus older keys that are still accepted
+/// when reading. Compaction re-encrypts everything with the current key, after which
+/// the previous keys can be dropped.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    current: Option<EncryptionKey>,
+    previous: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    pub fn new(current: Option<EncryptionKey>, previous: Vec<EncryptionKey>) -> Self {
+        Self { current, previous }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Encrypts with the current key. The output is the nonce followed by the
+    /// ciphertext and its authentication tag, which also covers `aad`.
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let key = self
+            .current
+            .as_ref()
+            .ok_or_else(|| KiviError::Generic("no encryption key configured".to_string()))?;
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| KiviError::Generic("encryption failed".to_string()))?;
+
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+
+        Ok(out)
+    }
+
+    /// Decrypts with the current key, falling back to the previous keys. Fails unless
+    /// `aad` is the one given to `encrypt`.
+    pub fn decrypt(&self, input: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if input.len() < NONCE_SIZE {
+            return Err(KiviError::Corrupted(
+                "encrypted payload too short".to_string(),
+            ));
+        }
+
+        if self.current.is_none() && self.previous.is_empty() {
+            return Err(KiviError::Decryption(
+                "record is encrypted but no encryption key is configured".to_string(),
+            ));
+        }
+
+        let (nonce, ciphertext) = input.split_at(NONCE_SIZE);
+        let nonce = Nonce::from_slice(nonce);
+
+        self.current
+            .iter()
+            .chain(self.previous.iter())
+            .find_map(|key| {
+                key.cipher()
+                    .decrypt(
+                        nonce,
+                        Payload {
+                            msg: ciphertext,
+                            aad,
+                        },
+                    )
+                    .ok()
+            })
+            .ok_or_else(|| {
+                KiviError::Decryption("wrong encryption key or tampered record".to_string())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let keyring = Keyring::new(Some(EncryptionKey::new([1; KEY_SIZE])), vec![]);
+
+        let encrypted = keyring.encrypt(b"secret", b"aad").unwrap();
+
+        assert_ne!(&encrypted[NONCE_SIZE..], b"secret");
+        assert_eq!(keyring.decrypt(&encrypted, b"aad").unwrap(), b"secret");
+        assert!(matches!(
+            keyring.decrypt(&encrypted, b"other"),
+            Err(KiviError::Decryption(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let keyring = Keyring::new(Some(EncryptionKey::new([1; KEY_SIZE])), vec![]);
+        let other = Keyring::new(Some(EncryptionKey::new([2; KEY_SIZE])), vec![]);
+
+        let encrypted = keyring.encrypt(b"secret", b"aad").unwrap();
+
+        assert!(matches!(
+            other.decrypt(&encrypted, b"aad"),
+            Err(KiviError::Decryption(_))
+        ));
+        assert!(matches!(
+            Keyring::default().decrypt(&encrypted, b"aad"),
+            Err(KiviError::Decryption(_))
+        ));
+    }
+
+    #[test]
+    fn test_previous_key() {
+        let old = Keyring::new(Some(EncryptionKey::new([1; KEY_SIZE])), vec![]);
+        let rotated = Keyring::new(
+            Some(EncryptionKey::new([2; KEY_SIZE])),
+            vec![EncryptionKey::new([1; KEY_SIZE])],
+        );
+
+        let encrypted = old.encrypt(b"secret", b"aad").unwrap();
+
+        assert_eq!(rotated.decrypt(&encrypted, b"aad").unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_from_hex() {
+        let key = EncryptionKey::from_hex(&"ab".repeat(KEY_SIZE)).unwrap();
+
+        assert_eq!(key, EncryptionKey::new([0xab; KEY_SIZE]));
+        assert!(EncryptionKey::from_hex("abcd").is_err());
+        assert!(EncryptionKey::from_hex("zz").is_err());
+    }
+}
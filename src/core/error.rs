@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::core::record::Codec;
+
 #[derive(Error, Debug)]
 pub enum KiviError {
     #[error("Error: {0}")]
@@ -11,8 +13,53 @@ pub enum KiviError {
     #[error("Serde_json error: {0}")]
     Serde(#[from] serde_json::Error),
 
+    #[error("Csv error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Toml error: {0}")]
+    Toml(#[from] toml::de::Error),
+
     #[error("GlobPatternError error: {0}")]
     GlobPatternError(#[from] glob::PatternError),
+
+    #[error("Key not found: {0}")]
+    KeyNotFound(String),
+
+    #[error("Value of key {0} is not an integer")]
+    NotAnInteger(String),
+
+    #[error("Integer overflow for key {0}")]
+    IntegerOverflow(String),
+
+    #[error("Corrupted record: {0}")]
+    Corrupted(String),
+
+    #[error("Unsupported data file format: {0}")]
+    UnsupportedFormat(String),
+
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("Could not decrypt record: {0}")]
+    Decryption(String),
+
+    #[error("Codec {0:?} is not enabled, rebuild with the matching cargo feature")]
+    UnsupportedCodec(Codec),
+
+    #[error("Bucket name {0:?} is reserved")]
+    ReservedBucket(String),
+
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    #[error("Not the cluster leader, current leader: {0:?}")]
+    NotLeader(Option<String>),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
 }
 
 pub type Result<T> = std::result::Result<T, KiviError>;
@@ -0,0 +1,363 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+use crate::core::{
+    error::{KiviError, Result},
+    kv::{self, KiviCommand, KiviStore},
+};
+
+/// Logical format of an export, one key/value pair per line or row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `{"bucket":"b","key":"k","value":"v"}`, the bucket is omitted for the default one
+    Jsonl,
+
+    /// `bucket,key,value` with a header row
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = KiviError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(KiviError::Generic(format!("unknown format: {}", s))),
+        }
+    }
+}
+
+/// What to do when an imported key already exists.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    #[default]
+    Overwrite,
+    SkipExisting,
+}
+
+impl FromStr for ImportMode {
+    type Err = KiviError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "overwrite" => Ok(ImportMode::Overwrite),
+            "skip-existing" => Ok(ImportMode::SkipExisting),
+            _ => Err(KiviError::Generic(format!("unknown import mode: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ImportStats {
+    pub imported: usize,
+    pub skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Row {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    bucket: String,
+    key: String,
+    value: String,
+}
+
+/// Writes every live pair whose key starts with `prefix`, across all buckets, sorted by
+/// bucket then key. Returns the number of exported pairs.
+pub fn export<W: Write>(store: &KiviStore, out: W, format: Format, prefix: &str) -> Result<usize> {
+    let rows = store.records(prefix).map(|record| match record? {
+        KiviCommand::Set { bucket, key, value } => Ok(Row { bucket, key, value }),
+        command => Err(KiviError::Corrupted(format!(
+            "index points to {:?}",
+            command
+        ))),
+    });
+
+    let mut count = 0;
+
+    match format {
+        Format::Jsonl => {
+            let mut out = std::io::BufWriter::new(out);
+
+            for row in rows {
+                serde_json::to_writer(&mut out, &row?)?;
+                out.write_all(b"\n")?;
+                count += 1;
+            }
+
+            out.flush()?;
+        }
+        Format::Csv => {
+            let mut out = csv::Writer::from_writer(out);
+            out.write_record(["bucket", "key", "value"])?;
+
+            for row in rows {
+                let row = row?;
+                out.write_record([&row.bucket, &row.key, &row.value])?;
+                count += 1;
+            }
+
+            out.flush()?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Reads pairs written by `export` and stores them with a single write batch. Nothing
+/// is written if any row is invalid, or targets a reserved bucket.
+pub fn import<R: Read>(
+    store: &mut KiviStore,
+    input: R,
+    format: Format,
+    mode: ImportMode,
+) -> Result<ImportStats> {
+    let rows = read_rows(input, format)?;
+
+    if let Some(row) = rows.iter().find(|row| kv::is_reserved_bucket(&row.bucket)) {
+        return Err(KiviError::ReservedBucket(row.bucket.clone()));
+    }
+
+    let mut stats = ImportStats::default();
+    let mut seen = HashSet::new();
+    let mut batch = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        if mode == ImportMode::SkipExisting {
+            // The first occurrence wins, in the store or in the input
+            let new = seen.insert((row.bucket.clone(), row.key.clone()));
+
+            if !new || store.contains(&row.bucket, &row.key) {
+                stats.skipped += 1;
+                continue;
+            }
+        }
+
+        batch.push(KiviCommand::Set {
+            bucket: row.bucket,
+            key: row.key,
+            value: row.value,
+        });
+        stats.imported += 1;
+    }
+
+    store.write_batch(batch)?;
+
+    Ok(stats)
+}
+
+fn read_rows<R: Read>(input: R, format: Format) -> Result<Vec<Row>> {
+    match format {
+        Format::Jsonl => {
+            let mut rows = Vec::new();
+
+            for (n, line) in BufReader::new(input).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let row = serde_json::from_str(&line).map_err(|e| {
+                    KiviError::Generic(format!("invalid record on line {}: {}", n + 1, e))
+                })?;
+                rows.push(row);
+            }
+
+            Ok(rows)
+        }
+        Format::Csv => Ok(csv::Reader::from_reader(input)
+            .deserialize()
+            .collect::<std::result::Result<_, _>>()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::Config;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn open(dir: &TempDir) -> KiviStore {
+        KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build()).unwrap()
+    }
+
+    fn sample(store: &mut KiviStore) {
+        store.set("user:1".to_string(), "ann".to_string()).unwrap();
+        store
+            .set("user:2".to_string(), "bob, \"jr\"".to_string())
+            .unwrap();
+        store.set("other".to_string(), "x".to_string()).unwrap();
+        store
+            .bucket("b")
+            .unwrap()
+            .set("user:3".to_string(), "cid".to_string())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let dir = TempDir::new("export").unwrap();
+        let mut store = open(&dir);
+        sample(&mut store);
+
+        let mut out = Vec::new();
+        assert_eq!(export(&store, &mut out, Format::Jsonl, "user:").unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"{"key":"user:1","value":"ann"}"#,
+                "\n",
+                r#"{"key":"user:2","value":"bob, \"jr\""}"#,
+                "\n",
+                r#"{"bucket":"b","key":"user:3","value":"cid"}"#,
+                "\n",
+            )
+        );
+
+        let mut out = Vec::new();
+        export(&store, &mut out, Format::Csv, "user:").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "bucket,key,value\n,user:1,ann\n,user:2,\"bob, \"\"jr\"\"\"\nb,user:3,cid\n"
+        );
+    }
+
+    #[test]
+    fn test_export_skips_expiry() {
+        let dir = TempDir::new("export").unwrap();
+        let mut store = open(&dir);
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        store.expire("a".to_string(), Duration::ZERO).unwrap();
+        store
+            .expire("b".to_string(), Duration::from_secs(3600))
+            .unwrap();
+
+        // Neither the expired key nor the deadlines, which are store metadata
+        let mut out = Vec::new();
+        assert_eq!(export(&store, &mut out, Format::Jsonl, "").unwrap(), 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"key\":\"b\",\"value\":\"2\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_import_clears_expiry() {
+        let dir = TempDir::new("import").unwrap();
+        let mut store = open(&dir);
+        store.set("a".to_string(), "old".to_string()).unwrap();
+        store.set("b".to_string(), "old".to_string()).unwrap();
+        store.expire("a".to_string(), Duration::ZERO).unwrap();
+        store
+            .expire("b".to_string(), Duration::from_secs(3600))
+            .unwrap();
+
+        // The expired key is gone, so it is not skipped
+        let input = "{\"key\":\"a\",\"value\":\"new\"}\n";
+        let stats = import(
+            &mut store,
+            input.as_bytes(),
+            Format::Jsonl,
+            ImportMode::SkipExisting,
+        )
+        .unwrap();
+        assert_eq!(stats.imported, 1);
+        assert_eq!(store.get("a".to_string()).unwrap().value, "new");
+        assert_eq!(store.ttl("a").unwrap(), None);
+
+        let input = "{\"key\":\"b\",\"value\":\"new\"}\n";
+        import(
+            &mut store,
+            input.as_bytes(),
+            Format::Jsonl,
+            ImportMode::Overwrite,
+        )
+        .unwrap();
+        assert_eq!(store.ttl("b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for format in [Format::Jsonl, Format::Csv] {
+            let dir = TempDir::new("export").unwrap();
+            let mut store = open(&dir);
+            sample(&mut store);
+
+            let mut out = Vec::new();
+            export(&store, &mut out, format, "").unwrap();
+
+            let target_dir = TempDir::new("import").unwrap();
+            let mut target = open(&target_dir);
+            let stats = import(&mut target, out.as_slice(), format, ImportMode::Overwrite).unwrap();
+            assert_eq!(stats.imported, 4);
+
+            assert_eq!(
+                target.live_commands().unwrap(),
+                store.live_commands().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_modes() {
+        let dir = TempDir::new("import").unwrap();
+        let mut store = open(&dir);
+        store.set("a".to_string(), "old".to_string()).unwrap();
+
+        let input = "{\"key\":\"a\",\"value\":\"new\"}\n{\"key\":\"b\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\n";
+
+        let stats = import(
+            &mut store,
+            input.as_bytes(),
+            Format::Jsonl,
+            ImportMode::SkipExisting,
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                imported: 1,
+                skipped: 2
+            }
+        );
+        assert_eq!(store.get("a".to_string()).unwrap().value, "old");
+        assert_eq!(store.get("b".to_string()).unwrap().value, "1");
+
+        import(
+            &mut store,
+            input.as_bytes(),
+            Format::Jsonl,
+            ImportMode::Overwrite,
+        )
+        .unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap().value, "new");
+        assert_eq!(store.get("b".to_string()).unwrap().value, "2");
+
+        // Invalid input writes nothing
+        let last_seq = store.last_seq();
+        let res = import(
+            &mut store,
+            "{\"key\":\"c\",\"value\":\"1\"}\nnot json\n".as_bytes(),
+            Format::Jsonl,
+            ImportMode::Overwrite,
+        );
+        assert!(matches!(res, Err(KiviError::Generic(e)) if e.contains("line 2")));
+        assert_eq!(store.last_seq(), last_seq);
+
+        // Store metadata cannot be imported
+        let res = import(
+            &mut store,
+            "{\"key\":\"c\",\"value\":\"1\"}\n{\"bucket\":\"__expiry\",\"key\":\"a\",\"value\":\"0\"}\n"
+                .as_bytes(),
+            Format::Jsonl,
+            ImportMode::Overwrite,
+        );
+        assert!(matches!(res, Err(KiviError::ReservedBucket(b)) if b == "__expiry"));
+        assert_eq!(store.last_seq(), last_seq);
+        assert_eq!(store.get("a".to_string()).unwrap().value, "new");
+    }
+}
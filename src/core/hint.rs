@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use crate::core::{
+    crypto::Keyring,
+    error::{KiviError, Result},
+    kv::KiviCommand,
+    record,
+};
+
+/// Extension of the hint file written next to every sealed data file.
+pub const HINT_EXTENSION: &str = "hint";
+
+/// Authenticated along with encrypted hints, so a record payload can't pass for one.
+const HINT_AAD: &[u8] = b"kivi hint";
+
+/// Summary of a sealed data file, so opening a store does not have to decode every
+/// record. Stored as `| crc32 (4) | body |`, where the body is JSON, encrypted with the
+/// store keys when encryption is enabled so keys are not leaked in plain text.
+///
+/// A hint is only trusted if its checksum is valid and the data file still has the
+/// size the hint was built from, otherwise the data file is scanned instead.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct Hint {
+    pub(crate) file_size: u64,
+    pub(crate) last_seq: u64,
+    pub(crate) entries: Vec<HintEntry>,
+}
+
+/// A record of the data file, without its value.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) enum HintEntry {
+    Set {
+        bucket: String,
+        key: String,
+        pos: i32,
+        size: i32,
+    },
+    Delete {
+        bucket: String,
+        key: String,
+    },
+    DropBucket {
+        bucket: String,
+    },
+}
+
+impl Hint {
+    /// Builds the hint of a data file by decoding all of its records.
+    pub(crate) fn scan(data_file: &Path, keyring: &Keyring) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(data_file)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = std::io::BufReader::new(file);
+
+        let mut entries = Vec::new();
+        let mut last_seq = 0;
+        let mut pos = record::read_file_header(&mut reader)? as i32;
+
+        while let Some(buf) = record::read_next(&mut reader)? {
+            let size = buf.len() as i32;
+            last_seq = last_seq.max(record::sequence(&buf));
+
+            entries.push(match record::decode(&buf, keyring)? {
+                KiviCommand::Set { bucket, key, .. } => HintEntry::Set {
+                    bucket,
+                    key,
+                    pos,
+                    size,
+                },
+                KiviCommand::Delete { bucket, key } => HintEntry::Delete { bucket, key },
+                KiviCommand::DropBucket { bucket } => HintEntry::DropBucket { bucket },
+            });
+
+            pos += size;
+        }
+
+        Ok(Self {
+            file_size,
+            last_seq,
+            entries,
+        })
+    }
+
+    /// Reads the hint of a data file. Returns None if there is no usable hint.
+    pub(crate) fn load(data_file: &Path, keyring: &Keyring) -> Option<Self> {
+        let bytes = std::fs::read(hint_path(data_file)).ok()?;
+        let file_size = std::fs::metadata(data_file).ok()?.len();
+
+        match Self::decode(&bytes, keyring) {
+            Ok(hint) if hint.file_size == file_size => Some(hint),
+            Ok(_) => {
+                log::warn!("Ignoring stale hint of {}", data_file.display());
+                None
+            }
+            Err(e) => {
+                log::warn!("Ignoring hint of {}: {}", data_file.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Writes the hint of a data file atomically.
+    pub(crate) fn save(&self, data_file: &Path, keyring: &Keyring) -> Result<()> {
+        let mut body = serde_json::to_vec(self)?;
+        if keyring.is_enabled() {
+            body = keyring.encrypt(&body, HINT_AAD)?;
+        }
+
+        let mut bytes = crc32fast::hash(&body).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&body);
+
+        let path = hint_path(data_file);
+        let temp_path = path.with_extension("hint.tmp");
+
+        std::fs::write(&temp_path, bytes)?;
+        std::fs::rename(temp_path, path)?;
+
+        Ok(())
+    }
+
+    fn decode(bytes: &[u8], keyring: &Keyring) -> Result<Self> {
+        if bytes.len() < 4 {
+            return Err(KiviError::Corrupted("hint file is truncated".to_string()));
+        }
+
+        let (crc, body) = bytes.split_at(4);
+        if crc32fast::hash(body).to_le_bytes() != crc {
+            return Err(KiviError::Corrupted("hint checksum mismatch".to_string()));
+        }
+
+        if keyring.is_enabled() {
+            Ok(serde_json::from_slice(&keyring.decrypt(body, HINT_AAD)?)?)
+        } else {
+            Ok(serde_json::from_slice(body)?)
+        }
+    }
+}
+
+pub(crate) fn hint_path(data_file: &Path) -> PathBuf {
+    data_file.with_extension(HINT_EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::EncryptionKey;
+    use crate::core::record::Codec;
+    use tempdir::TempDir;
+
+    fn write_records(path: &Path, keyring: &Keyring) {
+        let commands = [
+            KiviCommand::Set {
+                bucket: String::new(),
+                key: "a".to_string(),
+                value: "1".to_string(),
+            },
+            KiviCommand::Delete {
+                bucket: String::new(),
+                key: "a".to_string(),
+            },
+        ];
+
+        let mut bytes = record::file_header().to_vec();
+        for (seq, command) in commands.iter().enumerate() {
+            bytes.extend(record::encode(command, seq as u64 + 1, Codec::None, keyring).unwrap());
+        }
+
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_hint_roundtrip() {
+        let dir = TempDir::new("hint").unwrap();
+        let path = dir.path().join("1.log");
+        let keyring = Keyring::new(Some(EncryptionKey::new([7; 32])), Vec::new());
+
+        write_records(&path, &keyring);
+
+        let hint = Hint::scan(&path, &keyring).unwrap();
+        assert_eq!(hint.last_seq, 2);
+        assert_eq!(hint.entries.len(), 2);
+
+        hint.save(&path, &keyring).unwrap();
+        assert_eq!(Hint::load(&path, &keyring), Some(hint));
+
+        // Keys are not readable without the encryption key
+        let raw = std::fs::read(hint_path(&path)).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("Set"));
+        assert_eq!(Hint::load(&path, &Keyring::default()), None);
+    }
+
+    #[test]
+    fn test_stale_or_corrupted_hint_is_ignored() {
+        let dir = TempDir::new("hint").unwrap();
+        let path = dir.path().join("1.log");
+        let keyring = Keyring::default();
+
+        write_records(&path, &keyring);
+        Hint::scan(&path, &keyring)
+            .unwrap()
+            .save(&path, &keyring)
+            .unwrap();
+
+        // The data file grew after the hint was written
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend(
+            record::encode(
+                &KiviCommand::DropBucket {
+                    bucket: "b".to_string(),
+                },
+                3,
+                Codec::None,
+                &keyring,
+            )
+            .unwrap(),
+        );
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(Hint::load(&path, &keyring), None);
+
+        // Flipped bit in the hint
+        let hint = Hint::scan(&path, &keyring).unwrap();
+        hint.save(&path, &keyring).unwrap();
+        let mut raw = std::fs::read(hint_path(&path)).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        std::fs::write(hint_path(&path), raw).unwrap();
+        assert_eq!(Hint::load(&path, &keyring), None);
+    }
+}
@@ -0,0 +1,241 @@
+use serde::Serialize;
+use std::fs::File;
+use std::path::Path;
+
+use crate::core::{
+    error::Result,
+    kv::{KiviCommand, KiviStore},
+    record,
+};
+
+/// Number of characters of a value shown by the inspector.
+pub const PREVIEW_LENGTH: usize = 32;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    Set,
+    Tombstone,
+    DropBucket,
+
+    /// Could not be decoded, see the error
+    Corrupted,
+}
+
+/// A single record of a data file, as decoded by the inspector.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RecordInfo {
+    pub offset: u64,
+
+    /// Size of the record, header included
+    pub size: u64,
+    pub seq: Option<u64>,
+    pub kind: RecordKind,
+    pub checksum_ok: bool,
+
+    /// True if the store index no longer points to this record: a newer write of the
+    /// key superseded it. None when the file is not part of the store.
+    pub shadowed: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+
+    /// At most `PREVIEW_LENGTH` characters of the value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_preview: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Decodes a data file record by record, the same way the index is built, and
+/// compares every record with the current index of `store`. Unlike opening the store,
+/// a bad record does not stop the inspection, only a truncated one or a file of an
+/// unsupported format does.
+pub fn inspect(store: &KiviStore, path: &Path) -> Result<Vec<RecordInfo>> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = std::io::BufReader::new(file);
+
+    let in_store = is_store_file(store, path);
+    let mut records = Vec::new();
+    let mut offset = record::read_file_header(&mut reader)?;
+
+    loop {
+        let buf = match record::read_next(&mut reader) {
+            Ok(Some(buf)) => buf,
+            Ok(None) => break,
+            Err(e) => {
+                records.push(RecordInfo {
+                    offset,
+                    size: file_size - offset,
+                    seq: None,
+                    kind: RecordKind::Corrupted,
+                    checksum_ok: false,
+                    shadowed: None,
+                    bucket: None,
+                    key: None,
+                    value_preview: None,
+                    error: Some(e.to_string()),
+                });
+                break;
+            }
+        };
+
+        let mut info = RecordInfo {
+            offset,
+            size: buf.len() as u64,
+            seq: Some(record::sequence(&buf)),
+            kind: RecordKind::Corrupted,
+            checksum_ok: record::checksum_matches(&buf),
+            shadowed: None,
+            bucket: None,
+            key: None,
+            value_preview: None,
+            error: None,
+        };
+
+        match record::decode(&buf, store.get_config().get_keyring()) {
+            Ok(KiviCommand::Set { bucket, key, value }) => {
+                info.kind = RecordKind::Set;
+                info.shadowed = in_store.then(|| !points_here(store, &bucket, &key, path, offset));
+                info.value_preview = Some(preview(&value));
+                info.bucket = Some(bucket);
+                info.key = Some(key);
+            }
+            Ok(KiviCommand::Delete { bucket, key }) => {
+                info.kind = RecordKind::Tombstone;
+                // A tombstone is superseded once the key is written again
+                info.shadowed = in_store.then(|| store.location(&bucket, &key).is_some());
+                info.bucket = Some(bucket);
+                info.key = Some(key);
+            }
+            Ok(KiviCommand::DropBucket { bucket }) => {
+                info.kind = RecordKind::DropBucket;
+                info.bucket = Some(bucket);
+            }
+            Err(e) => info.error = Some(e.to_string()),
+        }
+
+        offset += buf.len() as u64;
+        records.push(info);
+    }
+
+    Ok(records)
+}
+
+fn is_store_file(store: &KiviStore, path: &Path) -> bool {
+    let data_dir = std::fs::canonicalize(store.get_config().get_full_path());
+    let parent = path.parent().map(std::fs::canonicalize);
+
+    matches!((data_dir, parent), (Ok(a), Some(Ok(b))) if a == b)
+}
+
+fn points_here(store: &KiviStore, bucket: &str, key: &str, path: &Path, offset: u64) -> bool {
+    match store.location(bucket, key) {
+        Some((file, pos)) => pos == offset && file.file_name() == path.file_name(),
+        None => false,
+    }
+}
+
+fn preview(value: &str) -> String {
+    match value.char_indices().nth(PREVIEW_LENGTH) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::Config;
+    use tempdir::TempDir;
+
+    fn open(dir: &TempDir) -> KiviStore {
+        KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build()).unwrap()
+    }
+
+    #[test]
+    fn test_inspect() {
+        let dir = TempDir::new("inspect").unwrap();
+        let mut store = open(&dir);
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("a".to_string(), "x".repeat(40)).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        store.delete("b".to_string()).unwrap();
+        store.set("c".to_string(), "3".to_string()).unwrap();
+        store.delete("c".to_string()).unwrap();
+        store.set("c".to_string(), "4".to_string()).unwrap();
+
+        let path = store.data_files().unwrap().pop().unwrap();
+        let records = inspect(&store, &path).unwrap();
+
+        let summary: Vec<(RecordKind, Option<bool>)> =
+            records.iter().map(|r| (r.kind, r.shadowed)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (RecordKind::Set, Some(true)),
+                (RecordKind::Set, Some(false)),
+                (RecordKind::Set, Some(true)),
+                (RecordKind::Tombstone, Some(false)),
+                (RecordKind::Set, Some(true)),
+                (RecordKind::Tombstone, Some(true)),
+                (RecordKind::Set, Some(false)),
+            ]
+        );
+
+        assert_eq!(records[0].offset, record::FILE_HEADER_SIZE);
+        assert_eq!(records[1].offset, records[0].offset + records[0].size);
+        assert_eq!(records[1].seq, Some(2));
+        assert_eq!(
+            records[1].value_preview,
+            Some(format!("{}...", "x".repeat(PREVIEW_LENGTH)))
+        );
+        assert!(records.iter().all(|r| r.checksum_ok));
+
+        // A copy outside of the store cannot be compared with the index
+        let copy = dir.path().join("copy.log");
+        std::fs::copy(&path, &copy).unwrap();
+        assert!(inspect(&store, &copy)
+            .unwrap()
+            .iter()
+            .all(|r| r.shadowed.is_none()));
+    }
+
+    #[test]
+    fn test_inspect_corrupted_file() {
+        let dir = TempDir::new("inspect").unwrap();
+        let mut store = open(&dir);
+        store.set("a".to_string(), "1".to_string()).unwrap();
+        store.set("b".to_string(), "2".to_string()).unwrap();
+        store.set("c".to_string(), "3".to_string()).unwrap();
+
+        let original = store.data_files().unwrap().pop().unwrap();
+        let first = inspect(&store, &original).unwrap()[0].size as usize;
+        let mut bytes = std::fs::read(original).unwrap();
+
+        // Flipped bit in the first value, and the last record cut short
+        bytes[first - 3] ^= 1;
+        bytes.truncate(bytes.len() - 2);
+        let path = dir.path().join("broken.log");
+        std::fs::write(&path, bytes).unwrap();
+
+        let records = inspect(&store, &path).unwrap();
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0].kind, RecordKind::Corrupted);
+        assert!(!records[0].checksum_ok);
+        assert_eq!(
+            records[0].error.as_deref(),
+            Some("Corrupted record: checksum mismatch")
+        );
+
+        assert_eq!(records[1].kind, RecordKind::Set);
+        assert_eq!(records[1].key.as_deref(), Some("b"));
+
+        assert_eq!(records[2].kind, RecordKind::Corrupted);
+        assert_eq!(records[2].seq, None);
+    }
+}
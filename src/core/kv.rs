@@ -1,21 +1,48 @@
 use glob::glob;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::io::prelude::*;
-use std::path::PathBuf;
-use std::{collections::BTreeMap, fs::File, fs::OpenOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs::File, fs::OpenOptions};
 
 use crate::core::{
-    config::Config,
+    bucket::{Bucket, BucketStats},
+    bulk,
+    changes::{ChangeEvent, KeyPattern, Subscription, WriteHook, WriteHooks},
+    config::{Config, SyncPolicy},
     error::{KiviError, Result},
+    hint::{self, Hint, HintEntry},
+    meta::StoreMeta,
+    record,
+    stats::{FileStats, StoreStats},
 };
 use log;
 
+/// Name of the bucket used by the top level `KiviStore` operations.
+pub const DEFAULT_BUCKET: &str = "";
+
+/// Bucket holding the deadline of every expiring key of the default bucket, in
+/// milliseconds since the Unix epoch.
+pub const EXPIRY_BUCKET: &str = "__expiry";
+
+/// Buckets whose name starts with this hold the store's own metadata, like
+/// `EXPIRY_BUCKET`. They cannot be opened or dropped by users.
+pub const RESERVED_BUCKET_PREFIX: &str = "__";
+
 pub struct KiviStore {
-    mem_index: BTreeMap<String, InternalRecord>,
+    mem_index: KeyDir,
     active_file: File,
     stale_files: Vec<PathBuf>,
     config: Config,
+
+    /// Sequence number of the next appended record
+    next_seq: u64,
+    hooks: WriteHooks,
+    meta: StoreMeta,
+
+    /// Last time the active file was flushed to disk, see `SyncPolicy`
+    last_sync: Instant,
 }
 
 #[derive(Debug)]
@@ -25,13 +52,40 @@ struct InternalRecord {
     value_pos: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// In memory index of the live records, keyed by bucket name and key.
+type KeyDir = BTreeMap<(String, String), InternalRecord>;
+
+/// A single record in the data files. The bucket is omitted for the default bucket,
+/// which keeps records written before buckets existed readable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum KiviCommand {
-    Set { key: String, value: String },
-    Delete { key: String },
+    Set {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        bucket: String,
+        key: String,
+        value: String,
+    },
+    Delete {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        bucket: String,
+        key: String,
+    },
+    DropBucket {
+        bucket: String,
+    },
+}
+
+impl KiviCommand {
+    pub fn bucket(&self) -> &str {
+        match self {
+            KiviCommand::Set { bucket, .. }
+            | KiviCommand::Delete { bucket, .. }
+            | KiviCommand::DropBucket { bucket } => bucket,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KeyValue {
     pub key: String,
     pub value: String,
@@ -49,8 +103,13 @@ impl KiviStore {
     }
 
     fn initialize(config: Config) -> Result<Self> {
+        if !config.get_codec().is_supported() {
+            return Err(KiviError::UnsupportedCodec(config.get_codec()));
+        }
+
         // Create directories if they dont exist
         Self::create_directories(&config)?;
+        bulk::recover(&config)?;
 
         let stale_file_list = data_files_sorted(&config)?;
         let new_active_file_index = last_file_index(&stale_file_list) + 1;
@@ -58,426 +117,2134 @@ impl KiviStore {
 
         log::info!("Current active file index: {}", new_active_file_index);
 
-        let active_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(config.new_active_file_path(new_active_file_index))?;
+        let active_file =
+            record::open_data_file(config.new_active_file_path(new_active_file_index))?;
 
-        let mem_index = build_index(&stale_files)?;
+        let (mem_index, last_seq) = build_index(&stale_files, &config)?;
+        let meta = StoreMeta::load(&config)?;
+        let next_seq = last_seq.max(meta.last_seq) + 1;
 
         Ok(Self {
             mem_index,
             active_file,
             stale_files,
             config,
+            next_seq,
+            hooks: WriteHooks::default(),
+            meta,
+            last_sync: Instant::now(),
         })
     }
 
     pub fn new() -> Result<Self> {
-        let res = Self::initialize(Config::default())?;
-
-        Ok(Self {
-            mem_index: res.mem_index,
-            active_file: res.active_file,
-            stale_files: res.stale_files,
-            config: res.config,
-        })
+        Self::initialize(Config::default())
     }
 
     pub fn with_config(config: Config) -> Result<Self> {
-        let res = Self::initialize(config)?;
-
-        Ok(Self {
-            mem_index: res.mem_index,
-            active_file: res.active_file,
-            stale_files: res.stale_files,
-            config: res.config,
-        })
+        Self::initialize(config)
     }
 
-    fn get_internal(&self, record: &InternalRecord) -> Result<KiviCommand> {
-        // Read from file
+    /// Reads the raw bytes of a record, header included.
+    fn read_raw(&self, record: &InternalRecord) -> Result<Vec<u8>> {
         let mut file = OpenOptions::new()
             .read(true)
             .open(record.file_id.as_str())?;
 
-        // We use String as a buffer
-        let mut s = String::new();
-        file.read_to_string(&mut s)?;
+        file.seek(SeekFrom::Start(record.value_pos as u64))?;
 
-        let get = s
-            .get(record.value_pos as usize..record.value_pos as usize + record.value_size as usize);
+        let mut buf = vec![0; record.value_size as usize];
+        file.read_exact(&mut buf)?;
 
-        match get {
-            Some(x) => Ok(serde_json::from_str(x)?),
-            None => Err(KiviError::Generic("Internal failed".to_string())),
-        }
+        Ok(buf)
+    }
+
+    fn get_internal(&self, record: &InternalRecord) -> Result<KiviCommand> {
+        let buf = self.read_raw(record)?;
+
+        // Decompresses transparently, whatever codec the record was written with
+        record::decode(&buf, self.config.get_keyring())
     }
 
     pub fn get(&self, key: String) -> Option<KeyValue> {
-        log::trace!("GET command key: {}", key);
-
-        match self.mem_index.get(&key) {
-            Some(i) => match self.get_internal(i) {
-                Ok(kv) => {
-                    if let KiviCommand::Set { key, value } = kv {
-                        Some(KeyValue { key, value })
-                    } else {
-                        None
-                    }
-                }
-                Err(_) => None,
-            },
-            None => None,
-        }
+        self.get_in(DEFAULT_BUCKET, key)
     }
 
+    /// Sets `key` to `value`, clearing any expiry it had. Both are written at once, so
+    /// a crash cannot bring the old expiry back.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         log::trace!("SET command key: {}, value: {}", key, value);
 
-        let set = KiviCommand::Set {
-            key: key.clone(),
+        self.write_batch(vec![KiviCommand::Set {
+            bucket: DEFAULT_BUCKET.to_string(),
+            key,
             value,
-        };
-        let j = serde_json::to_string(&set)?;
-
-        self.active_file.write_all(j.as_bytes())?;
-
-        // TODO: cleaner
-        let path = self
-            .config
-            .new_active_file_path(last_file_index(&self.stale_files) + 1);
-
-        let rec = InternalRecord {
-            file_id: path,
-            value_size: j.len() as i32,
-            value_pos: self.active_file.metadata().unwrap().len() as i32 - j.len() as i32,
-        };
+        }])
+    }
 
-        log::info!("InternalRecord: {:?}", rec);
-        self.mem_index.insert(key, rec);
+    /// Deletes a key by appending a tombstone record. Fails with
+    /// [`KiviError::KeyNotFound`] if the key does not exist.
+    pub fn delete(&mut self, key: String) -> Result<()> {
+        let res = self.delete_in(DEFAULT_BUCKET, key.clone());
+        self.clear_expiry(&key)?;
 
-        Ok(())
+        res
     }
 
-    pub fn delete(&mut self, _key: String) {
-        unimplemented!();
+    /// Returns all pairs whose key starts with `prefix`, sorted by key.
+    pub fn scan(&self, prefix: &str) -> Result<Vec<KeyValue>> {
+        self.scan_in(DEFAULT_BUCKET, prefix)
     }
 
-    // TODO: Can simplify this shit
-    pub fn compact(&mut self) -> Result<()> {
-        let new_file_test_path = format!("./db/data/temp/{}", "costam.test");
-        std::fs::create_dir_all("./db/data/temp").unwrap();
+    /// Loads a large number of pairs into the default bucket, much faster than calling
+    /// `set` for each of them. See [`Bucket::bulk_load`].
+    pub fn bulk_load<I>(&mut self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.bulk_load_in(DEFAULT_BUCKET, pairs)
+    }
 
-        let mut new_file_test = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(new_file_test_path.clone()) // TODO: change str
-            .expect("openoptions fails");
+    /// Returns a handle whose operations are scoped to the given bucket. Fails with
+    /// `ReservedBucket` for names starting with `RESERVED_BUCKET_PREFIX`.
+    pub fn bucket(&mut self, name: &str) -> Result<Bucket<'_>> {
+        Bucket::new(self, name)
+    }
 
-        for record in self.mem_index.values() {
-            let internal = self.get_internal(record)?;
-            let as_str = serde_json::to_string(&internal)?;
+    /// Names of all buckets that currently hold at least one key, except the default
+    /// and expiry buckets.
+    pub fn buckets(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .mem_index
+            .keys()
+            .map(|(bucket, _)| bucket.clone())
+            .filter(|bucket| bucket != DEFAULT_BUCKET && !is_reserved_bucket(bucket))
+            .collect();
+        names.dedup();
+
+        names
+    }
 
-            new_file_test.write_all(as_str.as_bytes())?;
+    /// Removes every key of a bucket by appending a single drop record. The space is
+    /// reclaimed by the next compaction. Neither the default bucket nor reserved ones
+    /// can be dropped.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT_BUCKET || is_reserved_bucket(name) {
+            return Err(KiviError::ReservedBucket(name.to_string()));
         }
 
-        // 1. Delete all log files in db/data/
-        data_files_sorted(&self.config)?.iter().for_each(|f| {
-            std::fs::remove_file(f).unwrap();
-        });
+        self.drop_bucket_in(name)
+    }
 
-        // 2. Move new_file_test to db/data directory
-        drop(new_file_test);
-        std::fs::rename(new_file_test_path, "db/data/1.log").unwrap();
-        let new_stale_files = data_files_sorted(&self.config)?;
-        self.stale_files = new_stale_files;
-        // 3. Set new_file_test as stale files
-        // 4. Create new active_file
+    pub(crate) fn drop_bucket_in(&mut self, name: &str) -> Result<()> {
+        log::trace!("DROP BUCKET command bucket: {}", name);
 
-        let active_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(
-                self.config
-                    .new_active_file_path(last_file_index(&self.stale_files) + 1),
-            )
-            .unwrap();
+        let keys = self.bucket_keys(name);
+        if keys.is_empty() {
+            return Ok(());
+        }
 
-        std::fs::remove_dir("./db/data/temp").unwrap();
+        self.append(&KiviCommand::DropBucket {
+            bucket: name.to_string(),
+        })?;
 
-        self.active_file = active_file;
+        for key in keys {
+            self.mem_index.remove(&key);
+        }
 
         Ok(())
     }
-}
 
-fn last_file_index(input: &[PathBuf]) -> usize {
-    let res = input
-        .last()
-        .and_then(|x| x.file_stem())
-        .and_then(|x| x.to_str())
-        .and_then(|x| x.parse::<usize>().ok());
+    pub(crate) fn get_in(&self, bucket: &str, key: String) -> Option<KeyValue> {
+        log::trace!("GET command bucket: {}, key: {}", bucket, key);
 
-    match res {
-        Some(value) => value,
-        None => 0_usize,
+        self.current_value(bucket, &key)
+            .ok()
+            .flatten()
+            .map(|value| KeyValue { key, value })
     }
-}
 
-fn build_index(stales: &[PathBuf]) -> Result<BTreeMap<String, InternalRecord>> {
-    let mut index = BTreeMap::new();
+    pub(crate) fn set_in(&mut self, bucket: &str, key: String, value: String) -> Result<()> {
+        log::trace!(
+            "SET command bucket: {}, key: {}, value: {}",
+            bucket,
+            key,
+            value
+        );
 
-    for file in stales {
-        let file_d = OpenOptions::new().read(true).open(file)?;
+        let set = KiviCommand::Set {
+            bucket: bucket.to_string(),
+            key: key.clone(),
+            value,
+        };
+        let rec = self.append(&set)?;
+
+        log::info!("InternalRecord: {:?}", rec);
+        self.mem_index.insert((bucket.to_string(), key), rec);
+
+        Ok(())
+    }
 
-        let reader = std::io::BufReader::new(file_d);
+    pub(crate) fn delete_in(&mut self, bucket: &str, key: String) -> Result<()> {
+        log::trace!("DELETE command bucket: {}, key: {}", bucket, key);
+
+        let index_key = (bucket.to_string(), key);
+        let expired = self.is_expired(bucket, &index_key.1)?;
+        if !self.mem_index.contains_key(&index_key) {
+            return Err(KiviError::KeyNotFound(index_key.1));
+        }
 
-        let mut pos: i32 = 0;
+        self.append(&KiviCommand::Delete {
+            bucket: bucket.to_string(),
+            key: index_key.1.clone(),
+        })?;
+        self.mem_index.remove(&index_key);
 
-        let mut comms = Deserializer::from_reader(reader).into_iter::<KiviCommand>();
+        // Already gone for readers, the record is only cleaned up
+        if expired {
+            return Err(KiviError::KeyNotFound(index_key.1));
+        }
 
-        while let Some(command) = comms.next() {
-            let new_pos = comms.byte_offset() as i32;
+        Ok(())
+    }
 
-            match command {
-                Ok(c) => {
-                    if let KiviCommand::Set { key, value: _ } = c {
-                        let as_str = file.as_path().display().to_string();
+    pub(crate) fn bulk_load_in<I>(&mut self, bucket: &str, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        log::trace!("BULK LOAD command bucket: {}", bucket);
+
+        // The loaded files go after every existing write. The active file is empty once
+        // sealed, and is replaced by a new one after them.
+        self.seal_active_file()?;
+        let active_path = self.active_file_path();
+        let first_index = last_file_index(&self.stale_files) + 2;
+
+        let mut staged = bulk::stage(&self.config, bucket, pairs, first_index, self.next_seq)?;
+        if staged.files.is_empty() {
+            return Ok(0);
+        }
 
-                        let rec = InternalRecord {
-                            file_id: as_str,
-                            value_size: new_pos - pos,
-                            value_pos: pos,
-                        };
-                        index.insert(key, rec);
+        let paths = staged.commit(&self.config)?;
+        std::fs::remove_file(active_path)?;
+
+        let mut count = 0;
+        let mut cleared = BTreeSet::new();
+        for ((_, hint), path) in std::mem::take(&mut staged.files).into_iter().zip(&paths) {
+            for entry in hint.entries {
+                if let HintEntry::Set {
+                    bucket,
+                    key,
+                    pos,
+                    size,
+                } = entry
+                {
+                    let rec = InternalRecord {
+                        file_id: path.display().to_string(),
+                        value_size: size,
+                        value_pos: pos,
+                    };
+                    // The loaded value replaces the key, expiry included
+                    if bucket == DEFAULT_BUCKET && self.deadline(&key)?.is_some() {
+                        cleared.insert(key.clone());
                     }
+
+                    self.mem_index.insert((bucket, key), rec);
+                    count += 1;
                 }
-                Err(e) => {
-                    return Err(KiviError::Generic(e.to_string()));
+            }
+        }
+
+        self.next_seq = staged.next_seq;
+
+        // Values are not kept in memory, so they are only read back if someone listens
+        if !self.hooks.is_empty() {
+            for path in &paths {
+                let mut reader = std::io::BufReader::new(File::open(path)?);
+                record::read_file_header(&mut reader)?;
+
+                while let Some(buf) = record::read_next(&mut reader)? {
+                    self.hooks.run(&ChangeEvent {
+                        seq: record::sequence(&buf),
+                        command: record::decode(&buf, self.config.get_keyring())?,
+                    });
                 }
             }
-            pos = new_pos;
         }
+
+        self.stale_files.extend(paths);
+        self.active_file = record::open_data_file(self.active_file_path())?;
+
+        if !cleared.is_empty() {
+            self.write_commands(
+                cleared
+                    .into_iter()
+                    .map(|key| KiviCommand::Delete {
+                        bucket: EXPIRY_BUCKET.to_string(),
+                        key,
+                    })
+                    .collect(),
+            )?;
+        }
+
+        Ok(count)
     }
 
-    // log::debug!("KeyDir: {:?}", index);
+    pub(crate) fn scan_in(&self, bucket: &str, prefix: &str) -> Result<Vec<KeyValue>> {
+        log::trace!("SCAN command bucket: {}, prefix: {}", bucket, prefix);
 
-    Ok(index)
-}
+        let mut res = Vec::new();
 
-fn data_files_sorted(config: &Config) -> Result<Vec<std::path::PathBuf>> {
-    let mut files = Vec::new();
+        let start = (bucket.to_string(), prefix.to_string());
+        for ((b, key), rec) in self.mem_index.range(start..) {
+            if b != bucket || !key.starts_with(prefix) {
+                break;
+            }
 
-    let paths = glob(config.get_glob_pattern().as_ref())?;
+            if self.is_expired(bucket, key)? {
+                continue;
+            }
 
-    for path in paths {
-        match path {
-            Ok(file) => files.push(file),
-            Err(_) => {
-                return Err(KiviError::Generic("a".to_string()));
+            if let KiviCommand::Set { value, .. } = self.get_internal(rec)? {
+                res.push(KeyValue {
+                    key: key.clone(),
+                    value,
+                });
             }
         }
+
+        Ok(res)
     }
 
-    files.sort();
+    pub(crate) fn bucket_stats(&self, bucket: &str) -> BucketStats {
+        let mut stats = BucketStats::default();
 
-    Ok(files)
-}
+        for key in self.bucket_keys(bucket) {
+            stats.key_count += 1;
+            stats.live_bytes += self.mem_index[&key].value_size as u64;
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::Path;
-    use tempdir::TempDir;
+        stats
+    }
 
-    #[test]
-    fn test_last_file_index() {
-        let first = PathBuf::from("./1.log");
-        let second = PathBuf::from("./2.log");
-        let third = PathBuf::from("./3.log");
+    fn bucket_keys(&self, bucket: &str) -> Vec<(String, String)> {
+        self.mem_index
+            .range((bucket.to_string(), String::new())..)
+            .take_while(|((b, _), _)| b == bucket)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
 
-        let zero: Vec<PathBuf> = vec![];
-        let only_first = vec![first.clone()];
-        let first_and_third = vec![first.clone(), third.clone()];
-        let all = vec![first, second, third];
+    /// Sets `key` to `new` only if its current value is exactly `expected`. Returns
+    /// whether the value was swapped.
+    pub fn compare_and_swap(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        log::trace!("CAS command key: {}, expected: {}", key, expected);
 
-        assert_eq!(last_file_index(&zero), 0_usize);
-        assert_eq!(last_file_index(&only_first), 1_usize);
-        assert_eq!(last_file_index(&first_and_third), 3_usize);
-        assert_eq!(last_file_index(&all), 3_usize);
+        if self.current_value(DEFAULT_BUCKET, &key)?.as_ref() != Some(&expected) {
+            return Ok(false);
+        }
+
+        self.set(key, new)?;
+
+        Ok(true)
     }
 
-    #[test]
-    fn test_last_file_index_fuzzed() {
-        let first = PathBuf::from("./aaa.log");
-        let second = PathBuf::from("./bbb.log");
+    /// Sets `key` only if it does not exist yet. Returns whether the value was set.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        log::trace!("SETNX command key: {}", key);
 
-        let all = vec![first, second];
+        if self.current_value(DEFAULT_BUCKET, &key)?.is_some() {
+            return Ok(false);
+        }
 
-        assert_eq!(last_file_index(&all), 0_usize);
+        self.set(key, value)?;
+
+        Ok(true)
     }
 
-    #[test]
-    fn test_creating() {
-        let tempdir = TempDir::new("creating").unwrap();
+    /// Deletes `key` only if its current value is exactly `expected`. Returns whether
+    /// the key was deleted.
+    pub fn delete_if_equals(&mut self, key: String, expected: String) -> Result<bool> {
+        log::trace!("DELIFEQ command key: {}, expected: {}", key, expected);
 
-        let kv = KiviStore::with_config(
-            Config::new()
-                .set_db_path(tempdir.path().to_path_buf())
-                .build(),
-        );
+        if self.current_value(DEFAULT_BUCKET, &key)?.as_ref() != Some(&expected) {
+            return Ok(false);
+        }
 
-        assert!(kv.is_ok());
-    }
+        self.delete(key)?;
 
-    #[test]
-    fn test_empty_get() {
-        let tempdir = TempDir::new("empty_get").unwrap();
+        Ok(true)
+    }
 
-        let kv = KiviStore::with_config(
-            Config::new()
-                .set_db_path(tempdir.path().to_path_buf())
-                .build(),
-        )
-        .unwrap();
+    /// Increments the integer stored at `key` by one and returns the new value.
+    pub fn incr(&mut self, key: String) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
 
-        assert_eq!(kv.get("a".to_string()), None)
+    /// Decrements the integer stored at `key` by one and returns the new value.
+    pub fn decr(&mut self, key: String) -> Result<i64> {
+        self.incr_by(key, -1)
     }
 
-    #[test]
-    fn test_single_set() {
-        let tempdir = TempDir::new("single_set").unwrap();
+    /// Adds `delta` to the integer stored at `key` and returns the new value. A missing
+    /// key counts as zero. Fails with [`KiviError::NotAnInteger`] if the stored value
+    /// does not parse as an `i64`.
+    pub fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        log::trace!("INCRBY command key: {}, delta: {}", key, delta);
+
+        let current = match self.current_value(DEFAULT_BUCKET, &key)? {
+            Some(v) => v
+                .parse::<i64>()
+                .map_err(|_| KiviError::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
 
-        let mut kv = KiviStore::with_config(
-            Config::new()
-                .set_db_path(tempdir.path().to_path_buf())
-                .build(),
-        )
-        .unwrap();
+        let new = current
+            .checked_add(delta)
+            .ok_or_else(|| KiviError::IntegerOverflow(key.clone()))?;
 
-        let set = kv.set("a".to_string(), "b".to_string());
-        assert!(set.is_ok());
+        self.set(key, new.to_string())?;
 
-        assert_eq!(
-            kv.get("a".to_string()),
-            Some(KeyValue {
-                key: "a".to_string(),
-                value: "b".to_string()
-            })
-        );
-        assert_eq!(kv.get("c".to_string()), None);
+        Ok(new)
     }
 
-    #[test]
-    fn test_multiple_set() {
-        let tempdir = TempDir::new("multiple_set").unwrap();
+    /// Makes `key` expire after `ttl`. Expired keys are hidden right away, and removed
+    /// by the next compaction. Setting the key again clears its expiry.
+    /// Returns false if the key does not exist.
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        log::trace!("EXPIRE command key: {}, ttl: {:?}", key, ttl);
 
-        let mut kv = KiviStore::with_config(
-            Config::new()
-                .set_db_path(tempdir.path().to_path_buf())
-                .build(),
-        )
-        .unwrap();
+        if self.current_value(DEFAULT_BUCKET, &key)?.is_none() {
+            return Ok(false);
+        }
 
-        let set1 = kv.set("a".to_string(), "b".to_string());
-        let set2 = kv.set("c".to_string(), "d".to_string());
-        let set3 = kv.set("e".to_string(), "f".to_string());
-        assert!(set1.is_ok());
-        assert!(set2.is_ok());
-        assert!(set3.is_ok());
+        let deadline = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.set_in(EXPIRY_BUCKET, key, deadline.to_string())?;
 
-        assert_eq!(
-            kv.get("a".to_string()),
-            Some(KeyValue {
-                key: "a".to_string(),
-                value: "b".to_string()
-            })
-        );
-        assert_eq!(
-            kv.get("c".to_string()),
-            Some(KeyValue {
-                key: "c".to_string(),
-                value: "d".to_string()
-            })
-        );
-        assert_eq!(
-            kv.get("e".to_string()),
-            Some(KeyValue {
-                key: "e".to_string(),
-                value: "f".to_string()
-            })
-        );
-        assert_eq!(kv.get("g".to_string()), None);
+        Ok(true)
     }
 
-    #[test]
-    fn test_file_indexing_works() {
-        let tempdir = TempDir::new("file_indexing").unwrap();
+    /// Time left before `key` expires, None if it never does. Fails with
+    /// [`KiviError::KeyNotFound`] if the key does not exist.
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        if self.current_value(DEFAULT_BUCKET, key)?.is_none() {
+            return Err(KiviError::KeyNotFound(key.to_string()));
+        }
 
-        let config = Config::new()
-            .set_db_path(tempdir.path().to_path_buf())
-            .build();
+        Ok(self
+            .deadline(key)?
+            .map(|deadline| Duration::from_millis(deadline.saturating_sub(now_millis()))))
+    }
 
-        // Create directories
-        std::fs::create_dir_all(config.get_full_path()).unwrap();
+    fn deadline(&self, key: &str) -> Result<Option<u64>> {
+        match self.current_raw_value(EXPIRY_BUCKET, key)? {
+            Some(deadline) => Ok(deadline.parse().ok()),
+            None => Ok(None),
+        }
+    }
 
-        // Create file with data extension
-        let file_path =
-            Path::new(&config.get_full_path()).join(format!("1.{}", config.get_data_extension()));
+    /// Only keys of the default bucket expire.
+    fn is_expired(&self, bucket: &str, key: &str) -> Result<bool> {
+        if bucket != DEFAULT_BUCKET {
+            return Ok(false);
+        }
 
-        assert!(!&file_path.exists());
+        Ok(matches!(self.deadline(key)?, Some(deadline) if deadline <= now_millis()))
+    }
 
-        let _ = File::create(&file_path).unwrap();
+    /// Only done by the top level writes. Replicated and replayed writes already carry
+    /// the records that clear expiries.
+    fn clear_expiry(&mut self, key: &str) -> Result<()> {
+        let index_key = (EXPIRY_BUCKET.to_string(), key.to_string());
 
-        assert!(&file_path.exists());
+        if self.mem_index.contains_key(&index_key) {
+            self.delete_in(EXPIRY_BUCKET, index_key.1)?;
+        }
 
-        let new_file_path =
-            Path::new(&config.get_full_path()).join(format!("2.{}", config.get_data_extension()));
+        Ok(())
+    }
 
-        let _ = KiviStore::with_config(config).unwrap();
+    /// Like `get`, but propagates read errors instead of hiding them.
+    fn current_value(&self, bucket: &str, key: &str) -> Result<Option<String>> {
+        if self.is_expired(bucket, key)? {
+            return Ok(None);
+        }
 
-        assert!(new_file_path.exists());
+        self.current_raw_value(bucket, key)
     }
 
-    #[test]
-    fn after_drop_works() {
-        let tempdir = TempDir::new("after_drop").unwrap();
+    /// Value in the data files, even if the key expired.
+    fn current_raw_value(&self, bucket: &str, key: &str) -> Result<Option<String>> {
+        match self.mem_index.get(&(bucket.to_string(), key.to_string())) {
+            Some(i) => match self.get_internal(i)? {
+                KiviCommand::Set { value, .. } => Ok(Some(value)),
+                _ => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
 
-        let mut kv1 = KiviStore::with_config(
-            Config::new()
-                .set_db_path(tempdir.path().to_path_buf())
-                .build(),
-        )
-        .unwrap();
+    /// Registers a hook that runs after every committed write.
+    pub fn add_write_hook(&mut self, hook: Box<dyn WriteHook>) {
+        self.hooks.add(hook);
+    }
 
-        let set = kv1.set("a".to_string(), "b".to_string());
+    /// Returns a stream of all writes committed from now on. Writes to the store's own
+    /// metadata, like expiry deadlines, are left out.
+    pub fn subscribe(&mut self) -> Subscription {
+        self.subscribe_with(Vec::new(), None, false)
+    }
+
+    /// Like `subscribe`, with the writes to reserved buckets. Replicas need them to
+    /// expire keys like their primary.
+    pub(crate) fn subscribe_all(&mut self) -> Subscription {
+        self.subscribe_with(Vec::new(), None, true)
+    }
+
+    /// Returns a stream of the writes to keys of the default bucket that match
+    /// `pattern`, committed from now on.
+    pub fn watch(&mut self, pattern: KeyPattern) -> Subscription {
+        self.subscribe_with(Vec::new(), Some(pattern), false)
+    }
+
+    /// Returns a stream of committed writes, starting with the ones whose sequence
+    /// number is at least `seq`. Past events are re-read from the data files, so
+    /// writes that compaction already discarded are skipped. Like `subscribe`, writes
+    /// to reserved buckets are left out.
+    pub fn subscribe_from(&mut self, seq: u64) -> Result<Subscription> {
+        let mut backlog = Vec::new();
+
+        for file in data_files_sorted(&self.config)? {
+            let mut reader = std::io::BufReader::new(File::open(file)?);
+            record::read_file_header(&mut reader)?;
+
+            while let Some(buf) = record::read_next(&mut reader)? {
+                let record_seq = record::sequence(&buf);
+
+                if record_seq >= seq {
+                    backlog.push(ChangeEvent {
+                        seq: record_seq,
+                        command: record::decode(&buf, self.config.get_keyring())?,
+                    });
+                }
+            }
+        }
+
+        // Compacted files are ordered by key, not by sequence number
+        backlog.sort_by_key(|event| event.seq);
+
+        Ok(self.subscribe_with(backlog, None, false))
+    }
+
+    fn subscribe_with(
+        &mut self,
+        backlog: Vec<ChangeEvent>,
+        pattern: Option<KeyPattern>,
+        reserved: bool,
+    ) -> Subscription {
+        let (subscription, hook) = Subscription::new(backlog, pattern, reserved);
+        self.hooks.add(hook);
+
+        subscription
+    }
+
+    /// Paths of all data files, including the active one.
+    pub fn data_files(&self) -> Result<Vec<PathBuf>> {
+        data_files_sorted(&self.config)
+    }
+
+    /// Sequence number of the last committed write.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Records with a sequence number up to this one may have been dropped by the last
+    /// compaction, so they can't be read back from the data files.
+    pub fn compacted_seq(&self) -> u64 {
+        self.meta.last_seq
+    }
+
+    /// Replays a write that was committed on another store, keeping its sequence
+    /// number. Used by replicas to follow their primary.
+    pub fn apply(&mut self, event: ChangeEvent) -> Result<()> {
+        if event.seq < self.next_seq {
+            return Err(KiviError::Generic(format!(
+                "event {} is older than the next sequence number {}",
+                event.seq, self.next_seq
+            )));
+        }
+
+        self.next_seq = event.seq;
+
+        match self.execute(event.command) {
+            // Already gone, but the sequence number still has to be used up
+            Err(KiviError::KeyNotFound(_)) => {}
+            res => res?,
+        }
+
+        self.next_seq = self.next_seq.max(event.seq + 1);
+
+        Ok(())
+    }
+
+    /// Runs a command as a new write of this store.
+    pub fn execute(&mut self, command: KiviCommand) -> Result<()> {
+        match command {
+            KiviCommand::Set { bucket, key, value } => self.set_in(&bucket, key, value),
+            KiviCommand::Delete { bucket, key } => self.delete_in(&bucket, key),
+            KiviCommand::DropBucket { bucket } => self.drop_bucket_in(&bucket),
+        }
+    }
+
+    /// Every live key of every bucket, as the `Set` commands that recreate them. Expired
+    /// keys and the store's own metadata are left out.
+    pub fn live_commands(&self) -> Result<Vec<KiviCommand>> {
+        self.records("").collect()
+    }
+
+    /// Live records of every bucket whose key starts with `prefix`, as `Set` commands
+    /// sorted by bucket then key. Values are read lazily. Like `scan`, expired keys and
+    /// reserved buckets are skipped.
+    pub fn records<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<KiviCommand>> + 'a {
+        self.mem_index
+            .iter()
+            .filter(move |((bucket, key), _)| {
+                key.starts_with(prefix) && !is_reserved_bucket(bucket)
+            })
+            .filter_map(
+                |((bucket, key), record)| match self.is_expired(bucket, key) {
+                    Ok(true) => None,
+                    Ok(false) => Some(self.get_internal(record)),
+                    Err(e) => Some(Err(e)),
+                },
+            )
+    }
+
+    /// Appends several commands to the active file with a single write, which is much
+    /// faster than running them one by one. Keys of the default bucket that are set or
+    /// deleted lose their expiry in the same write. Fails with `ReservedBucket`, writing
+    /// nothing, if a command touches a reserved bucket.
+    pub fn write_batch(&mut self, commands: Vec<KiviCommand>) -> Result<()> {
+        for command in &commands {
+            if is_reserved_bucket(command.bucket()) {
+                return Err(KiviError::ReservedBucket(command.bucket().to_string()));
+            }
+        }
+
+        self.write_commands(commands)
+    }
+
+    /// `write_batch` without the reserved bucket check.
+    fn write_commands(&mut self, commands: Vec<KiviCommand>) -> Result<()> {
+        self.rotate_if_full()?;
+
+        let mut expiring = BTreeSet::new();
+        for command in &commands {
+            if let KiviCommand::Set { bucket, key, .. } | KiviCommand::Delete { bucket, key } =
+                command
+            {
+                if bucket == DEFAULT_BUCKET
+                    && self
+                        .mem_index
+                        .contains_key(&(EXPIRY_BUCKET.to_string(), key.clone()))
+                {
+                    expiring.insert(key.clone());
+                }
+            }
+        }
+
+        let commands: Vec<KiviCommand> = commands
+            .into_iter()
+            .chain(expiring.into_iter().map(|key| KiviCommand::Delete {
+                bucket: EXPIRY_BUCKET.to_string(),
+                key,
+            }))
+            .collect();
+
+        let mut buf = Vec::new();
+        let mut sizes = Vec::with_capacity(commands.len());
+
+        for (i, command) in commands.iter().enumerate() {
+            let encoded = record::encode(
+                command,
+                self.next_seq + i as u64,
+                self.config.get_codec(),
+                self.config.get_keyring(),
+            )?;
+
+            sizes.push(encoded.len() as i32);
+            buf.extend(encoded);
+        }
+
+        let mut pos = self.active_file.metadata()?.len() as i32;
+        self.active_file.write_all(&buf)?;
+        self.sync_if_due()?;
+
+        let file_id = self.active_file_path();
+
+        for (command, size) in commands.into_iter().zip(sizes) {
+            match &command {
+                KiviCommand::Set { bucket, key, .. } => {
+                    let rec = InternalRecord {
+                        file_id: file_id.clone(),
+                        value_size: size,
+                        value_pos: pos,
+                    };
+                    self.mem_index.insert((bucket.clone(), key.clone()), rec);
+                }
+                KiviCommand::Delete { bucket, key } => {
+                    self.mem_index.remove(&(bucket.clone(), key.clone()));
+                }
+                KiviCommand::DropBucket { bucket } => {
+                    self.mem_index.retain(|(b, _), _| b != bucket);
+                }
+            }
+
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            pos += size;
+
+            self.hooks.run(&ChangeEvent { seq, command });
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if `key` holds a value in `bucket` that has not expired.
+    pub fn contains(&self, bucket: &str, key: &str) -> bool {
+        self.mem_index
+            .contains_key(&(bucket.to_string(), key.to_string()))
+            && !matches!(self.is_expired(bucket, key), Ok(true))
+    }
+
+    /// Data file and offset of the live record of a key.
+    pub(crate) fn location(&self, bucket: &str, key: &str) -> Option<(&Path, u64)> {
+        self.mem_index
+            .get(&(bucket.to_string(), key.to_string()))
+            .map(|rec| (Path::new(&rec.file_id), rec.value_pos as u64))
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    fn active_file_path(&self) -> String {
+        self.config
+            .new_active_file_path(last_file_index(&self.stale_files) + 1)
+    }
+
+    /// Closes the active file for writes, writes its hint file and starts a new active
+    /// file. Sealed files never change until compaction removes them. Does nothing if
+    /// the active file has no records.
+    pub fn seal_active_file(&mut self) -> Result<()> {
+        if self.active_file.metadata()?.len() <= record::FILE_HEADER_SIZE {
+            return Ok(());
+        }
+
+        self.active_file.sync_all()?;
+
+        let sealed = PathBuf::from(self.active_file_path());
+        Hint::scan(&sealed, self.config.get_keyring())?.save(&sealed, self.config.get_keyring())?;
+
+        self.stale_files.push(sealed);
+        self.active_file = record::open_data_file(self.active_file_path())?;
+
+        Ok(())
+    }
+
+    /// Data files other than the active one, oldest first.
+    pub fn sealed_files(&self) -> &[PathBuf] {
+        &self.stale_files
+    }
+
+    /// Seals the active file once it has reached the configured size.
+    fn rotate_if_full(&mut self) -> Result<()> {
+        if self.active_file.metadata()?.len() >= self.config.get_max_file_size() {
+            self.seal_active_file()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the active file to disk, whatever the sync policy.
+    pub fn sync(&mut self) -> Result<()> {
+        self.active_file.sync_all()?;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    pub fn set_sync_policy(&mut self, p: SyncPolicy) {
+        self.config.set_sync_policy(p);
+    }
+
+    /// Flushes the active file after a write, as often as the sync policy asks.
+    fn sync_if_due(&mut self) -> Result<()> {
+        let due = match self.config.get_sync_policy() {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(interval) => self.last_sync.elapsed() >= interval,
+        };
+
+        if due {
+            self.active_file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Appends a command to the active file and returns where it was written.
+    fn append(&mut self, command: &KiviCommand) -> Result<InternalRecord> {
+        self.rotate_if_full()?;
+
+        let seq = self.next_seq;
+        let j = record::encode(
+            command,
+            seq,
+            self.config.get_codec(),
+            self.config.get_keyring(),
+        )?;
+
+        self.active_file.write_all(&j)?;
+        self.sync_if_due()?;
+        self.next_seq += 1;
+
+        self.hooks.run(&ChangeEvent {
+            seq,
+            command: command.clone(),
+        });
+
+        Ok(InternalRecord {
+            file_id: self.active_file_path(),
+            value_size: j.len() as i32,
+            value_pos: self.active_file.metadata()?.len() as i32 - j.len() as i32,
+        })
+    }
+
+    /// Rewrites all live records into a single data file. Records are re-encoded with
+    /// the currently configured codec and encryption key, so this also recompresses old
+    /// data and rotates keys.
+    pub fn compact(&mut self) -> Result<()> {
+        // Expired keys are left out with their deadline, without writing tombstones
+        let mut expired = HashSet::new();
+        for (_, key) in self.bucket_keys(EXPIRY_BUCKET) {
+            if self.is_expired(DEFAULT_BUCKET, &key)? {
+                expired.insert(key);
+            }
+        }
+
+        let temp_dir =
+            Path::new(&self.config.get_full_path()).join(self.config.get_temp_data_dir());
+        std::fs::create_dir_all(&temp_dir)?;
+
+        let temp_file_path = temp_dir.join(format!("1.{}", self.config.get_data_extension()));
+        let compacted_path = self.config.new_active_file_path(1);
+
+        let mut temp_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_file_path)?;
+        temp_file.write_all(&record::file_header())?;
+
+        let mut new_index = BTreeMap::new();
+        let mut hint_entries = Vec::new();
+        let mut hint_last_seq = 0;
+        let mut pos = record::FILE_HEADER_SIZE as i32;
+
+        for (key, record) in self.mem_index.iter() {
+            if (key.0 == DEFAULT_BUCKET || key.0 == EXPIRY_BUCKET) && expired.contains(&key.1) {
+                continue;
+            }
+
+            let raw = self.read_raw(record)?;
+            let internal = record::decode(&raw, self.config.get_keyring())?;
+            let seq = record::sequence(&raw);
+            let encoded = record::encode(
+                &internal,
+                seq,
+                self.config.get_codec(),
+                self.config.get_keyring(),
+            )?;
+
+            temp_file.write_all(&encoded)?;
+
+            hint_entries.push(HintEntry::Set {
+                bucket: key.0.clone(),
+                key: key.1.clone(),
+                pos,
+                size: encoded.len() as i32,
+            });
+            hint_last_seq = hint_last_seq.max(seq);
+
+            new_index.insert(
+                key.clone(),
+                InternalRecord {
+                    file_id: compacted_path.clone(),
+                    value_size: encoded.len() as i32,
+                    value_pos: pos,
+                },
+            );
+            pos += encoded.len() as i32;
+        }
+
+        temp_file.sync_all()?;
+        drop(temp_file);
+
+        // Remember the sequence numbers of the records that are about to disappear
+        self.meta.last_seq = self.last_seq();
+        self.meta.save(&self.config)?;
+
+        // 1. Delete all data files, including the active one
+        remove_data_files(&self.config)?;
+
+        // 2. Move the compacted file to the data directory
+        std::fs::rename(&temp_file_path, &compacted_path)?;
+        std::fs::remove_dir(&temp_dir)?;
+
+        Hint {
+            file_size: pos as u64,
+            last_seq: hint_last_seq,
+            entries: hint_entries,
+        }
+        .save(Path::new(&compacted_path), self.config.get_keyring())?;
+
+        // 3. Set the compacted file as the only stale file
+        self.stale_files = data_files_sorted(&self.config)?;
+
+        // 4. Create new active_file
+        self.active_file = record::open_data_file(
+            self.config
+                .new_active_file_path(last_file_index(&self.stale_files) + 1),
+        )?;
+
+        self.mem_index = new_index;
+
+        self.meta.compactions += 1;
+        self.meta.last_compaction = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+        self.meta.save(&self.config)?;
+
+        Ok(())
+    }
+
+    /// Sizes of the data files and of the index, and compaction history.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let active_path = PathBuf::from(self.active_file_path());
+        let mut files = Vec::new();
+        let mut positions = HashMap::new();
+
+        for path in self.stale_files.iter().chain([&active_path]) {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            positions.insert(name.clone(), files.len());
+            files.push(FileStats {
+                name,
+                total_bytes: std::fs::metadata(path)?.len(),
+                live_bytes: 0,
+            });
+        }
+
+        let mut key_count = 0;
+        let mut keydir_bytes = 0;
+        for ((bucket, key), rec) in &self.mem_index {
+            if !is_reserved_bucket(bucket) && !self.is_expired(bucket, key)? {
+                key_count += 1;
+            }
+
+            // Roughly what an entry takes, not counting the tree nodes
+            keydir_bytes += (std::mem::size_of::<((String, String), InternalRecord)>()
+                + bucket.len()
+                + key.len()
+                + rec.file_id.len()) as u64;
+
+            let name = Path::new(&rec.file_id)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if let Some(&i) = positions.get(&name) {
+                files[i].live_bytes += rec.value_size as u64;
+            }
+        }
+
+        let total_bytes: u64 = files.iter().map(|f| f.total_bytes).sum();
+        let live_bytes: u64 = files.iter().map(|f| f.live_bytes).sum();
+
+        // File headers are neither live nor dead
+        let record_bytes = total_bytes
+            - files
+                .iter()
+                .map(|f| f.total_bytes.min(record::FILE_HEADER_SIZE))
+                .sum::<u64>();
+
+        Ok(StoreStats {
+            key_count,
+            files,
+            total_bytes,
+            live_bytes,
+            dead_ratio: match record_bytes {
+                0 => 0.0,
+                t => (t - live_bytes) as f64 / t as f64,
+            },
+            keydir_bytes,
+            compactions: self.meta.compactions,
+            last_compaction: self.meta.last_compaction,
+            write_hooks: self.hooks.active(),
+        })
+    }
+}
+
+pub(crate) fn is_reserved_bucket(name: &str) -> bool {
+    name.starts_with(RESERVED_BUCKET_PREFIX)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn last_file_index(input: &[PathBuf]) -> usize {
+    input.last().map(|x| file_index(x)).unwrap_or_default()
+}
+
+/// Builds the KeyDir from the data files. Also returns the highest sequence number
+/// found.
+fn build_index(stales: &[PathBuf], config: &Config) -> Result<(KeyDir, u64)> {
+    let mut index = BTreeMap::new();
+    let mut last_seq = 0;
+
+    for file in stales {
+        // Stale files never change again, so a missing hint is written for next time
+        let hint = match Hint::load(file, config.get_keyring()) {
+            Some(hint) => hint,
+            None => {
+                let hint = Hint::scan(file, config.get_keyring())?;
+                hint.save(file, config.get_keyring())?;
+                hint
+            }
+        };
+        last_seq = last_seq.max(hint.last_seq);
+
+        for entry in hint.entries {
+            match entry {
+                HintEntry::Set {
+                    bucket,
+                    key,
+                    pos,
+                    size,
+                } => {
+                    let rec = InternalRecord {
+                        file_id: file.as_path().display().to_string(),
+                        value_size: size,
+                        value_pos: pos,
+                    };
+                    index.insert((bucket, key), rec);
+                }
+                HintEntry::Delete { bucket, key } => {
+                    index.remove(&(bucket, key));
+                }
+                HintEntry::DropBucket { bucket } => {
+                    index.retain(|(b, _), _| *b != bucket);
+                }
+            }
+        }
+    }
+
+    Ok((index, last_seq))
+}
+
+pub(crate) fn data_files_sorted(config: &Config) -> Result<Vec<std::path::PathBuf>> {
+    let mut files = Vec::new();
+
+    let paths = glob(config.get_glob_pattern().as_ref())?;
+
+    for path in paths {
+        match path {
+            Ok(file) => files.push(file),
+            Err(_) => {
+                return Err(KiviError::Generic("a".to_string()));
+            }
+        }
+    }
+
+    // Numerically, so 10.log comes after 9.log
+    files.sort_by_key(|f| file_index(f));
+
+    Ok(files)
+}
+
+/// Deletes every data file in the data directory, together with its hint file.
+pub(crate) fn remove_data_files(config: &Config) -> Result<()> {
+    for f in data_files_sorted(config)? {
+        std::fs::remove_file(&f)?;
+
+        match std::fs::remove_file(hint::hint_path(&f)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn file_index(path: &Path) -> usize {
+    path.file_stem()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        crypto::{EncryptionKey, KEY_SIZE},
+        record::Codec,
+    };
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_last_file_index() {
+        let first = PathBuf::from("./1.log");
+        let second = PathBuf::from("./2.log");
+        let third = PathBuf::from("./3.log");
+
+        let zero: Vec<PathBuf> = vec![];
+        let only_first = vec![first.clone()];
+        let first_and_third = vec![first.clone(), third.clone()];
+        let all = vec![first, second, third];
+
+        assert_eq!(last_file_index(&zero), 0_usize);
+        assert_eq!(last_file_index(&only_first), 1_usize);
+        assert_eq!(last_file_index(&first_and_third), 3_usize);
+        assert_eq!(last_file_index(&all), 3_usize);
+    }
+
+    #[test]
+    fn test_last_file_index_fuzzed() {
+        let first = PathBuf::from("./aaa.log");
+        let second = PathBuf::from("./bbb.log");
+
+        let all = vec![first, second];
+
+        assert_eq!(last_file_index(&all), 0_usize);
+    }
+
+    #[test]
+    fn test_creating() {
+        let tempdir = TempDir::new("creating").unwrap();
+
+        let kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        );
+
+        assert!(kv.is_ok());
+    }
+
+    #[test]
+    fn test_empty_get() {
+        let tempdir = TempDir::new("empty_get").unwrap();
+
+        let kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(kv.get("a".to_string()), None)
+    }
+
+    #[test]
+    fn test_single_set() {
+        let tempdir = TempDir::new("single_set").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        let set = kv.set("a".to_string(), "b".to_string());
+        assert!(set.is_ok());
+
+        assert_eq!(
+            kv.get("a".to_string()),
+            Some(KeyValue {
+                key: "a".to_string(),
+                value: "b".to_string()
+            })
+        );
+        assert_eq!(kv.get("c".to_string()), None);
+    }
+
+    #[test]
+    fn test_multiple_set() {
+        let tempdir = TempDir::new("multiple_set").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        let set1 = kv.set("a".to_string(), "b".to_string());
+        let set2 = kv.set("c".to_string(), "d".to_string());
+        let set3 = kv.set("e".to_string(), "f".to_string());
+        assert!(set1.is_ok());
+        assert!(set2.is_ok());
+        assert!(set3.is_ok());
+
+        assert_eq!(
+            kv.get("a".to_string()),
+            Some(KeyValue {
+                key: "a".to_string(),
+                value: "b".to_string()
+            })
+        );
+        assert_eq!(
+            kv.get("c".to_string()),
+            Some(KeyValue {
+                key: "c".to_string(),
+                value: "d".to_string()
+            })
+        );
+        assert_eq!(
+            kv.get("e".to_string()),
+            Some(KeyValue {
+                key: "e".to_string(),
+                value: "f".to_string()
+            })
+        );
+        assert_eq!(kv.get("g".to_string()), None);
+    }
+
+    #[test]
+    fn test_file_indexing_works() {
+        let tempdir = TempDir::new("file_indexing").unwrap();
+
+        let config = Config::new()
+            .set_db_path(tempdir.path().to_path_buf())
+            .build();
+
+        // Create directories
+        std::fs::create_dir_all(config.get_full_path()).unwrap();
+
+        // Create file with data extension
+        let file_path =
+            Path::new(&config.get_full_path()).join(format!("1.{}", config.get_data_extension()));
+
+        assert!(!&file_path.exists());
+
+        let _ = File::create(&file_path).unwrap();
+
+        assert!(&file_path.exists());
+
+        let new_file_path =
+            Path::new(&config.get_full_path()).join(format!("2.{}", config.get_data_extension()));
+
+        let _ = KiviStore::with_config(config).unwrap();
+
+        assert!(new_file_path.exists());
+    }
+
+    #[test]
+    fn after_drop_works() {
+        let tempdir = TempDir::new("after_drop").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        let set = kv1.set("a".to_string(), "b".to_string());
         assert!(set.is_ok());
 
         drop(kv1);
 
-        let kv2 = KiviStore::with_config(
+        let kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            kv2.get("a".to_string()),
+            Some(KeyValue {
+                key: "a".to_string(),
+                value: "b".to_string()
+            })
+        );
+        assert_eq!(kv2.get("c".to_string()), None);
+    }
+
+    #[test]
+    fn test_get_after_compact() {
+        let tempdir = TempDir::new("get_after_compact").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("a".to_string(), "b".to_string()).unwrap();
+        kv.set("a".to_string(), "c".to_string()).unwrap();
+        kv.set("d".to_string(), "e".to_string()).unwrap();
+
+        kv.compact().unwrap();
+
+        assert_eq!(
+            kv.get("a".to_string()),
+            Some(KeyValue {
+                key: "a".to_string(),
+                value: "c".to_string()
+            })
+        );
+        assert_eq!(kv.get("d".to_string()).unwrap().value, "e".to_string());
+        assert!(!Path::new(&kv.config.get_full_path())
+            .join(kv.config.get_temp_data_dir())
+            .exists());
+    }
+
+    #[test]
+    fn test_unsupported_codec_fails() {
+        let tempdir = TempDir::new("unsupported_codec").unwrap();
+
+        let kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_codec(Codec::Lz4)
+                .build(),
+        );
+
+        assert_eq!(kv.is_ok(), Codec::Lz4.is_supported());
+    }
+
+    #[test]
+    fn test_unsupported_data_file_fails() {
+        let tempdir = TempDir::new("unsupported_format").unwrap();
+        let config = Config::new()
+            .set_db_path(tempdir.path().to_path_buf())
+            .build();
+        std::fs::create_dir_all(config.get_full_path()).unwrap();
+
+        // A data file of the first version, commands as concatenated JSON
+        std::fs::write(
+            config.new_active_file_path(1),
+            r#"{"Set":{"key":"a","value":"b"}}"#,
+        )
+        .unwrap();
+
+        let err = KiviStore::with_config(config).err().unwrap();
+        assert!(matches!(err, KiviError::UnsupportedFormat(_)));
+        assert!(err.to_string().starts_with("Unsupported data file format"));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_mixed_codecs() {
+        let tempdir = TempDir::new("mixed_codecs").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+        kv1.set("a".to_string(), "b".to_string()).unwrap();
+        drop(kv1);
+
+        // Reopen with compression, old uncompressed records stay readable
+        let mut kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_codec(Codec::Lz4)
+                .build(),
+        )
+        .unwrap();
+        kv2.set("c".to_string(), "d".to_string()).unwrap();
+
+        assert_eq!(kv2.get("a".to_string()).unwrap().value, "b".to_string());
+        assert_eq!(kv2.get("c".to_string()).unwrap().value, "d".to_string());
+
+        // Compaction rewrites everything with the current codec
+        kv2.compact().unwrap();
+
+        let compacted = std::fs::read(kv2.config.new_active_file_path(1)).unwrap();
+        let mut reader = compacted.as_slice();
+        record::read_file_header(&mut reader).unwrap();
+        while let Some(buf) = record::read_next(&mut reader).unwrap() {
+            assert_eq!(buf[4], Codec::Lz4.as_byte());
+        }
+
+        assert_eq!(kv2.get("a".to_string()).unwrap().value, "b".to_string());
+    }
+
+    #[test]
+    fn test_delete() {
+        let tempdir = TempDir::new("delete").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv1.set("a".to_string(), "b".to_string()).unwrap();
+        kv1.set("c".to_string(), "d".to_string()).unwrap();
+        kv1.delete("a".to_string()).unwrap();
+
+        assert_eq!(kv1.get("a".to_string()), None);
+        assert!(matches!(
+            kv1.delete("a".to_string()),
+            Err(KiviError::KeyNotFound(_))
+        ));
+
+        drop(kv1);
+
+        // Tombstones survive a restart
+        let kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(kv2.get("a".to_string()), None);
+        assert_eq!(kv2.get("c".to_string()).unwrap().value, "d".to_string());
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let tempdir = TempDir::new("compare_and_swap").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert!(!kv
+            .compare_and_swap("a".to_string(), "b".to_string(), "c".to_string())
+            .unwrap());
+        assert_eq!(kv.get("a".to_string()), None);
+
+        kv.set("a".to_string(), "b".to_string()).unwrap();
+
+        assert!(!kv
+            .compare_and_swap("a".to_string(), "x".to_string(), "c".to_string())
+            .unwrap());
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "b".to_string());
+
+        assert!(kv
+            .compare_and_swap("a".to_string(), "b".to_string(), "c".to_string())
+            .unwrap());
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "c".to_string());
+    }
+
+    #[test]
+    fn test_set_if_absent() {
+        let tempdir = TempDir::new("set_if_absent").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert!(kv.set_if_absent("a".to_string(), "b".to_string()).unwrap());
+        assert!(!kv.set_if_absent("a".to_string(), "c".to_string()).unwrap());
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "b".to_string());
+    }
+
+    #[test]
+    fn test_expire() {
+        let tempdir = TempDir::new("expire").unwrap();
+        let config = || {
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build()
+        };
+        let mut kv = KiviStore::with_config(config()).unwrap();
+
+        assert!(!kv.expire("a".to_string(), Duration::ZERO).unwrap());
+        for key in ["a", "b", "c"] {
+            kv.set(key.to_string(), "1".to_string()).unwrap();
+        }
+
+        assert!(kv.expire("a".to_string(), Duration::ZERO).unwrap());
+        assert!(kv
+            .expire("b".to_string(), Duration::from_secs(3600))
+            .unwrap());
+        assert_eq!(kv.get("a".to_string()), None);
+        assert!(matches!(kv.ttl("a"), Err(KiviError::KeyNotFound(_))));
+        assert!(kv.ttl("b").unwrap().unwrap() > Duration::from_secs(3500));
+        assert_eq!(kv.ttl("c").unwrap(), None);
+        assert_eq!(kv.scan("").unwrap().len(), 2);
+        assert!(kv.set_if_absent("a".to_string(), "2".to_string()).unwrap());
+
+        // A new value clears the expiry
+        kv.expire("a".to_string(), Duration::ZERO).unwrap();
+        kv.set("b".to_string(), "2".to_string()).unwrap();
+        assert_eq!(kv.ttl("b").unwrap(), None);
+        assert!(kv.buckets().is_empty());
+
+        drop(kv);
+        let mut kv = KiviStore::with_config(config()).unwrap();
+        assert_eq!(kv.get("a".to_string()), None);
+
+        kv.compact().unwrap();
+        assert!(!kv.contains(DEFAULT_BUCKET, "a"));
+        assert!(!kv.contains(EXPIRY_BUCKET, "a"));
+        assert_eq!(kv.scan("").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_expiry_is_not_a_key() {
+        let tempdir = TempDir::new("expiry_keys").unwrap();
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        for key in ["a", "b", "c"] {
+            kv.set(key.to_string(), "1".to_string()).unwrap();
+        }
+        kv.expire("a".to_string(), Duration::ZERO).unwrap();
+        kv.expire("b".to_string(), Duration::from_secs(3600))
+            .unwrap();
+        kv.expire("c".to_string(), Duration::from_secs(3600))
+            .unwrap();
+
+        assert!(!kv.contains(DEFAULT_BUCKET, "a"));
+        assert_eq!(kv.stats().unwrap().key_count, 2);
+        assert_eq!(kv.live_commands().unwrap().len(), 2);
+
+        // Bulk loaded values replace the keys, expiry included
+        kv.bulk_load([("b".to_string(), "2".to_string())]).unwrap();
+        assert_eq!(kv.ttl("b").unwrap(), None);
+        assert!(kv.ttl("c").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_delete_if_equals() {
+        let tempdir = TempDir::new("delete_if_equals").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("a".to_string(), "b".to_string()).unwrap();
+
+        assert!(!kv
+            .delete_if_equals("a".to_string(), "x".to_string())
+            .unwrap());
+        assert!(kv.get("a".to_string()).is_some());
+
+        assert!(kv
+            .delete_if_equals("a".to_string(), "b".to_string())
+            .unwrap());
+        assert_eq!(kv.get("a".to_string()), None);
+
+        assert!(!kv
+            .delete_if_equals("a".to_string(), "b".to_string())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let tempdir = TempDir::new("incr_decr").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(kv.incr("a".to_string()).unwrap(), 1);
+        assert_eq!(kv.incr("a".to_string()).unwrap(), 2);
+        assert_eq!(kv.incr_by("a".to_string(), 10).unwrap(), 12);
+        assert_eq!(kv.decr("a".to_string()).unwrap(), 11);
+        assert_eq!(kv.decr("b".to_string()).unwrap(), -1);
+
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "11".to_string());
+    }
+
+    #[test]
+    fn test_incr_not_an_integer() {
+        let tempdir = TempDir::new("incr_not_an_integer").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("a".to_string(), "b".to_string()).unwrap();
+        kv.set("max".to_string(), i64::MAX.to_string()).unwrap();
+
+        assert!(matches!(
+            kv.incr("a".to_string()),
+            Err(KiviError::NotAnInteger(_))
+        ));
+        assert!(matches!(
+            kv.incr("max".to_string()),
+            Err(KiviError::IntegerOverflow(_))
+        ));
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "b".to_string());
+    }
+
+    #[test]
+    fn test_scan() {
+        let tempdir = TempDir::new("scan").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("user:1".to_string(), "a".to_string()).unwrap();
+        kv.set("user:2".to_string(), "b".to_string()).unwrap();
+        kv.set("video:1".to_string(), "c".to_string()).unwrap();
+
+        let res = kv.scan("user:").unwrap();
+
+        assert_eq!(
+            res,
+            vec![
+                KeyValue {
+                    key: "user:1".to_string(),
+                    value: "a".to_string()
+                },
+                KeyValue {
+                    key: "user:2".to_string(),
+                    value: "b".to_string()
+                }
+            ]
+        );
+        assert_eq!(kv.scan("").unwrap().len(), 3);
+        assert!(kv.scan("x").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drop_bucket_after_restart_and_compact() {
+        let tempdir = TempDir::new("drop_bucket").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv1.bucket("users")
+            .unwrap()
+            .set("a".to_string(), "dropped".to_string())
+            .unwrap();
+        kv1.bucket("videos")
+            .unwrap()
+            .set("a".to_string(), "kept".to_string())
+            .unwrap();
+        kv1.drop_bucket("users").unwrap();
+        kv1.bucket("users")
+            .unwrap()
+            .set("b".to_string(), "after drop".to_string())
+            .unwrap();
+
+        drop(kv1);
+
+        let mut kv2 = KiviStore::with_config(
             Config::new()
                 .set_db_path(tempdir.path().to_path_buf())
                 .build(),
         )
         .unwrap();
 
+        assert_eq!(kv2.bucket("users").unwrap().get("a".to_string()), None);
+        assert!(kv2.bucket("users").unwrap().get("b".to_string()).is_some());
+        assert!(kv2.bucket("videos").unwrap().get("a".to_string()).is_some());
+
+        kv2.compact().unwrap();
+
+        let compacted = std::fs::read(kv2.config.new_active_file_path(1)).unwrap();
+        assert!(!String::from_utf8_lossy(&compacted).contains("dropped"));
         assert_eq!(
-            kv2.get("a".to_string()),
-            Some(KeyValue {
+            kv2.buckets(),
+            vec!["users".to_string(), "videos".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let tempdir = TempDir::new("subscribe").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.set("before".to_string(), "x".to_string()).unwrap();
+
+        let mut sub = kv.subscribe();
+
+        kv.set("a".to_string(), "b".to_string()).unwrap();
+        // Expiry deadlines are store metadata, not changes of user data
+        kv.expire("a".to_string(), Duration::from_secs(60)).unwrap();
+        kv.delete("a".to_string()).unwrap();
+
+        assert_eq!(
+            sub.next(),
+            Some(ChangeEvent {
+                seq: 2,
+                command: KiviCommand::Set {
+                    bucket: DEFAULT_BUCKET.to_string(),
+                    key: "a".to_string(),
+                    value: "b".to_string()
+                }
+            })
+        );
+        assert_eq!(
+            sub.next(),
+            Some(ChangeEvent {
+                seq: 4,
+                command: KiviCommand::Delete {
+                    bucket: DEFAULT_BUCKET.to_string(),
+                    key: "a".to_string(),
+                }
+            })
+        );
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)), None);
+
+        let backlog = kv.subscribe_from(0).unwrap();
+        drop(kv);
+        assert_eq!(sub.next(), None);
+        assert_eq!(
+            backlog.map(|event| event.seq).collect::<Vec<u64>>(),
+            vec![1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn test_subscribe_from() {
+        let tempdir = TempDir::new("subscribe_from").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv1.set("a".to_string(), "1".to_string()).unwrap();
+        kv1.set("b".to_string(), "2".to_string()).unwrap();
+        drop(kv1);
+
+        let mut kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+        kv2.set("c".to_string(), "3".to_string()).unwrap();
+
+        let sub = kv2.subscribe_from(2).unwrap();
+        kv2.set("d".to_string(), "4".to_string()).unwrap();
+
+        let seqs: Vec<u64> = sub.take(3).map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_sequence_not_reused_after_compact() {
+        let tempdir = TempDir::new("sequence_compact").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv1.set("b".to_string(), "1".to_string()).unwrap();
+        kv1.set("a".to_string(), "2".to_string()).unwrap();
+        kv1.delete("a".to_string()).unwrap();
+        kv1.compact().unwrap();
+        drop(kv1);
+
+        let mut kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        assert_eq!(kv2.last_seq(), 3);
+
+        // Only the surviving record can be replayed
+        let mut sub = kv2.subscribe_from(0).unwrap();
+        assert_eq!(sub.next().unwrap().seq, 1);
+        assert_eq!(sub.next_timeout(Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn test_watch() {
+        let tempdir = TempDir::new("watch").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        let exact = kv.watch(KeyPattern::parse("a"));
+        let prefix = kv.watch(KeyPattern::parse("user:*"));
+
+        kv.set("a".to_string(), "1".to_string()).unwrap();
+        kv.set("user:1".to_string(), "2".to_string()).unwrap();
+        kv.set("other".to_string(), "3".to_string()).unwrap();
+        kv.bucket("b")
+            .unwrap()
+            .set("a".to_string(), "4".to_string())
+            .unwrap();
+        kv.delete("user:1".to_string()).unwrap();
+
+        let exact_seqs: Vec<u64> = std::iter::from_fn(|| exact.next_timeout(Duration::ZERO))
+            .map(|e| e.seq)
+            .collect();
+        let prefix_seqs: Vec<u64> = std::iter::from_fn(|| prefix.next_timeout(Duration::ZERO))
+            .map(|e| e.seq)
+            .collect();
+
+        assert_eq!(exact_seqs, vec![1]);
+        assert_eq!(prefix_seqs, vec![2, 5]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let tempdir = TempDir::new("apply").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+
+        kv.apply(ChangeEvent {
+            seq: 5,
+            command: KiviCommand::Set {
+                bucket: DEFAULT_BUCKET.to_string(),
                 key: "a".to_string(),
-                value: "b".to_string()
+                value: "b".to_string(),
+            },
+        })
+        .unwrap();
+        kv.apply(ChangeEvent {
+            seq: 6,
+            command: KiviCommand::Delete {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: "missing".to_string(),
+            },
+        })
+        .unwrap();
+
+        assert_eq!(kv.get("a".to_string()).unwrap().value, "b".to_string());
+        assert_eq!(kv.last_seq(), 6);
+        assert!(kv
+            .apply(ChangeEvent {
+                seq: 6,
+                command: KiviCommand::DropBucket {
+                    bucket: DEFAULT_BUCKET.to_string(),
+                },
             })
+            .is_err());
+    }
+
+    #[test]
+    fn test_encryption_wrong_key() {
+        let tempdir = TempDir::new("encryption_wrong_key").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_encryption_key(EncryptionKey::new([1; KEY_SIZE]))
+                .build(),
+        )
+        .unwrap();
+        kv1.set("a".to_string(), "secret".to_string()).unwrap();
+        drop(kv1);
+
+        let raw = std::fs::read(tempdir.path().join("data/1.log")).unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("secret"));
+
+        let kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_encryption_key(EncryptionKey::new([2; KEY_SIZE]))
+                .build(),
         );
-        assert_eq!(kv2.get("c".to_string()), None);
+        assert!(matches!(kv2, Err(KiviError::Decryption(_))));
+
+        let kv3 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build(),
+        );
+        assert!(matches!(kv3, Err(KiviError::Decryption(_))));
+    }
+
+    #[test]
+    fn test_encryption_key_rotation() {
+        let tempdir = TempDir::new("encryption_key_rotation").unwrap();
+
+        let mut kv1 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_encryption_key(EncryptionKey::new([1; KEY_SIZE]))
+                .build(),
+        )
+        .unwrap();
+        kv1.set("a".to_string(), "b".to_string()).unwrap();
+        drop(kv1);
+
+        let mut kv2 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_encryption_key(EncryptionKey::new([2; KEY_SIZE]))
+                .set_previous_encryption_keys(vec![EncryptionKey::new([1; KEY_SIZE])])
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(kv2.get("a".to_string()).unwrap().value, "b".to_string());
+
+        kv2.compact().unwrap();
+        drop(kv2);
+
+        // The old key is no longer needed after compaction
+        let kv3 = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_encryption_key(EncryptionKey::new([2; KEY_SIZE]))
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(kv3.get("a".to_string()).unwrap().value, "b".to_string());
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let tempdir = TempDir::new("batch").unwrap();
+        let config = || {
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build()
+        };
+
+        let mut kv = KiviStore::with_config(config()).unwrap();
+        kv.set("a".to_string(), "1".to_string()).unwrap();
+
+        kv.write_batch(vec![
+            KiviCommand::Set {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: "b".to_string(),
+                value: "2".to_string(),
+            },
+            KiviCommand::Delete {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: "a".to_string(),
+            },
+            KiviCommand::Set {
+                bucket: "x".to_string(),
+                key: "c".to_string(),
+                value: "3".to_string(),
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(kv.get("a".to_string()), None);
+        assert_eq!(kv.get("b".to_string()).unwrap().value, "2");
+        assert_eq!(kv.last_seq(), 4);
+
+        let res = kv.write_batch(vec![
+            KiviCommand::Set {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: "d".to_string(),
+                value: "4".to_string(),
+            },
+            KiviCommand::Delete {
+                bucket: EXPIRY_BUCKET.to_string(),
+                key: "d".to_string(),
+            },
+        ]);
+        assert!(matches!(res, Err(KiviError::ReservedBucket(_))));
+        assert_eq!(kv.last_seq(), 4);
+        drop(kv);
+
+        let mut kv = KiviStore::with_config(config()).unwrap();
+        assert_eq!(kv.get("a".to_string()), None);
+        assert_eq!(
+            kv.bucket("x").unwrap().get("c".to_string()).unwrap().value,
+            "3"
+        );
+        assert_eq!(kv.last_seq(), 4);
+    }
+
+    #[test]
+    fn test_stats() {
+        let tempdir = TempDir::new("stats").unwrap();
+        let config = || {
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build()
+        };
+
+        let mut kv = KiviStore::with_config(config()).unwrap();
+        kv.set("a".to_string(), "1".to_string()).unwrap();
+        kv.set("a".to_string(), "2".to_string()).unwrap();
+        kv.set("b".to_string(), "3".to_string()).unwrap();
+        kv.delete("b".to_string()).unwrap();
+
+        let stats = kv.stats().unwrap();
+        let record_size = kv.mem_index.values().next().unwrap().value_size as u64;
+        assert_eq!(stats.key_count, 1);
+        assert_eq!(stats.files.len(), 1);
+        assert_eq!(stats.live_bytes, record_size);
+        assert_eq!(stats.files[0].live_bytes, record_size);
+        assert!(stats.total_bytes > 3 * record_size);
+        assert!(stats.dead_ratio > 0.7 && stats.dead_ratio < 1.0);
+        assert!(stats.keydir_bytes > 0);
+        assert_eq!(stats.compactions, 0);
+        assert_eq!(stats.last_compaction, None);
+
+        kv.compact().unwrap();
+        let stats = kv.stats().unwrap();
+        assert_eq!(stats.dead_ratio, 0.0);
+        assert_eq!(stats.files.len(), 2);
+        assert_eq!(stats.files[0].live_bytes, record_size);
+        assert_eq!(stats.compactions, 1);
+        assert!(stats.last_compaction.is_some());
+        drop(kv);
+
+        // The compaction history survives a restart
+        let kv = KiviStore::with_config(config()).unwrap();
+        assert_eq!(kv.stats().unwrap().compactions, 1);
+        assert_eq!(kv.last_seq(), 4);
+    }
+
+    #[test]
+    fn test_active_file_rotation() {
+        let tempdir = TempDir::new("rotation").unwrap();
+
+        let mut kv = KiviStore::with_config(
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .set_max_file_size(100)
+                .build(),
+        )
+        .unwrap();
+
+        for i in 0..10 {
+            kv.set(format!("k{}", i), i.to_string()).unwrap();
+        }
+
+        assert!(kv.sealed_files().len() >= 3);
+        assert!(kv
+            .sealed_files()
+            .iter()
+            .all(|f| hint::hint_path(f).exists()));
+        assert_eq!(kv.get("k0".to_string()).unwrap().value, "0");
+        assert_eq!(kv.get("k9".to_string()).unwrap().value, "9");
+    }
+
+    #[test]
+    fn test_sealed_files_reopen_in_order() {
+        let tempdir = TempDir::new("sealed").unwrap();
+        let config = || {
+            Config::new()
+                .set_db_path(tempdir.path().to_path_buf())
+                .build()
+        };
+
+        let mut store = KiviStore::with_config(config()).unwrap();
+        for i in 0..12 {
+            store.set("k".to_string(), i.to_string()).unwrap();
+            store.seal_active_file().unwrap();
+        }
+        // Sealing an empty active file does nothing
+        store.seal_active_file().unwrap();
+        assert_eq!(store.sealed_files().len(), 12);
+        assert!(hint::hint_path(&store.sealed_files()[11]).exists());
+        drop(store);
+
+        // 10.log, 11.log and 12.log are read after 9.log
+        let store = KiviStore::with_config(config()).unwrap();
+        assert_eq!(store.get("k".to_string()).unwrap().value, "11");
+        assert_eq!(store.last_seq(), 12);
     }
 
     #[test]
     fn test_bad_inside_files_fail() {
-        // What if i write some corrupted file 1.log?
+        let tempdir = TempDir::new("bad_files").unwrap();
+        let config = Config::new()
+            .set_db_path(tempdir.path().to_path_buf())
+            .build();
+
+        let mut kv = KiviStore::with_config(config.clone()).unwrap();
+        kv.set("a".to_string(), "1".to_string()).unwrap();
+        kv.set("b".to_string(), "2".to_string()).unwrap();
+        drop(kv);
+
+        // A flipped byte in the last record fails its checksum
+        let path = config.new_active_file_path(1);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = KiviStore::with_config(config.clone()).err().unwrap();
+        assert!(matches!(err, KiviError::Corrupted(_)));
+
+        // A file written by a newer version is refused, not misread
+        bytes[last] ^= 0xff;
+        bytes[record::FILE_HEADER_SIZE as usize - 1] = record::FORMAT_VERSION + 1;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = KiviStore::with_config(config).err().unwrap();
+        assert!(matches!(err, KiviError::UnsupportedFormat(_)));
     }
 }
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::core::{config::Config, error::Result};
+
+/// Store wide state that cannot be recovered from the data files alone. Kept as a
+/// small JSON file next to the data directory.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct StoreMeta {
+    /// Highest sequence number ever assigned. Compaction can drop the records that
+    /// carried the latest numbers, so this keeps them from being reused.
+    pub last_seq: u64,
+
+    /// Number of compactions run on the store
+    #[serde(default)]
+    pub compactions: u64,
+
+    /// Seconds since the Unix epoch
+    #[serde(default)]
+    pub last_compaction: Option<u64>,
+}
+
+impl StoreMeta {
+    pub fn load(config: &Config) -> Result<Self> {
+        match std::fs::read(meta_path(config)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the file atomically by renaming a temporary file over it.
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let path = meta_path(config);
+        let temp_path = path.with_extension("json.tmp");
+
+        std::fs::write(&temp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(temp_path, path)?;
+
+        Ok(())
+    }
+}
+
+fn meta_path(config: &Config) -> PathBuf {
+    config.get_db_path().join("meta.json")
+}
@@ -1,5 +1,18 @@
+pub mod backup;
+pub mod bucket;
+pub mod bulk;
+pub mod changes;
 pub mod config;
+pub mod crypto;
 pub mod error;
+pub mod export;
+pub mod hint;
+pub mod inspect;
 pub mod kv;
+// Not wired into the parser yet
+#[allow(dead_code, clippy::match_result_ok)]
 pub mod lexer;
+pub mod meta;
+pub mod record;
+pub mod stats;
 pub mod token;
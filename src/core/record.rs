@@ -0,0 +1,425 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::core::{
+    crypto::Keyring,
+    error::{KiviError, Result},
+    kv::KiviCommand,
+};
+
+/// Size of the record header in bytes.
+///
+/// Every record on disk has the following layout:
+///
+/// | crc32 (4) | codec (1) | sequence number (8) | payload size (4) | payload |
+///
+/// The crc covers everything after itself. Integers are little endian. The highest
+/// bit of the codec byte marks a payload that was encrypted after compression.
+pub const HEADER_SIZE: usize = 17;
+
+/// Bit of the codec byte that is set for encrypted payloads.
+pub const ENCRYPTED_FLAG: u8 = 0x80;
+
+/// Bytes every data file starts with, followed by the format version byte.
+pub const FILE_MAGIC: &[u8; 4] = b"KIVI";
+
+/// Version of the record layout described in `HEADER_SIZE`. Files of any other version
+/// are refused rather than misread.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Size of the file header: the magic bytes and the format version.
+pub const FILE_HEADER_SIZE: u64 = 5;
+
+/// Compression codec used for a single record payload. It is stored as a byte in the
+/// record header, so records written with different codecs can live in the same file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            _ => Err(KiviError::Corrupted(format!("unknown codec byte {}", b))),
+        }
+    }
+
+    /// Returns true if this codec was compiled in through its cargo feature.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Codec::None => true,
+            Codec::Lz4 => cfg!(feature = "lz4"),
+            Codec::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(input.to_vec()),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(input)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(zstd::encode_all(input, 0)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(KiviError::UnsupportedCodec(*self)),
+        }
+    }
+
+    fn decompress(&self, input: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(input.to_vec()),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(input)
+                .map_err(|e| KiviError::Corrupted(e.to_string())),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(zstd::decode_all(input)?),
+            #[allow(unreachable_patterns)]
+            _ => Err(KiviError::UnsupportedCodec(*self)),
+        }
+    }
+}
+
+/// Header fields an encrypted payload is authenticated with, so a payload can't be
+/// moved to another record or have its codec byte changed.
+fn associated_data(codec_byte: u8, seq: u64) -> [u8; 9] {
+    let mut aad = [0; 9];
+    aad[0] = codec_byte;
+    aad[1..].copy_from_slice(&seq.to_le_bytes());
+    aad
+}
+
+/// Serializes a command into a complete record, header included. The payload is
+/// encrypted when the keyring has a current key.
+pub fn encode(command: &KiviCommand, seq: u64, codec: Codec, keyring: &Keyring) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(command)?;
+    let mut payload = codec.compress(&json)?;
+    let mut codec_byte = codec.as_byte();
+
+    if keyring.is_enabled() {
+        codec_byte |= ENCRYPTED_FLAG;
+        payload = keyring.encrypt(&payload, &associated_data(codec_byte, seq))?;
+    }
+
+    let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(codec_byte);
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&payload);
+
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[0..4].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(buf)
+}
+
+/// Decodes a complete record, verifying its checksum, then decrypting and
+/// decompressing the payload. With an encryption key configured, records that are not
+/// encrypted are refused, as anyone able to write the files could have added them.
+pub fn decode(buf: &[u8], keyring: &Keyring) -> Result<KiviCommand> {
+    if buf.len() < HEADER_SIZE {
+        return Err(KiviError::Corrupted(
+            "record shorter than header".to_string(),
+        ));
+    }
+
+    if !checksum_matches(buf) {
+        return Err(KiviError::Corrupted("checksum mismatch".to_string()));
+    }
+
+    let codec = Codec::from_byte(buf[4] & !ENCRYPTED_FLAG)?;
+
+    let json = if buf[4] & ENCRYPTED_FLAG != 0 {
+        let aad = associated_data(buf[4], sequence(buf));
+        codec.decompress(&keyring.decrypt(&buf[HEADER_SIZE..], &aad)?)?
+    } else if keyring.is_enabled() {
+        return Err(KiviError::Decryption(
+            "record is not encrypted although an encryption key is configured".to_string(),
+        ));
+    } else {
+        codec.decompress(&buf[HEADER_SIZE..])?
+    };
+
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Returns true if the crc of a complete record matches its content.
+pub fn checksum_matches(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_SIZE
+        && u32::from_le_bytes(buf[0..4].try_into().unwrap()) == crc32fast::hash(&buf[4..])
+}
+
+/// Returns the sequence number of a complete record without decoding its payload.
+pub fn sequence(buf: &[u8]) -> u64 {
+    u64::from_le_bytes(buf[5..13].try_into().unwrap())
+}
+
+/// Returns the header that starts every data file.
+pub fn file_header() -> [u8; FILE_HEADER_SIZE as usize] {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+    header[..4].copy_from_slice(FILE_MAGIC);
+    header[4] = FORMAT_VERSION;
+    header
+}
+
+/// Opens a data file for appending, creating it with its file header if it does not
+/// exist or is still empty.
+pub fn open_data_file(path: impl AsRef<Path>) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(path)?;
+
+    if file.metadata()?.len() == 0 {
+        file.write_all(&file_header())?;
+    }
+
+    Ok(file)
+}
+
+/// Reads and checks the file header at the start of a data file, leaving the reader on
+/// the first record. Returns the number of bytes read, which is 0 for an empty file.
+pub fn read_file_header<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+
+    let mut filled = 0;
+    while filled < header.len() {
+        let n = reader.read(&mut header[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+
+    if filled == 0 {
+        return Ok(0);
+    }
+
+    if filled < header.len() || &header[..4] != FILE_MAGIC {
+        return Err(KiviError::UnsupportedFormat(
+            "missing file header, the file was written by an older version".to_string(),
+        ));
+    }
+
+    if header[4] != FORMAT_VERSION {
+        return Err(KiviError::UnsupportedFormat(format!(
+            "version {}, expected {}",
+            header[4], FORMAT_VERSION
+        )));
+    }
+
+    Ok(FILE_HEADER_SIZE)
+}
+
+/// Reads the raw bytes of the next record from the reader. Returns `None` on a clean
+/// end of file.
+pub fn read_next<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; HEADER_SIZE];
+
+    // Distinguish a clean end of file from a truncated header
+    let mut filled = 0;
+    while filled < HEADER_SIZE {
+        let n = reader.read(&mut header[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+
+    match filled {
+        0 => return Ok(None),
+        HEADER_SIZE => {}
+        _ => return Err(KiviError::Corrupted("truncated record header".to_string())),
+    }
+
+    let payload_size = u32::from_le_bytes(header[13..17].try_into().unwrap()) as usize;
+
+    let mut buf = header.to_vec();
+    buf.resize(HEADER_SIZE + payload_size, 0);
+    reader
+        .read_exact(&mut buf[HEADER_SIZE..])
+        .map_err(|_| KiviError::Corrupted("truncated record payload".to_string()))?;
+
+    Ok(Some(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::{EncryptionKey, KEY_SIZE};
+
+    fn set_command() -> KiviCommand {
+        KiviCommand::Set {
+            bucket: String::new(),
+            key: "a".to_string(),
+            value: "{\"name\": \"b\", \"name\": \"b\", \"name\": \"b\"}".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_uncompressed() {
+        let encoded = encode(&set_command(), 1, Codec::None, &Keyring::default()).unwrap();
+
+        assert_eq!(encoded[4], 0);
+        assert_eq!(
+            decode(&encoded, &Keyring::default()).unwrap(),
+            set_command()
+        );
+    }
+
+    #[test]
+    fn test_corrupted_checksum() {
+        let mut encoded = encode(&set_command(), 1, Codec::None, &Keyring::default()).unwrap();
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0xff;
+
+        assert!(matches!(
+            decode(&encoded, &Keyring::default()),
+            Err(KiviError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_next() {
+        let mut bytes = encode(&set_command(), 1, Codec::None, &Keyring::default()).unwrap();
+        let first_len = bytes.len();
+        bytes.extend(
+            encode(
+                &KiviCommand::Delete {
+                    bucket: String::new(),
+                    key: "a".to_string(),
+                },
+                2,
+                Codec::None,
+                &Keyring::default(),
+            )
+            .unwrap(),
+        );
+
+        let mut reader = bytes.as_slice();
+
+        let first = read_next(&mut reader).unwrap().unwrap();
+        assert_eq!(first.len(), first_len);
+        assert_eq!(sequence(&first), 1);
+        assert_eq!(sequence(&read_next(&mut reader).unwrap().unwrap()), 2);
+        assert!(read_next(&mut reader).unwrap().is_none());
+
+        let mut truncated = &bytes[..first_len - 1];
+        assert!(read_next(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn test_file_header() {
+        let mut reader = &[][..];
+        assert_eq!(read_file_header(&mut reader).unwrap(), 0);
+
+        let mut bytes = file_header().to_vec();
+        bytes.extend(encode(&set_command(), 1, Codec::None, &Keyring::default()).unwrap());
+        let mut reader = bytes.as_slice();
+        assert_eq!(read_file_header(&mut reader).unwrap(), FILE_HEADER_SIZE);
+        assert_eq!(sequence(&read_next(&mut reader).unwrap().unwrap()), 1);
+
+        // Files of the first versions had no header, records were plain JSON at first
+        let json = serde_json::to_vec(&set_command()).unwrap();
+        let headerless = encode(&set_command(), 1, Codec::None, &Keyring::default()).unwrap();
+        for old in [json, headerless] {
+            assert!(matches!(
+                read_file_header(&mut old.as_slice()),
+                Err(KiviError::UnsupportedFormat(_))
+            ));
+        }
+
+        let mut newer = file_header();
+        newer[4] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            read_file_header(&mut newer.as_slice()),
+            Err(KiviError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_roundtrip_encrypted() {
+        let keyring = Keyring::new(Some(EncryptionKey::new([7; KEY_SIZE])), vec![]);
+        let encoded = encode(&set_command(), 1, Codec::None, &keyring).unwrap();
+
+        assert_eq!(encoded[4], ENCRYPTED_FLAG);
+        assert_eq!(decode(&encoded, &keyring).unwrap(), set_command());
+        assert!(matches!(
+            decode(&encoded, &Keyring::default()),
+            Err(KiviError::Decryption(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_header_is_authenticated() {
+        let keyring = Keyring::new(Some(EncryptionKey::new([7; KEY_SIZE])), vec![]);
+
+        // Plain records can't be slipped into an encrypted store
+        let plain = encode(&set_command(), 1, Codec::None, &Keyring::default()).unwrap();
+        assert!(matches!(
+            decode(&plain, &keyring),
+            Err(KiviError::Decryption(_))
+        ));
+
+        // Neither can an encrypted payload be given another sequence number, even with
+        // a valid checksum
+        let mut moved = encode(&set_command(), 1, Codec::None, &keyring).unwrap();
+        moved[5..13].copy_from_slice(&2u64.to_le_bytes());
+        let crc = crc32fast::hash(&moved[4..]);
+        moved[0..4].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            decode(&moved, &keyring),
+            Err(KiviError::Decryption(_))
+        ));
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn test_disabled_codec() {
+        assert!(!Codec::Lz4.is_supported());
+        assert!(matches!(
+            encode(&set_command(), 1, Codec::Lz4, &Keyring::default()),
+            Err(KiviError::UnsupportedCodec(Codec::Lz4))
+        ));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_roundtrip_lz4() {
+        let encoded = encode(&set_command(), 1, Codec::Lz4, &Keyring::default()).unwrap();
+
+        assert_eq!(encoded[4], 1);
+        assert_eq!(
+            decode(&encoded, &Keyring::default()).unwrap(),
+            set_command()
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_roundtrip_zstd() {
+        let encoded = encode(&set_command(), 1, Codec::Zstd, &Keyring::default()).unwrap();
+
+        assert_eq!(encoded[4], 2);
+        assert_eq!(
+            decode(&encoded, &Keyring::default()).unwrap(),
+            set_command()
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Health of a store, as returned by `KiviStore::stats`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoreStats {
+    /// Number of live keys, in every bucket
+    pub key_count: usize,
+
+    /// Data files, oldest first, the active one included
+    pub files: Vec<FileStats>,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+
+    /// Share of the bytes on disk that the next compaction would reclaim, from 0 to 1
+    pub dead_ratio: f64,
+
+    /// Estimated memory used by the in memory index
+    pub keydir_bytes: u64,
+    pub compactions: u64,
+
+    /// Seconds since the Unix epoch
+    pub last_compaction: Option<u64>,
+
+    /// Write hooks, subscriptions and watches included, still registered
+    pub write_hooks: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileStats {
+    pub name: String,
+    pub total_bytes: u64,
+
+    /// Size of the records of this file the index points to
+    pub live_bytes: u64,
+}
+
+/// One `name: value` line per number, then one line per data file.
+impl fmt::Display for StoreStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last_compaction = self
+            .last_compaction
+            .map(|t| t.to_string())
+            .unwrap_or_else(|| "never".to_string());
+
+        let mut lines = vec![
+            format!("key_count: {}", self.key_count),
+            format!("data_files: {}", self.files.len()),
+            format!("total_bytes: {}", self.total_bytes),
+            format!("live_bytes: {}", self.live_bytes),
+            format!("dead_ratio: {:.3}", self.dead_ratio),
+            format!("keydir_bytes: {}", self.keydir_bytes),
+            format!("compactions: {}", self.compactions),
+            format!("last_compaction: {}", last_compaction),
+            format!("write_hooks: {}", self.write_hooks),
+        ];
+
+        for file in &self.files {
+            lines.push(format!(
+                "file {}: total_bytes {}, live_bytes {}",
+                file.name, file.total_bytes, file.live_bytes
+            ));
+        }
+
+        write!(f, "{}", lines.join("\n"))
+    }
+}
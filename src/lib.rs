@@ -1,3 +1,10 @@
 pub mod core;
 
+pub mod client;
+pub mod cluster;
+pub mod protocol;
+pub mod replication;
 pub mod server;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
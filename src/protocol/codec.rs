@@ -0,0 +1,638 @@
+//! Framed binary protocol.
+//!
+//! A connection starts with a handshake: the client sends `MAGIC` followed by the
+//! highest protocol version it speaks, and the server answers with `MAGIC` and the
+//! version both will use, or 0 if it supports none of them.
+//!
+//! After that every request and response is a frame: a big endian `u32` length, then
+//! that many bytes. A request frame starts with an opcode, a response frame with its
+//! code. Strings are a `u32` length followed by their bytes, so keys and values can
+//! hold spaces and newlines. A connection carries any number of requests, which may be
+//! sent without waiting for the previous responses: they are answered in order.
+//!
+//! Writes are not flushed, so callers can batch pipelined frames.
+
+use std::io::{self, Read, Write};
+
+use super::{Request, Response};
+use crate::core::{
+    error::{KiviError, Result},
+    kv::KeyValue,
+};
+
+/// First bytes sent by a binary client, which tell it apart from text commands.
+pub const MAGIC: &[u8; 4] = b"KIVI";
+
+/// Version spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Largest frame accepted by default, to not allocate whatever length a peer sends.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+mod opcode {
+    pub const GET: u8 = 1;
+    pub const SET: u8 = 2;
+    pub const DELETE: u8 = 3;
+    pub const SCAN: u8 = 4;
+    pub const COMPARE_AND_SWAP: u8 = 5;
+    pub const SET_IF_ABSENT: u8 = 6;
+    pub const DELETE_IF_EQUALS: u8 = 7;
+    pub const INCR_BY: u8 = 8;
+    pub const SUBSCRIBE: u8 = 9;
+    pub const WATCH: u8 = 10;
+    pub const BACKUP: u8 = 11;
+    pub const ROLE: u8 = 12;
+    pub const INFO: u8 = 13;
+    pub const EXPIRE: u8 = 14;
+    pub const TTL: u8 = 15;
+    pub const COMPACT: u8 = 16;
+    pub const AUTH: u8 = 17;
+}
+
+/// Response codes. Successful responses are below 0x10, the others map to a `Status`.
+mod code {
+    pub const OK: u8 = 0x00;
+    pub const VALUE: u8 = 0x01;
+    pub const INTEGER: u8 = 0x02;
+    pub const APPLIED: u8 = 0x03;
+    pub const PAIRS: u8 = 0x04;
+    pub const TEXT: u8 = 0x05;
+    pub const EVENT: u8 = 0x06;
+    pub const NOT_FOUND: u8 = 0x10;
+    pub const ERROR: u8 = 0x20;
+    pub const REDIRECT: u8 = 0x21;
+    pub const UNAUTHORIZED: u8 = 0x22;
+}
+
+/// Client side of the handshake, returns the version chosen by the server.
+pub fn handshake<S: Read + Write>(stream: &mut S) -> Result<u8> {
+    stream.write_all(&greeting(PROTOCOL_VERSION))?;
+
+    accepted_version(read_greeting(stream)?)
+}
+
+/// Checks the version answered by the server is one this client speaks.
+pub(super) fn accepted_version(version: u8) -> Result<u8> {
+    if version == 0 || version > PROTOCOL_VERSION {
+        return Err(protocol_error(format!(
+            "server does not support protocol version {}",
+            PROTOCOL_VERSION
+        )));
+    }
+
+    Ok(version)
+}
+
+/// Server side of the handshake, returns the version both ends will use.
+pub fn accept_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u8> {
+    let offered = read_greeting(reader)?;
+    let version = offered.min(PROTOCOL_VERSION);
+
+    writer.write_all(&greeting(version))?;
+
+    if version == 0 {
+        return Err(protocol_error("client offered protocol version 0"));
+    }
+
+    Ok(version)
+}
+
+pub(super) fn greeting(version: u8) -> [u8; 5] {
+    let mut greeting = [0; 5];
+    greeting[..4].copy_from_slice(MAGIC);
+    greeting[4] = version;
+
+    greeting
+}
+
+fn read_greeting<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut greeting = [0; 5];
+    reader.read_exact(&mut greeting)?;
+
+    parse_greeting(&greeting)
+}
+
+/// Version offered or chosen by the peer.
+pub(super) fn parse_greeting(greeting: &[u8; 5]) -> Result<u8> {
+    if &greeting[..4] != MAGIC {
+        return Err(protocol_error("bad handshake"));
+    }
+
+    Ok(greeting[4])
+}
+
+pub fn write_request<W: Write>(writer: &mut W, request: &Request) -> Result<()> {
+    write_frame(writer, &encode_request(request))
+}
+
+/// Returns None if the peer closed the connection between two frames. Frames larger
+/// than `max_size` are refused before being read.
+pub fn read_request<R: Read>(reader: &mut R, max_size: usize) -> Result<Option<Request>> {
+    match read_frame(reader, max_size)? {
+        Some(frame) => decode_request(&frame).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn write_response<W: Write>(writer: &mut W, response: &Response) -> Result<()> {
+    write_frame(writer, &encode_response(response)?)
+}
+
+/// Returns None if the peer closed the connection between two frames.
+pub fn read_response<R: Read>(reader: &mut R) -> Result<Option<Response>> {
+    match read_frame(reader, MAX_FRAME_SIZE)? {
+        Some(frame) => decode_response(&frame).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    check_frame_size(payload.len(), MAX_FRAME_SIZE)?;
+
+    // One write, so small frames go out in a single packet
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+
+    Ok(())
+}
+
+fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+
+    // EOF before the first byte is a clean close, anywhere else a truncated frame
+    match reader.read(&mut len[..1]) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => return read_frame(reader, max_size),
+        Err(e) => return Err(e.into()),
+    }
+    reader.read_exact(&mut len[1..])?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    check_frame_size(len, max_size)?;
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(payload))
+}
+
+pub(super) fn check_frame_size(len: usize, max_size: usize) -> Result<()> {
+    if len > max_size {
+        return Err(protocol_error(format!(
+            "frame of {} bytes is over the limit of {}",
+            len, max_size
+        )));
+    }
+
+    Ok(())
+}
+
+pub(super) fn encode_request(request: &Request) -> Vec<u8> {
+    let mut out = Encoder::default();
+
+    match request {
+        Request::Get { key } => {
+            out.u8(opcode::GET);
+            out.str(key);
+        }
+        Request::Set { key, value } => {
+            out.u8(opcode::SET);
+            out.str(key);
+            out.str(value);
+        }
+        Request::Delete { key } => {
+            out.u8(opcode::DELETE);
+            out.str(key);
+        }
+        Request::Scan { prefix } => {
+            out.u8(opcode::SCAN);
+            out.str(prefix);
+        }
+        Request::CompareAndSwap { key, expected, new } => {
+            out.u8(opcode::COMPARE_AND_SWAP);
+            out.str(key);
+            out.str(expected);
+            out.str(new);
+        }
+        Request::SetIfAbsent { key, value } => {
+            out.u8(opcode::SET_IF_ABSENT);
+            out.str(key);
+            out.str(value);
+        }
+        Request::DeleteIfEquals { key, expected } => {
+            out.u8(opcode::DELETE_IF_EQUALS);
+            out.str(key);
+            out.str(expected);
+        }
+        Request::IncrBy { key, delta } => {
+            out.u8(opcode::INCR_BY);
+            out.str(key);
+            out.i64(*delta);
+        }
+        Request::Expire { key, seconds } => {
+            out.u8(opcode::EXPIRE);
+            out.str(key);
+            out.u64(*seconds);
+        }
+        Request::Ttl { key } => {
+            out.u8(opcode::TTL);
+            out.str(key);
+        }
+        Request::Subscribe { from_seq } => {
+            out.u8(opcode::SUBSCRIBE);
+            match from_seq {
+                Some(seq) => {
+                    out.u8(1);
+                    out.u64(*seq);
+                }
+                None => out.u8(0),
+            }
+        }
+        Request::Watch { pattern } => {
+            out.u8(opcode::WATCH);
+            out.str(pattern);
+        }
+        Request::Backup { dir } => {
+            out.u8(opcode::BACKUP);
+            out.str(dir);
+        }
+        Request::Compact => out.u8(opcode::COMPACT),
+        Request::Role => out.u8(opcode::ROLE),
+        Request::Info => out.u8(opcode::INFO),
+        Request::Auth { user, secret } => {
+            out.u8(opcode::AUTH);
+            match user {
+                Some(user) => {
+                    out.u8(1);
+                    out.str(user);
+                }
+                None => out.u8(0),
+            }
+            out.str(secret);
+        }
+    }
+
+    out.buf
+}
+
+pub(super) fn decode_request(frame: &[u8]) -> Result<Request> {
+    let mut input = Decoder { buf: frame };
+
+    let request = match input.u8()? {
+        opcode::GET => Request::Get { key: input.str()? },
+        opcode::SET => Request::Set {
+            key: input.str()?,
+            value: input.str()?,
+        },
+        opcode::DELETE => Request::Delete { key: input.str()? },
+        opcode::SCAN => Request::Scan {
+            prefix: input.str()?,
+        },
+        opcode::COMPARE_AND_SWAP => Request::CompareAndSwap {
+            key: input.str()?,
+            expected: input.str()?,
+            new: input.str()?,
+        },
+        opcode::SET_IF_ABSENT => Request::SetIfAbsent {
+            key: input.str()?,
+            value: input.str()?,
+        },
+        opcode::DELETE_IF_EQUALS => Request::DeleteIfEquals {
+            key: input.str()?,
+            expected: input.str()?,
+        },
+        opcode::INCR_BY => Request::IncrBy {
+            key: input.str()?,
+            delta: input.i64()?,
+        },
+        opcode::EXPIRE => Request::Expire {
+            key: input.str()?,
+            seconds: input.u64()?,
+        },
+        opcode::TTL => Request::Ttl { key: input.str()? },
+        opcode::SUBSCRIBE => Request::Subscribe {
+            from_seq: match input.u8()? {
+                0 => None,
+                _ => Some(input.u64()?),
+            },
+        },
+        opcode::WATCH => Request::Watch {
+            pattern: input.str()?,
+        },
+        opcode::BACKUP => Request::Backup { dir: input.str()? },
+        opcode::COMPACT => Request::Compact,
+        opcode::ROLE => Request::Role,
+        opcode::INFO => Request::Info,
+        opcode::AUTH => Request::Auth {
+            user: match input.u8()? {
+                0 => None,
+                _ => Some(input.str()?),
+            },
+            secret: input.str()?,
+        },
+        op => return Err(protocol_error(format!("unknown opcode {}", op))),
+    };
+
+    input.finish()?;
+
+    Ok(request)
+}
+
+pub(super) fn encode_response(response: &Response) -> Result<Vec<u8>> {
+    let mut out = Encoder::default();
+
+    match response {
+        Response::Ok => out.u8(code::OK),
+        Response::Value(value) => {
+            out.u8(code::VALUE);
+            out.str(value);
+        }
+        Response::Integer(value) => {
+            out.u8(code::INTEGER);
+            out.i64(*value);
+        }
+        Response::Applied(applied) => {
+            out.u8(code::APPLIED);
+            out.u8(*applied as u8);
+        }
+        Response::Pairs(pairs) => {
+            out.u8(code::PAIRS);
+            out.u32(pairs.len() as u32);
+            for kv in pairs {
+                out.str(&kv.key);
+                out.str(&kv.value);
+            }
+        }
+        Response::Text(text) => {
+            out.u8(code::TEXT);
+            out.str(text);
+        }
+        Response::Event(event) => {
+            out.u8(code::EVENT);
+            out.bytes(&serde_json::to_vec(event)?);
+        }
+        Response::NotFound => out.u8(code::NOT_FOUND),
+        Response::Error(message) => {
+            out.u8(code::ERROR);
+            out.str(message);
+        }
+        Response::Redirect(addr) => {
+            out.u8(code::REDIRECT);
+            out.str(addr);
+        }
+        Response::Unauthorized(message) => {
+            out.u8(code::UNAUTHORIZED);
+            out.str(message);
+        }
+    }
+
+    Ok(out.buf)
+}
+
+pub(super) fn decode_response(frame: &[u8]) -> Result<Response> {
+    let mut input = Decoder { buf: frame };
+
+    let response = match input.u8()? {
+        code::OK => Response::Ok,
+        code::VALUE => Response::Value(input.str()?),
+        code::INTEGER => Response::Integer(input.i64()?),
+        code::APPLIED => Response::Applied(input.u8()? != 0),
+        code::PAIRS => {
+            let count = input.u32()?;
+            let mut pairs = Vec::new();
+            for _ in 0..count {
+                pairs.push(KeyValue {
+                    key: input.str()?,
+                    value: input.str()?,
+                });
+            }
+
+            Response::Pairs(pairs)
+        }
+        code::TEXT => Response::Text(input.str()?),
+        code::EVENT => Response::Event(serde_json::from_slice(input.bytes()?)?),
+        code::NOT_FOUND => Response::NotFound,
+        code::ERROR => Response::Error(input.str()?),
+        code::REDIRECT => Response::Redirect(input.str()?),
+        code::UNAUTHORIZED => Response::Unauthorized(input.str()?),
+        c => return Err(protocol_error(format!("unknown response code {}", c))),
+    };
+
+    input.finish()?;
+
+    Ok(response)
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn i64(&mut self, v: i64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    fn str(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(protocol_error("truncated frame"));
+        }
+
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+
+        self.take(len)
+    }
+
+    /// Keys and values are stored as strings, so they have to be valid UTF-8.
+    fn str(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| protocol_error("string is not valid UTF-8"))
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.buf.is_empty() {
+            true => Ok(()),
+            false => Err(protocol_error("trailing bytes in frame")),
+        }
+    }
+}
+
+fn protocol_error<S: Into<String>>(message: S) -> KiviError {
+    KiviError::Protocol(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{changes::ChangeEvent, kv::KiviCommand};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let requests = vec![
+            Request::Set {
+                key: "a key".to_string(),
+                value: "x\ny ".repeat(1000),
+            },
+            Request::IncrBy {
+                key: "n".to_string(),
+                delta: -3,
+            },
+            Request::Expire {
+                key: "e".to_string(),
+                seconds: 10,
+            },
+            Request::Subscribe { from_seq: Some(7) },
+            Request::Subscribe { from_seq: None },
+            Request::Compact,
+            Request::Info,
+            Request::Auth {
+                user: Some("app".to_string()),
+                secret: "secret".to_string(),
+            },
+            Request::Auth {
+                user: None,
+                secret: "token".to_string(),
+            },
+        ];
+        let responses = vec![
+            Response::Value("with spaces".to_string()),
+            Response::Pairs(vec![KeyValue {
+                key: "a".to_string(),
+                value: "1".to_string(),
+            }]),
+            Response::Event(ChangeEvent {
+                seq: 1,
+                command: KiviCommand::Delete {
+                    bucket: String::new(),
+                    key: "a".to_string(),
+                },
+            }),
+            Response::Applied(true),
+            Response::NotFound,
+            Response::Redirect("127.0.0.1:1".to_string()),
+            Response::Unauthorized("read-only user".to_string()),
+        ];
+
+        let mut buf = Vec::new();
+        for request in &requests {
+            write_request(&mut buf, request).unwrap();
+        }
+        let mut reader = Cursor::new(buf);
+        for request in &requests {
+            assert_eq!(
+                read_request(&mut reader, MAX_FRAME_SIZE).unwrap().as_ref(),
+                Some(request)
+            );
+        }
+        assert_eq!(read_request(&mut reader, MAX_FRAME_SIZE).unwrap(), None);
+
+        let mut buf = Vec::new();
+        for response in &responses {
+            write_response(&mut buf, response).unwrap();
+        }
+        let mut reader = Cursor::new(buf);
+        for response in &responses {
+            assert_eq!(read_response(&mut reader).unwrap().as_ref(), Some(response));
+        }
+        assert_eq!(read_response(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        // Length over the limit
+        let mut frame = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        frame.push(opcode::INFO);
+        assert!(matches!(
+            read_request(&mut Cursor::new(frame), MAX_FRAME_SIZE),
+            Err(KiviError::Protocol(_))
+        ));
+
+        // Cut in the middle of the payload
+        let mut frame = Vec::new();
+        write_request(
+            &mut frame,
+            &Request::Get {
+                key: "a".to_string(),
+            },
+        )
+        .unwrap();
+        frame.pop();
+        assert!(read_request(&mut Cursor::new(frame), MAX_FRAME_SIZE).is_err());
+
+        // Invalid UTF-8 key
+        let frame = [0, 0, 0, 6, opcode::GET, 0, 0, 0, 1, 0xff];
+        assert!(matches!(
+            read_request(&mut Cursor::new(frame), MAX_FRAME_SIZE),
+            Err(KiviError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut server_out = Vec::new();
+        let mut client_hello = MAGIC.to_vec();
+        client_hello.push(9);
+        assert_eq!(
+            accept_handshake(&mut Cursor::new(client_hello), &mut server_out).unwrap(),
+            PROTOCOL_VERSION
+        );
+        assert_eq!(&server_out[..4], MAGIC);
+        assert_eq!(server_out[4], PROTOCOL_VERSION);
+
+        assert!(accept_handshake(&mut Cursor::new(b"get a".to_vec()), &mut Vec::new()).is_err());
+    }
+}
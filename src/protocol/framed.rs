@@ -0,0 +1,170 @@
+//! The binary protocol of `codec` for tokio connections.
+//!
+//! The handshake is done on the raw stream, then `Framed` with `ServerCodec` or
+//! `ClientCodec` turns it into a stream of requests or responses.
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::codec::{self, MAX_FRAME_SIZE, PROTOCOL_VERSION};
+use super::{Request, Response};
+use crate::core::error::{KiviError, Result};
+
+/// Client side of the handshake, returns the version chosen by the server.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<u8> {
+    stream.write_all(&codec::greeting(PROTOCOL_VERSION)).await?;
+
+    let mut greeting = [0; 5];
+    stream.read_exact(&mut greeting).await?;
+
+    codec::accepted_version(codec::parse_greeting(&greeting)?)
+}
+
+/// Server side of the handshake, for a greeting already read from the client.
+pub async fn accept_handshake<W: AsyncWrite + Unpin>(
+    greeting: &[u8; 5],
+    writer: &mut W,
+) -> Result<u8> {
+    let version = codec::parse_greeting(greeting)?.min(PROTOCOL_VERSION);
+
+    writer.write_all(&codec::greeting(version)).await?;
+
+    if version == 0 {
+        return Err(KiviError::Protocol(
+            "client offered protocol version 0".to_string(),
+        ));
+    }
+
+    Ok(version)
+}
+
+/// Decodes requests and encodes responses.
+#[derive(Debug, Clone)]
+pub struct ServerCodec {
+    max_request_size: usize,
+}
+
+impl ServerCodec {
+    /// Frames larger than `max_request_size` are refused before being read.
+    pub fn new(max_request_size: usize) -> Self {
+        Self { max_request_size }
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = Request;
+    type Error = KiviError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Request>> {
+        match decode_frame(src, self.max_request_size)? {
+            Some(frame) => codec::decode_request(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Response> for ServerCodec {
+    type Error = KiviError;
+
+    fn encode(&mut self, response: Response, dst: &mut BytesMut) -> Result<()> {
+        encode_frame(&codec::encode_response(&response)?, dst)
+    }
+}
+
+/// Encodes requests and decodes responses.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCodec;
+
+impl Decoder for ClientCodec {
+    type Item = Response;
+    type Error = KiviError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Response>> {
+        match decode_frame(src, MAX_FRAME_SIZE)? {
+            Some(frame) => codec::decode_response(&frame).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<&Request> for ClientCodec {
+    type Error = KiviError;
+
+    fn encode(&mut self, request: &Request, dst: &mut BytesMut) -> Result<()> {
+        encode_frame(&codec::encode_request(request), dst)
+    }
+}
+
+/// Splits a whole frame off `src`, or returns None until enough bytes arrived.
+fn decode_frame(src: &mut BytesMut, max_size: usize) -> Result<Option<BytesMut>> {
+    if src.len() < 4 {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+    codec::check_frame_size(len, max_size)?;
+
+    if src.len() < 4 + len {
+        src.reserve(4 + len - src.len());
+        return Ok(None);
+    }
+
+    src.advance(4);
+
+    Ok(Some(src.split_to(len)))
+}
+
+fn encode_frame(payload: &[u8], dst: &mut BytesMut) -> Result<()> {
+    codec::check_frame_size(payload.len(), MAX_FRAME_SIZE)?;
+
+    dst.reserve(4 + payload.len());
+    dst.put_u32(payload.len() as u32);
+    dst.put_slice(payload);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs() {
+        let request = Request::Set {
+            key: "a key".to_string(),
+            value: "x\ny".to_string(),
+        };
+        let mut buf = BytesMut::new();
+        ClientCodec.encode(&request, &mut buf).unwrap();
+
+        // Nothing is decoded before the whole frame arrived
+        let mut server = ServerCodec::new(MAX_FRAME_SIZE);
+        let mut partial = buf.split_to(6);
+        assert_eq!(server.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(server.decode(&mut partial).unwrap(), Some(request));
+        assert!(partial.is_empty());
+
+        let mut buf = BytesMut::new();
+        server.encode(Response::NotFound, &mut buf).unwrap();
+        server
+            .encode(Response::Value("1".to_string()), &mut buf)
+            .unwrap();
+        assert_eq!(
+            ClientCodec.decode(&mut buf).unwrap(),
+            Some(Response::NotFound)
+        );
+        assert_eq!(
+            ClientCodec.decode(&mut buf).unwrap(),
+            Some(Response::Value("1".to_string()))
+        );
+
+        // The length alone is enough to refuse a frame
+        let mut buf = BytesMut::from(&1025u32.to_be_bytes()[..]);
+        assert!(matches!(
+            ServerCodec::new(1024).decode(&mut buf),
+            Err(KiviError::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,347 @@
+//! Requests and responses understood by `KiviServer`.
+//!
+//! The same typed `Request` and `Response` are used by every wire format: the framed
+//! binary protocol of `codec` (and `framed` for tokio), Redis clients through `resp`,
+//! and the older text protocol where a command is a line of words separated by spaces.
+
+pub mod codec;
+#[cfg(feature = "async")]
+pub mod framed;
+pub mod resp;
+
+use crate::core::{
+    changes::ChangeEvent,
+    error::{KiviError, Result},
+    kv::KeyValue,
+};
+
+/// Message of the `Unauthorized` response to connections that did not log in yet.
+pub const AUTH_REQUIRED: &str = "authentication required";
+
+/// Commands of the text protocol, as accepted by `Request::parse`.
+const TEXT_COMMANDS: &[&str] = &[
+    "set",
+    "get",
+    "delete",
+    "del",
+    "scan",
+    "cas",
+    "setnx",
+    "delifeq",
+    "incr",
+    "decr",
+    "incrby",
+    "expire",
+    "ttl",
+    "subscribe",
+    "watch",
+    "backup",
+    "compact",
+    "role",
+    "info",
+    "auth",
+];
+
+/// The name `Request::name` gives the requests of a text command, so aliases like
+/// `del` or `incr` name the same command as `delete` or `incrby`. None for unknown
+/// commands.
+pub fn canonical_command(name: &str) -> Option<&'static str> {
+    match name.to_ascii_lowercase().as_str() {
+        "del" => Some("delete"),
+        "incr" | "decr" => Some("incrby"),
+        name => TEXT_COMMANDS.iter().find(|c| **c == name).copied(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    Scan {
+        prefix: String,
+    },
+    CompareAndSwap {
+        key: String,
+        expected: String,
+        new: String,
+    },
+    SetIfAbsent {
+        key: String,
+        value: String,
+    },
+    DeleteIfEquals {
+        key: String,
+        expected: String,
+    },
+    IncrBy {
+        key: String,
+        delta: i64,
+    },
+    Expire {
+        key: String,
+        seconds: u64,
+    },
+    /// Answered with the seconds left, -1 when the key never expires
+    Ttl {
+        key: String,
+    },
+    Subscribe {
+        from_seq: Option<u64>,
+    },
+    /// Exact key, or prefix when ending with `*`
+    Watch {
+        pattern: String,
+    },
+    Backup {
+        dir: String,
+    },
+    /// Rewrites the data files of the server, see `KiviStore::compact`
+    Compact,
+    Role,
+    Info,
+
+    /// Logs the connection in, with a user name and password or with a token alone
+    Auth {
+        user: Option<String>,
+        secret: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok,
+    Value(String),
+    Integer(i64),
+
+    /// Whether a conditional write was applied
+    Applied(bool),
+    Pairs(Vec<KeyValue>),
+    Text(String),
+
+    /// One of the responses streamed after a subscribe or watch request
+    Event(ChangeEvent),
+    NotFound,
+    Error(String),
+
+    /// Address of the node that can serve the request
+    Redirect(String),
+
+    /// The connection is not logged in, or its user is not allowed the request
+    Unauthorized(String),
+}
+
+/// Outcome of a request, the first byte of every binary response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    NotFound,
+    Error,
+    Redirect,
+    Unauthorized,
+}
+
+impl Response {
+    pub fn status(&self) -> Status {
+        match self {
+            Response::NotFound => Status::NotFound,
+            Response::Error(_) => Status::Error,
+            Response::Redirect(_) => Status::Redirect,
+            Response::Unauthorized(_) => Status::Unauthorized,
+            _ => Status::Ok,
+        }
+    }
+}
+
+impl Request {
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Request::Set { .. }
+                | Request::Delete { .. }
+                | Request::CompareAndSwap { .. }
+                | Request::SetIfAbsent { .. }
+                | Request::DeleteIfEquals { .. }
+                | Request::IncrBy { .. }
+                | Request::Expire { .. }
+        )
+    }
+
+    /// Name of the command in the text protocol, which ACLs refer to.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Delete { .. } => "delete",
+            Request::Scan { .. } => "scan",
+            Request::CompareAndSwap { .. } => "cas",
+            Request::SetIfAbsent { .. } => "setnx",
+            Request::DeleteIfEquals { .. } => "delifeq",
+            Request::IncrBy { .. } => "incrby",
+            Request::Expire { .. } => "expire",
+            Request::Ttl { .. } => "ttl",
+            Request::Subscribe { .. } => "subscribe",
+            Request::Watch { .. } => "watch",
+            Request::Backup { .. } => "backup",
+            Request::Compact => "compact",
+            Request::Role => "role",
+            Request::Info => "info",
+            Request::Auth { .. } => "auth",
+        }
+    }
+
+    /// The key this request reads or writes, if it is about a single one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Delete { key }
+            | Request::CompareAndSwap { key, .. }
+            | Request::SetIfAbsent { key, .. }
+            | Request::DeleteIfEquals { key, .. }
+            | Request::IncrBy { key, .. }
+            | Request::Expire { key, .. }
+            | Request::Ttl { key } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Parses the words of a text protocol command, like `set key value`. Unknown
+    /// commands and wrong arguments are a `KiviError::Protocol`.
+    pub fn parse(words: &[String]) -> Result<Request> {
+        let name = match words.first() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Err(KiviError::Protocol("empty command".to_string())),
+        };
+        let args = &words[1..];
+        let invalid = |what: &str| KiviError::Protocol(format!("{} {}", what, name));
+
+        let request = match (name.as_str(), args) {
+            ("set", [key, value]) => Request::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            ("get", [key]) => Request::Get { key: key.clone() },
+            ("delete" | "del", [key]) => Request::Delete { key: key.clone() },
+            ("scan", []) => Request::Scan {
+                prefix: String::new(),
+            },
+            ("scan", [prefix]) => Request::Scan {
+                prefix: prefix.clone(),
+            },
+            ("cas", [key, expected, new]) => Request::CompareAndSwap {
+                key: key.clone(),
+                expected: expected.clone(),
+                new: new.clone(),
+            },
+            ("setnx", [key, value]) => Request::SetIfAbsent {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            ("delifeq", [key, expected]) => Request::DeleteIfEquals {
+                key: key.clone(),
+                expected: expected.clone(),
+            },
+            ("incr", [key]) => Request::IncrBy {
+                key: key.clone(),
+                delta: 1,
+            },
+            ("decr", [key]) => Request::IncrBy {
+                key: key.clone(),
+                delta: -1,
+            },
+            ("incrby", [key, delta]) => Request::IncrBy {
+                key: key.clone(),
+                delta: delta.parse().map_err(|_| invalid("invalid delta for"))?,
+            },
+            ("expire", [key, seconds]) => Request::Expire {
+                key: key.clone(),
+                seconds: seconds
+                    .parse()
+                    .map_err(|_| invalid("invalid seconds for"))?,
+            },
+            ("ttl", [key]) => Request::Ttl { key: key.clone() },
+            ("subscribe", []) => Request::Subscribe { from_seq: None },
+            ("subscribe", [seq]) => Request::Subscribe {
+                from_seq: Some(seq.parse().map_err(|_| invalid("invalid sequence for"))?),
+            },
+            ("watch", [pattern]) => Request::Watch {
+                pattern: pattern.clone(),
+            },
+            ("backup", [dir]) => Request::Backup { dir: dir.clone() },
+            ("compact", []) => Request::Compact,
+            ("role", []) => Request::Role,
+            ("info", []) => Request::Info,
+            ("auth", [token]) => Request::Auth {
+                user: None,
+                secret: token.clone(),
+            },
+            ("auth", [user, password]) => Request::Auth {
+                user: Some(user.clone()),
+                secret: password.clone(),
+            },
+            (name, _) if TEXT_COMMANDS.contains(&name) => {
+                return Err(invalid("wrong number of arguments for"))
+            }
+            _ => return Err(invalid("unknown command")),
+        };
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<Request> {
+        let words: Vec<String> = line.split(' ').map(|w| w.to_string()).collect();
+
+        Request::parse(&words).ok()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("SET a 1"),
+            Some(Request::Set {
+                key: "a".to_string(),
+                value: "1".to_string()
+            })
+        );
+        assert_eq!(
+            parse("decr a"),
+            Some(Request::IncrBy {
+                key: "a".to_string(),
+                delta: -1
+            })
+        );
+        assert_eq!(
+            parse("subscribe 3"),
+            Some(Request::Subscribe { from_seq: Some(3) })
+        );
+        assert_eq!(parse("incrby a x"), None);
+        assert_eq!(parse("get a b"), None);
+        assert_eq!(parse("sync"), None);
+        assert_eq!(parse("COMPACT"), Some(Request::Compact));
+
+        let words = vec!["get".to_string(), "a".to_string(), "b".to_string()];
+        match Request::parse(&words) {
+            Err(KiviError::Protocol(message)) => {
+                assert_eq!(message, "wrong number of arguments for get")
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+        match Request::parse(&["frob".to_string()]) {
+            Err(KiviError::Protocol(message)) => assert_eq!(message, "unknown command frob"),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
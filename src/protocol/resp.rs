@@ -0,0 +1,816 @@
+//! Redis serialization protocol, so `redis-cli` and Redis client libraries can talk to
+//! kivi.
+//!
+//! Connections start in RESP2 and switch to RESP3 with `HELLO 3`. Commands are arrays
+//! of bulk strings, mapped to the same `Request`s as the other protocols. Only the
+//! default bucket is reachable, as database 0.
+
+use std::io::{BufRead, Read, Write};
+
+use super::{Request, Response, AUTH_REQUIRED};
+use crate::core::error::{KiviError, Result};
+
+/// Most arguments accepted in a single command.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+
+/// Longest header line, like `*3` or `$5`.
+const MAX_LINE_LENGTH: u64 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Value>),
+
+    /// Sent as a flat array of keys and values to RESP2 clients
+    Map(Vec<(Value, Value)>),
+}
+
+/// State of a RESP connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    /// 2 or 3, chosen by the client with `HELLO`
+    pub version: u8,
+
+    /// Set by `QUIT`, the connection is closed after the reply
+    pub closed: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            version: 2,
+            closed: false,
+        }
+    }
+}
+
+/// A supported command, as listed by `COMMAND`.
+struct CommandSpec {
+    name: &'static str,
+
+    /// Number of arguments, the name included. Negative for a minimum.
+    arity: i64,
+    flags: &'static [&'static str],
+    first_key: i64,
+    last_key: i64,
+    key_step: i64,
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    keys: (i64, i64, i64),
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        key_step: keys.2,
+    }
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    spec("get", 2, &["readonly", "fast"], (1, 1, 1)),
+    spec("set", -3, &["write"], (1, 1, 1)),
+    spec("setnx", 3, &["write", "fast"], (1, 1, 1)),
+    spec("del", -2, &["write"], (1, -1, 1)),
+    spec("exists", -2, &["readonly", "fast"], (1, -1, 1)),
+    spec("keys", 2, &["readonly"], (0, 0, 0)),
+    spec("scan", -2, &["readonly"], (0, 0, 0)),
+    spec("incr", 2, &["write", "fast"], (1, 1, 1)),
+    spec("decr", 2, &["write", "fast"], (1, 1, 1)),
+    spec("incrby", 3, &["write", "fast"], (1, 1, 1)),
+    spec("decrby", 3, &["write", "fast"], (1, 1, 1)),
+    spec("expire", 3, &["write", "fast"], (1, 1, 1)),
+    spec("ttl", 2, &["readonly", "fast"], (1, 1, 1)),
+    spec("ping", -1, &["fast"], (0, 0, 0)),
+    spec("echo", 2, &["fast"], (0, 0, 0)),
+    spec("info", -1, &["loading"], (0, 0, 0)),
+    spec("command", -1, &["loading"], (0, 0, 0)),
+    spec("hello", -1, &["fast", "no-auth"], (0, 0, 0)),
+    spec("auth", -2, &["fast", "no-auth"], (0, 0, 0)),
+    spec("client", -2, &["fast"], (0, 0, 0)),
+    spec("select", 2, &["fast"], (0, 0, 0)),
+    spec("quit", -1, &["fast"], (0, 0, 0)),
+];
+
+/// Reads the next command, as its raw arguments. Returns None if the peer closed the
+/// connection between two commands. Fails once the arguments add up to more than
+/// `max_size` bytes.
+pub fn read_command<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let count = match read_line(reader)? {
+        Some(line) => parse_header(&line, b'*', MAX_ARGUMENTS)?,
+        None => return Ok(None),
+    };
+
+    let mut args = Vec::new();
+    let mut size = 0;
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("truncated command"))?;
+        let len = parse_header(&line, b'$', usize::MAX)?;
+
+        size += len;
+        if size > max_size {
+            return Err(protocol_error(format!(
+                "request is over the limit of {} bytes",
+                max_size
+            )));
+        }
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', &mut line)?;
+
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    match line.strip_suffix(b"\r\n") {
+        Some(line) => Ok(Some(line.to_vec())),
+        None => Err(protocol_error("line not terminated by CRLF")),
+    }
+}
+
+/// Parses `*<count>` or `$<length>`.
+fn parse_header(line: &[u8], kind: u8, max: usize) -> Result<usize> {
+    if line.first() != Some(&kind) {
+        return Err(protocol_error(format!(
+            "expected '{}', got '{}'",
+            kind as char,
+            String::from_utf8_lossy(line)
+        )));
+    }
+
+    std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n <= max)
+        .ok_or_else(|| protocol_error(format!("invalid length {}", String::from_utf8_lossy(line))))
+}
+
+/// Not flushed, so callers can batch the replies of pipelined commands.
+pub fn write_value<W: Write>(writer: &mut W, value: &Value, version: u8) -> Result<()> {
+    let mut out = Vec::new();
+    encode(&mut out, value, version);
+    writer.write_all(&out)?;
+
+    Ok(())
+}
+
+/// Simple strings and errors end at the first line break, and often echo what the
+/// client sent, so line breaks must not get through.
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+fn encode(out: &mut Vec<u8>, value: &Value, version: u8) {
+    match value {
+        Value::Simple(s) => out.extend_from_slice(format!("+{}\r\n", single_line(s)).as_bytes()),
+        Value::Error(e) => out.extend_from_slice(format!("-{}\r\n", single_line(e)).as_bytes()),
+        Value::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+        Value::Bulk(s) => {
+            out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+            out.extend_from_slice(s.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Value::Null if version >= 3 => out.extend_from_slice(b"_\r\n"),
+        Value::Null => out.extend_from_slice(b"$-1\r\n"),
+        Value::Array(values) => {
+            out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+            for v in values {
+                encode(out, v, version);
+            }
+        }
+        Value::Map(pairs) => {
+            let header = match version >= 3 {
+                true => format!("%{}\r\n", pairs.len()),
+                false => format!("*{}\r\n", pairs.len() * 2),
+            };
+            out.extend_from_slice(header.as_bytes());
+
+            for (k, v) in pairs {
+                encode(out, k, version);
+                encode(out, v, version);
+            }
+        }
+    }
+}
+
+/// Runs a command. `handle` serves the requests it maps to, so the caller decides
+/// where they go, like for the other protocols.
+pub fn execute<F>(args: Vec<Vec<u8>>, session: &mut Session, mut handle: F) -> Value
+where
+    F: FnMut(Request) -> Response,
+{
+    let args: Vec<String> = match args.into_iter().map(String::from_utf8).collect() {
+        Ok(args) => args,
+        Err(_) => return error("arguments must be valid UTF-8"),
+    };
+
+    let name = match args.first() {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return error("empty command"),
+    };
+
+    match COMMANDS.iter().find(|c| c.name == name) {
+        Some(c) if !arity_matches(c.arity, args.len()) => {
+            return error(&format!("wrong number of arguments for '{}' command", name))
+        }
+        Some(_) => {}
+        None => return error(&format!("unknown command '{}'", args[0])),
+    }
+
+    let args = &args[1..];
+
+    match (name.as_str(), args) {
+        ("get", [key]) => to_value(handle(Request::Get { key: key.clone() })),
+        ("set", [key, value, options @ ..]) => set(key, value, options, &mut handle),
+        ("setnx", [key, value]) => to_value(handle(Request::SetIfAbsent {
+            key: key.clone(),
+            value: value.clone(),
+        })),
+        ("del", keys) => count(keys, &mut handle, |key| Request::Delete { key }),
+        ("exists", keys) => count(keys, &mut handle, |key| Request::Get { key }),
+        ("keys", [pattern]) => match matching_keys(pattern, &mut handle) {
+            Ok(keys) => Value::Array(keys),
+            Err(e) => e,
+        },
+        ("scan", [cursor, options @ ..]) => scan(cursor, options, &mut handle),
+        ("incr" | "decr", [key]) => to_value(handle(Request::IncrBy {
+            key: key.clone(),
+            delta: if name == "incr" { 1 } else { -1 },
+        })),
+        ("incrby" | "decrby", [key, delta]) => match delta.parse::<i64>() {
+            Ok(delta) => to_value(handle(Request::IncrBy {
+                key: key.clone(),
+                delta: if name == "incrby" { delta } else { -delta },
+            })),
+            Err(_) => not_an_integer(),
+        },
+        ("expire", [key, seconds]) => match seconds.parse::<u64>() {
+            Ok(seconds) => to_value(handle(Request::Expire {
+                key: key.clone(),
+                seconds,
+            })),
+            Err(_) => not_an_integer(),
+        },
+        ("ttl", [key]) => match handle(Request::Ttl { key: key.clone() }) {
+            Response::NotFound => Value::Integer(-2),
+            response => to_value(response),
+        },
+        ("ping", []) => Value::Simple("PONG".to_string()),
+        ("ping" | "echo", [message]) => Value::Bulk(message.clone()),
+        ("info", _) => match handle(Request::Info) {
+            Response::Text(text) => {
+                Value::Bulk(format!("# Kivi\r\n{}\r\n", text.replace('\n', "\r\n")))
+            }
+            response => to_value(response),
+        },
+        ("command", args) => command(args),
+        ("hello", args) => hello(args, session, &mut handle),
+        ("auth", [token]) => auth(None, token, &mut handle),
+        ("auth", [user, password]) => auth(Some(user), password, &mut handle),
+        ("client", _) => ok(),
+        ("select", [db]) if db == "0" => ok(),
+        ("select", _) => error("DB index is out of range"),
+        ("quit", _) => {
+            session.closed = true;
+
+            ok()
+        }
+        _ => error("syntax error"),
+    }
+}
+
+fn arity_matches(arity: i64, len: usize) -> bool {
+    match arity < 0 {
+        true => len as i64 >= -arity,
+        false => len as i64 == arity,
+    }
+}
+
+/// `SET key value [NX] [EX seconds]`
+fn set<F>(key: &str, value: &str, options: &[String], handle: &mut F) -> Value
+where
+    F: FnMut(Request) -> Response,
+{
+    let mut if_absent = false;
+    let mut ttl = None;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "nx" => if_absent = true,
+            "ex" => match options.next().and_then(|s| s.parse::<u64>().ok()) {
+                Some(seconds) if seconds > 0 => ttl = Some(seconds),
+                _ => return error("invalid expire time in 'set' command"),
+            },
+            _ => return error("syntax error"),
+        }
+    }
+
+    let response = match if_absent {
+        true => handle(Request::SetIfAbsent {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+        false => handle(Request::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+        }),
+    };
+
+    match response {
+        Response::Ok | Response::Applied(true) => {}
+        Response::Applied(false) => return Value::Null,
+        response => return to_value(response),
+    }
+
+    if let Some(seconds) = ttl {
+        let expire = handle(Request::Expire {
+            key: key.to_string(),
+            seconds,
+        });
+
+        if let Response::Error(_) | Response::Redirect(_) = expire {
+            return to_value(expire);
+        }
+    }
+
+    ok()
+}
+
+/// Number of keys for which the request found something.
+fn count<F, R>(keys: &[String], handle: &mut F, request: R) -> Value
+where
+    F: FnMut(Request) -> Response,
+    R: Fn(String) -> Request,
+{
+    let mut found = 0;
+
+    for key in keys {
+        match handle(request(key.clone())) {
+            Response::NotFound => {}
+            Response::Ok | Response::Value(_) => found += 1,
+            response => return to_value(response),
+        }
+    }
+
+    Value::Integer(found)
+}
+
+/// The whole key space is returned at once, so the cursor is always 0.
+fn scan<F>(cursor: &str, options: &[String], handle: &mut F) -> Value
+where
+    F: FnMut(Request) -> Response,
+{
+    if cursor != "0" {
+        return error("invalid cursor");
+    }
+
+    let mut pattern = "*";
+    for pair in options.chunks(2) {
+        match pair {
+            [option, arg] if option.eq_ignore_ascii_case("match") => pattern = arg,
+            [option, _] if option.eq_ignore_ascii_case("count") => {}
+            _ => return error("syntax error"),
+        }
+    }
+
+    match matching_keys(pattern, handle) {
+        Ok(keys) => Value::Array(vec![Value::Bulk("0".to_string()), Value::Array(keys)]),
+        Err(e) => e,
+    }
+}
+
+fn matching_keys<F>(pattern: &str, handle: &mut F) -> std::result::Result<Vec<Value>, Value>
+where
+    F: FnMut(Request) -> Response,
+{
+    // Only keys starting with the literal part of the pattern can match
+    let prefix: String = pattern
+        .chars()
+        .take_while(|c| !matches!(c, '*' | '?' | '\\'))
+        .collect();
+
+    match handle(Request::Scan { prefix }) {
+        Response::Pairs(pairs) => Ok(pairs
+            .into_iter()
+            .filter(|kv| glob_matches(pattern.as_bytes(), kv.key.as_bytes()))
+            .map(|kv| Value::Bulk(kv.key))
+            .collect()),
+        response => Err(to_value(response)),
+    }
+}
+
+#[derive(PartialEq)]
+enum Glob {
+    Star,
+    Any,
+    Byte(u8),
+}
+
+/// Matches `*`, `?` and `\` escapes, like Redis patterns without character classes.
+/// Only the last `*` is ever backtracked to, which keeps the match linear in the
+/// pattern times the text instead of exponential in the number of `*`.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut bytes = pattern.iter();
+
+    while let Some(&c) = bytes.next() {
+        tokens.push(match c {
+            b'*' => Glob::Star,
+            b'?' => Glob::Any,
+            b'\\' => Glob::Byte(bytes.next().copied().unwrap_or(b'\\')),
+            c => Glob::Byte(c),
+        });
+    }
+
+    let (mut p, mut t) = (0, 0);
+    // Token after the last `*` seen, and the text position it was tried at
+    let mut star = None;
+
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Glob::Star) => {
+                p += 1;
+                star = Some((p, t));
+            }
+            Some(Glob::Any) => {
+                p += 1;
+                t += 1;
+            }
+            Some(Glob::Byte(c)) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the `*` swallow one more byte
+                Some((after, tried)) => {
+                    p = after;
+                    t = tried + 1;
+                    star = Some((after, t));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    tokens[p..].iter().all(|token| *token == Glob::Star)
+}
+
+fn command(args: &[String]) -> Value {
+    let info = |c: &CommandSpec| {
+        Value::Array(vec![
+            Value::Bulk(c.name.to_string()),
+            Value::Integer(c.arity),
+            Value::Array(
+                c.flags
+                    .iter()
+                    .map(|f| Value::Simple(f.to_string()))
+                    .collect(),
+            ),
+            Value::Integer(c.first_key),
+            Value::Integer(c.last_key),
+            Value::Integer(c.key_step),
+        ])
+    };
+
+    match args.split_first() {
+        None => Value::Array(COMMANDS.iter().map(info).collect()),
+        Some((sub, _)) if sub.eq_ignore_ascii_case("count") => {
+            Value::Integer(COMMANDS.len() as i64)
+        }
+        Some((sub, names)) if sub.eq_ignore_ascii_case("info") => Value::Array(
+            names
+                .iter()
+                .map(
+                    |name| match COMMANDS.iter().find(|c| c.name.eq_ignore_ascii_case(name)) {
+                        Some(c) => info(c),
+                        None => Value::Null,
+                    },
+                )
+                .collect(),
+        ),
+        // No documentation to show
+        Some((sub, _)) if sub.eq_ignore_ascii_case("docs") => Value::Map(Vec::new()),
+        Some(_) => error("syntax error"),
+    }
+}
+
+/// `AUTH [user] password`, where a password without a user is a token.
+fn auth<F>(user: Option<&String>, secret: &str, handle: &mut F) -> Value
+where
+    F: FnMut(Request) -> Response,
+{
+    match handle(Request::Auth {
+        user: user.cloned(),
+        secret: secret.to_string(),
+    }) {
+        Response::Unauthorized(message) => Value::Error(format!("WRONGPASS {}", message)),
+        response => to_value(response),
+    }
+}
+
+/// `HELLO [version [AUTH user password] [SETNAME name]]`, the client name is ignored.
+fn hello<F>(args: &[String], session: &mut Session, handle: &mut F) -> Value
+where
+    F: FnMut(Request) -> Response,
+{
+    let version = match args.first().map(|v| v.as_str()) {
+        None => session.version,
+        Some("2") => 2,
+        Some("3") => 3,
+        Some(_) => return Value::Error("NOPROTO unsupported protocol version".to_string()),
+    };
+
+    let mut options = args.iter().skip(1);
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_str() {
+            "auth" => match (options.next(), options.next()) {
+                (Some(user), Some(password)) => {
+                    let reply = auth(Some(user), password, handle);
+                    if let Value::Error(_) = reply {
+                        return reply;
+                    }
+                }
+                _ => return error("syntax error"),
+            },
+            "setname" if options.next().is_some() => {}
+            _ => return error("syntax error"),
+        }
+    }
+
+    session.version = version;
+
+    let field = |k: &str, v: Value| (Value::Bulk(k.to_string()), v);
+
+    Value::Map(vec![
+        field("server", Value::Bulk("kivi".to_string())),
+        field(
+            "version",
+            Value::Bulk(env!("CARGO_PKG_VERSION").to_string()),
+        ),
+        field("proto", Value::Integer(session.version as i64)),
+        field("mode", Value::Bulk("standalone".to_string())),
+        field("role", Value::Bulk("master".to_string())),
+        field("modules", Value::Array(Vec::new())),
+    ])
+}
+
+fn to_value(response: Response) -> Value {
+    match response {
+        Response::Ok => ok(),
+        Response::Value(value) | Response::Text(value) => Value::Bulk(value),
+        Response::Integer(i) => Value::Integer(i),
+        Response::Applied(applied) => Value::Integer(applied as i64),
+        Response::Pairs(pairs) => Value::Array(
+            pairs
+                .into_iter()
+                .flat_map(|kv| [Value::Bulk(kv.key), Value::Bulk(kv.value)])
+                .collect(),
+        ),
+        Response::Event(_) | Response::NotFound => Value::Null,
+        Response::Error(message) => error(&message),
+        Response::Redirect(addr) => error(&format!("not the leader, redirect to {}", addr)),
+        Response::Unauthorized(message) if message == AUTH_REQUIRED => {
+            Value::Error("NOAUTH Authentication required.".to_string())
+        }
+        Response::Unauthorized(message) => Value::Error(format!("NOPERM {}", message)),
+    }
+}
+
+fn ok() -> Value {
+    Value::Simple("OK".to_string())
+}
+
+fn error(message: &str) -> Value {
+    Value::Error(format!("ERR {}", message))
+}
+
+fn not_an_integer() -> Value {
+    error("value is not an integer or out of range")
+}
+
+fn protocol_error<S: Into<String>>(message: S) -> KiviError {
+    KiviError::Protocol(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{start_server, start_server_with, test_users};
+    use crate::server::ServerConfig;
+    use redis::Commands;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_command() {
+        let input = b"*3\r\n$3\r\nSET\r\n$5\r\na b\r\n\r\n$0\r\n\r\n*1\r\n$4\r\nPING\r\n";
+        let mut reader = Cursor::new(input.to_vec());
+
+        assert_eq!(
+            read_command(&mut reader, usize::MAX).unwrap(),
+            Some(vec![b"SET".to_vec(), b"a b\r\n".to_vec(), Vec::new()])
+        );
+        assert_eq!(
+            read_command(&mut reader, usize::MAX).unwrap(),
+            Some(vec![b"PING".to_vec()])
+        );
+        assert_eq!(read_command(&mut reader, usize::MAX).unwrap(), None);
+
+        let ping = b"*1\r\n$4\r\nPING\r\n".to_vec();
+        assert!(read_command(&mut Cursor::new(ping), 3).is_err());
+
+        for bad in [&b"*1\r\n$4\r\nPING"[..], b"*1\r\n$x\r\n", b"GET a\r\n"] {
+            assert!(read_command(&mut Cursor::new(bad.to_vec()), usize::MAX).is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let value = Value::Map(vec![(Value::Bulk("a".to_string()), Value::Null)]);
+
+        let mut out = Vec::new();
+        write_value(&mut out, &value, 2).unwrap();
+        assert_eq!(out, b"*2\r\n$1\r\na\r\n$-1\r\n");
+
+        let mut out = Vec::new();
+        write_value(&mut out, &value, 3).unwrap();
+        assert_eq!(out, b"%1\r\n$1\r\na\r\n_\r\n");
+
+        // Text echoed from the client cannot start a new reply
+        let mut out = Vec::new();
+        let value = Value::Error("ERR unknown command 'a\r\n+OK'".to_string());
+        write_value(&mut out, &value, 2).unwrap();
+        assert_eq!(out, b"-ERR unknown command 'a  +OK'\r\n");
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches(b"*", b""));
+        assert!(glob_matches(b"user:*:name", b"user:42:name"));
+        assert!(glob_matches(b"h?llo", b"hello"));
+        assert!(glob_matches(b"a\\*", b"a*"));
+        assert!(!glob_matches(b"a\\*", b"ab"));
+        assert!(!glob_matches(b"h?llo", b"hllo"));
+        assert!(glob_matches(b"*a*b", b"xaayb"));
+        assert!(!glob_matches(b"*a*b", b"xaaybc"));
+        assert!(glob_matches(b"a\\", b"a\\"));
+
+        // Would take ages with a backtracking match
+        let text = "a".repeat(200);
+        assert!(!glob_matches(b"*a*a*a*a*a*a*a*a*a*a*a*b", text.as_bytes()));
+    }
+
+    #[test]
+    fn test_redis_client() {
+        let (client, _dir) = start_server();
+        let url = format!("redis://{}/", client.addr());
+        let mut con = redis::Client::open(url.as_str())
+            .unwrap()
+            .get_connection()
+            .unwrap();
+
+        let _: () = con.set("k", "a value").unwrap();
+        assert_eq!(
+            con.get::<_, Option<String>>("k").unwrap().as_deref(),
+            Some("a value")
+        );
+        assert_eq!(con.get::<_, Option<String>>("missing").unwrap(), None);
+        assert_eq!(con.exists::<_, i64>(&["k", "missing"]).unwrap(), 1);
+
+        assert_eq!(con.incr::<_, _, i64>("n", 1).unwrap(), 1);
+        assert_eq!(con.incr::<_, _, i64>("n", 5).unwrap(), 6);
+        assert!(con.incr::<_, _, i64>("k", 1).is_err());
+
+        assert!(con.expire::<_, bool>("k", 100).unwrap());
+        assert_eq!(con.ttl::<_, i64>("k").unwrap(), 100);
+        assert_eq!(con.ttl::<_, i64>("n").unwrap(), -1);
+        assert_eq!(con.ttl::<_, i64>("missing").unwrap(), -2);
+
+        let _: () = con.set("user:1", "x").unwrap();
+        assert_eq!(
+            con.keys::<_, Vec<String>>("*").unwrap(),
+            vec!["k", "n", "user:1"]
+        );
+        let scanned: Vec<String> = con.scan_match("user:*").unwrap().collect();
+        assert_eq!(scanned, vec!["user:1"]);
+
+        assert_eq!(con.del::<_, i64>(&["k", "missing"]).unwrap(), 1);
+        assert_eq!(
+            redis::cmd("PING").query::<String>(&mut con).unwrap(),
+            "PONG"
+        );
+        assert!(redis::cmd("INFO")
+            .query::<String>(&mut con)
+            .unwrap()
+            .contains("key_count: 2"));
+        assert_eq!(
+            redis::cmd("COMMAND")
+                .arg("COUNT")
+                .query::<usize>(&mut con)
+                .unwrap(),
+            COMMANDS.len()
+        );
+        assert!(redis::cmd("NOPE").query::<()>(&mut con).is_err());
+
+        let (a, b): (i64, i64) = redis::pipe()
+            .incr("p", 1)
+            .incr("p", 2)
+            .query(&mut con)
+            .unwrap();
+        assert_eq!((a, b), (1, 3));
+
+        let url = format!("redis://{}/?protocol=resp3", client.addr());
+        let mut con = redis::Client::open(url.as_str())
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        assert_eq!(con.get::<_, Option<String>>("missing").unwrap(), None);
+        assert_eq!(con.get::<_, String>("user:1").unwrap(), "x");
+        assert!(redis::cmd("SET")
+            .arg("user:1")
+            .arg("y")
+            .arg("NX")
+            .query::<Option<String>>(&mut con)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_redis_auth() {
+        let (client, _dir) = start_server_with(ServerConfig {
+            users: test_users(),
+            ..ServerConfig::default()
+        });
+
+        let open = |url: String| redis::Client::open(url.as_str()).unwrap().get_connection();
+
+        let mut con = open(format!("redis://{}/", client.addr())).unwrap();
+        match con.get::<_, Option<String>>("k") {
+            Err(e) => assert_eq!(e.code(), Some("NOAUTH")),
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(redis::cmd("AUTH")
+            .arg("admin")
+            .arg("wrong")
+            .query::<()>(&mut con)
+            .is_err());
+        let _: () = redis::cmd("AUTH")
+            .arg("admin")
+            .arg("admin secret")
+            .query(&mut con)
+            .unwrap();
+        let _: () = con.set("report:1", "x").unwrap();
+
+        // Sent as AUTH, then as HELLO 3 AUTH
+        for protocol in ["resp2", "resp3"] {
+            let url = format!(
+                "redis://admin:admin%20secret@{}/?protocol={}",
+                client.addr(),
+                protocol
+            );
+            let mut con = open(url).unwrap();
+            assert_eq!(
+                con.get::<_, Option<String>>("report:1").unwrap().as_deref(),
+                Some("x")
+            );
+        }
+
+        let _: () = redis::cmd("AUTH")
+            .arg("reports token")
+            .query(&mut con)
+            .unwrap();
+        match con.set::<_, _, ()>("report:2", "y") {
+            Err(e) => assert_eq!(e.code(), Some("NOPERM")),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let url = format!("redis://admin:wrong@{}/?protocol=resp3", client.addr());
+        assert!(open(url).is_err());
+    }
+}
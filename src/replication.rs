@@ -0,0 +1,607 @@
+//! Primary-replica replication.
+//!
+//! A replica sends `sync` to its primary and receives a snapshot of the primary's data
+//! files, followed by every write committed after the snapshot as one line of JSON per
+//! event. The replica answers with `ack <seq>` after applying each event, which the
+//! primary uses to track the offset of every replica.
+//!
+//! A replica that loses its primary reconnects with `sync <seq>`, the last sequence
+//! number it applied. The primary then only sends the writes after it, unless
+//! compaction may have dropped some of them, in which case it sends a new snapshot.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::core::{
+    changes::{ChangeEvent, Subscription},
+    config::Config,
+    crypto::Keyring,
+    error::{KiviError, Result},
+    kv::{self, KiviStore},
+    meta::StoreMeta,
+    record,
+};
+use crate::server::{lock_engine, Engine};
+
+/// Last sequence number acknowledged by each connected replica.
+pub type ReplicaOffsets = Arc<Mutex<HashMap<SocketAddr, u64>>>;
+
+/// Wait before the first attempt to reconnect to a lost primary, doubled after every
+/// failed attempt up to `RECONNECT_MAX_DELAY`.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a write to a replica may block. A replica that stops reading is dropped,
+/// and resumes where it was once it reconnects.
+const REPLICA_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Directory under the data directory a replica downloads a snapshot to, before
+/// swapping it in for its data files.
+const SNAPSHOT_STAGING_DIR: &str = "snapshot";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotHeader {
+    last_seq: u64,
+    files: Vec<SnapshotFile>,
+
+    /// Set when no files follow, only the writes after the sequence number the
+    /// replica sent
+    #[serde(default)]
+    resumed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SnapshotFile {
+    name: String,
+    size: u64,
+}
+
+/// Sealed data files of the primary, opened while the engine was locked. They stay
+/// readable once it is released, even if compaction removes them meanwhile.
+struct Snapshot {
+    last_seq: u64,
+    files: Vec<(SnapshotFile, File)>,
+    keyring: Keyring,
+}
+
+impl Snapshot {
+    /// Writes with a sequence number above `seq`, in order.
+    fn events_after(self, seq: u64) -> Result<Vec<ChangeEvent>> {
+        let mut events = Vec::new();
+
+        for (_, file) in self.files {
+            let mut reader = BufReader::new(file);
+            record::read_file_header(&mut reader)?;
+
+            while let Some(buf) = record::read_next(&mut reader)? {
+                if record::sequence(&buf) > seq {
+                    events.push(ChangeEvent {
+                        seq: record::sequence(&buf),
+                        command: record::decode(&buf, &self.keyring)?,
+                    });
+                }
+            }
+        }
+
+        // Compacted files are ordered by key, not by sequence number
+        events.sort_by_key(|event| event.seq);
+
+        Ok(events)
+    }
+}
+
+/// Primary side of `sync`. `after` is the last sequence number applied by a replica
+/// that reconnects. The active file is sealed and the data files are opened with the
+/// engine locked, so no write can slip in between the snapshot and the subscription
+/// that follows it. They are only read once the lock is released.
+pub(crate) fn serve_replica(
+    stream: &TcpStream,
+    engine: &Engine,
+    offsets: ReplicaOffsets,
+    after: Option<u64>,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+
+    let (snapshot, subscription, resume_after) = {
+        let mut engine = lock_engine(engine)?;
+        engine.seal_active_file()?;
+
+        let mut files = Vec::new();
+        for path in engine.sealed_files() {
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+
+            files.push((
+                SnapshotFile {
+                    name: file_name(path)?,
+                    size,
+                },
+                file,
+            ));
+        }
+
+        // Compaction may have dropped writes up to the last sequence number it saw
+        let resume_after =
+            after.filter(|seq| *seq >= engine.compacted_seq() && *seq <= engine.last_seq());
+
+        let snapshot = Snapshot {
+            last_seq: engine.last_seq(),
+            files,
+            keyring: engine.get_config().get_keyring().clone(),
+        };
+
+        (snapshot, engine.subscribe_all(), resume_after)
+    };
+
+    match resume_after {
+        Some(seq) => log::info!("Replica {} resuming after sequence number {}", peer, seq),
+        None => log::info!(
+            "Replica {} syncing, snapshot at sequence number {}",
+            peer,
+            snapshot.last_seq
+        ),
+    }
+
+    lock_offsets(&offsets)?.insert(peer, resume_after.unwrap_or(0));
+
+    let out = stream.try_clone()?;
+    out.set_write_timeout(Some(REPLICA_WRITE_TIMEOUT))?;
+    std::thread::spawn(move || {
+        if let Err(e) = send_snapshot_and_tail(out, snapshot, resume_after, subscription) {
+            log::info!("Replica {} stopped: {}", peer, e);
+        }
+    });
+
+    let input = stream.try_clone()?;
+    std::thread::spawn(move || {
+        for line in BufReader::new(input).lines() {
+            let Ok(line) = line else { break };
+
+            let seq = line
+                .strip_prefix("ack ")
+                .and_then(|s| s.trim().parse::<u64>().ok());
+
+            match (seq, lock_offsets(&offsets)) {
+                (Some(seq), Ok(mut o)) => {
+                    o.insert(peer, seq);
+                }
+                _ => log::error!("Invalid message from replica {}: {}", peer, line),
+            }
+        }
+
+        if let Ok(mut o) = lock_offsets(&offsets) {
+            o.remove(&peer);
+        }
+    });
+
+    Ok(())
+}
+
+/// Sends the snapshot, or only the writes after `resume_after`, then every new write.
+/// The connection is closed once the store goes away, which tells the replica to
+/// reconnect.
+fn send_snapshot_and_tail(
+    mut out: TcpStream,
+    snapshot: Snapshot,
+    resume_after: Option<u64>,
+    subscription: Subscription,
+) -> Result<()> {
+    let res = write_snapshot_and_tail(&mut out, snapshot, resume_after, subscription);
+
+    // The thread reading acks holds a clone of the socket
+    let _ = out.shutdown(Shutdown::Both);
+
+    res
+}
+
+fn write_snapshot_and_tail(
+    out: &mut TcpStream,
+    snapshot: Snapshot,
+    resume_after: Option<u64>,
+    subscription: Subscription,
+) -> Result<()> {
+    let header = SnapshotHeader {
+        last_seq: snapshot.last_seq,
+        files: match resume_after {
+            Some(_) => Vec::new(),
+            None => snapshot.files.iter().map(|(f, _)| f.clone()).collect(),
+        },
+        resumed: resume_after.is_some(),
+    };
+    writeln!(out, "{}", serde_json::to_string(&header)?)?;
+
+    match resume_after {
+        Some(seq) => {
+            for event in snapshot.events_after(seq)? {
+                writeln!(out, "{}", serde_json::to_string(&event)?)?;
+            }
+        }
+        None => {
+            for (file, handle) in snapshot.files {
+                if std::io::copy(&mut handle.take(file.size), out)? != file.size {
+                    return Err(KiviError::Generic(format!(
+                        "data file {} is shorter than {} bytes",
+                        file.name, file.size
+                    )));
+                }
+            }
+        }
+    }
+
+    for event in subscription {
+        writeln!(out, "{}", serde_json::to_string(&event)?)?;
+    }
+
+    Ok(())
+}
+
+/// Connection to the primary, positioned right after the snapshot.
+pub struct PrimaryLink {
+    primary: String,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// Replica side of `sync`. Replaces the data files in the directories of `config`
+/// with a snapshot of the primary and opens a store on them. Encrypted primaries need
+/// the same keys in `config`.
+pub fn sync_from_primary(primary: &str, config: Config) -> Result<(KiviStore, PrimaryLink)> {
+    let (header, mut reader, mut writer) = request_sync(primary, None)?;
+
+    let staging = download_snapshot(&header, &mut reader, &config)?;
+    let store = install_snapshot(&header, &staging, config)?;
+    writeln!(writer, "ack {}", store.last_seq())?;
+
+    log::info!("Synced snapshot at sequence number {}", header.last_seq);
+
+    Ok((
+        store,
+        PrimaryLink {
+            primary: primary.to_string(),
+            reader,
+            writer,
+        },
+    ))
+}
+
+/// Sends `sync`, with the last applied sequence number when resuming, and reads the
+/// header of the answer.
+fn request_sync(
+    primary: &str,
+    after: Option<u64>,
+) -> Result<(SnapshotHeader, BufReader<TcpStream>, TcpStream)> {
+    let mut writer = TcpStream::connect(primary)?;
+    match after {
+        Some(seq) => write!(writer, "sync {}", seq)?,
+        None => writer.write_all(b"sync")?,
+    }
+
+    let mut reader = BufReader::new(writer.try_clone()?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header = serde_json::from_str(&line)?;
+
+    Ok((header, reader, writer))
+}
+
+/// Writes the snapshot files that follow the header to a staging directory under the
+/// data directory, in place of what an earlier attempt left there. Returns the
+/// directory.
+fn download_snapshot<R: Read>(
+    header: &SnapshotHeader,
+    reader: &mut R,
+    config: &Config,
+) -> Result<PathBuf> {
+    let staging = Path::new(&config.get_full_path()).join(SNAPSHOT_STAGING_DIR);
+    match std::fs::remove_dir_all(&staging) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::fs::create_dir_all(&staging)?;
+
+    for file in &header.files {
+        // Never write outside of the staging directory
+        if Path::new(&file.name).file_name().and_then(|n| n.to_str()) != Some(&file.name) {
+            return Err(KiviError::Generic(format!(
+                "invalid snapshot file name: {}",
+                file.name
+            )));
+        }
+
+        let mut out = File::create(staging.join(&file.name))?;
+        if std::io::copy(&mut reader.take(file.size), &mut out)? != file.size {
+            return Err(KiviError::Generic(format!(
+                "snapshot file {} is shorter than {} bytes",
+                file.name, file.size
+            )));
+        }
+    }
+
+    Ok(staging)
+}
+
+/// Replaces the data files in the directories of `config` with the snapshot files
+/// downloaded to `staging`, then opens a store on them.
+fn install_snapshot(header: &SnapshotHeader, staging: &Path, config: Config) -> Result<KiviStore> {
+    kv::remove_data_files(&config)?;
+
+    for file in &header.files {
+        std::fs::rename(
+            staging.join(&file.name),
+            Path::new(&config.get_full_path()).join(&file.name),
+        )?;
+    }
+    std::fs::remove_dir(staging)?;
+
+    StoreMeta {
+        last_seq: header.last_seq,
+        ..StoreMeta::default()
+    }
+    .save(&config)?;
+
+    KiviStore::with_config(config)
+}
+
+impl PrimaryLink {
+    /// Applies the writes streamed by the primary on its own thread. When the
+    /// connection is lost, reconnects with a growing delay and resumes after the last
+    /// applied write.
+    pub fn follow(mut self, engine: Engine) {
+        std::thread::spawn(move || loop {
+            if let Err(e) = self.apply_events(&engine) {
+                log::warn!("Lost the primary {}: {}", self.primary, e);
+            }
+
+            self.reconnect(&engine);
+        });
+    }
+
+    fn apply_events(&mut self, engine: &Engine) -> Result<()> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(KiviError::Generic(
+                    "primary closed the connection".to_string(),
+                ));
+            }
+
+            let event: ChangeEvent = serde_json::from_str(&line)?;
+            let seq = event.seq;
+
+            lock_engine(engine)?.apply(event)?;
+
+            writeln!(self.writer, "ack {}", seq)?;
+        }
+    }
+
+    /// Tries again until the primary answers.
+    fn reconnect(&mut self, engine: &Engine) {
+        let mut delay = RECONNECT_MIN_DELAY;
+
+        loop {
+            std::thread::sleep(delay);
+
+            match self.resync(engine) {
+                Ok(()) => return,
+                Err(e) => {
+                    log::warn!("Could not reconnect to the primary {}: {}", self.primary, e);
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+            }
+        }
+    }
+
+    fn resync(&mut self, engine: &Engine) -> Result<()> {
+        let (last_seq, config) = {
+            let engine = lock_engine(engine)?;
+            (engine.last_seq(), engine.get_config().clone())
+        };
+        let (header, mut reader, mut writer) = request_sync(&self.primary, Some(last_seq))?;
+
+        if header.resumed {
+            log::info!("Resumed replication after sequence number {}", last_seq);
+        } else {
+            // Reads only wait while the downloaded files are swapped in
+            let staging = download_snapshot(&header, &mut reader, &config)?;
+            *lock_engine(engine)? = install_snapshot(&header, &staging, config)?;
+
+            log::info!("Synced snapshot at sequence number {}", header.last_seq);
+        }
+
+        writeln!(writer, "ack {}", lock_engine(engine)?.last_seq())?;
+        self.reader = reader;
+        self.writer = writer;
+
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or_else(|| KiviError::Generic(format!("invalid data file: {}", path.display())))
+}
+
+pub(crate) fn lock_offsets(
+    offsets: &ReplicaOffsets,
+) -> Result<std::sync::MutexGuard<'_, HashMap<SocketAddr, u64>>> {
+    offsets
+        .lock()
+        .map_err(|_| KiviError::Generic("replica offsets lock poisoned".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{KiviServer, ServerHandle};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use std::time::Instant;
+    use tempdir::TempDir;
+
+    fn request(addr: SocketAddr, command: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(command.as_bytes()).unwrap();
+
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).unwrap();
+
+        buf
+    }
+
+    fn start(mut server: KiviServer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || server.run_with_listener(listener));
+
+        addr
+    }
+
+    /// Serves on a clone of `listener`, so the address stays bound across restarts.
+    fn start_on(listener: &TcpListener, store: KiviStore) -> (ServerHandle, JoinHandle<()>) {
+        let listener = listener.try_clone().unwrap();
+        let mut server = KiviServer::with_store(store);
+        let handle = server.server_handle();
+
+        let thread = std::thread::spawn(move || server.run_with_listener(listener).unwrap());
+
+        (handle, thread)
+    }
+
+    /// Stops the server and waits until its store is closed.
+    fn stop((handle, thread): (ServerHandle, JoinHandle<()>)) {
+        handle.shutdown();
+        thread.join().unwrap();
+    }
+
+    fn wait_for(addr: SocketAddr, command: &str, done: impl Fn(&str) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !done(&request(addr, command)) {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting on {}",
+                command
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_replica_follows_primary() {
+        let primary_dir = TempDir::new("primary").unwrap();
+        let replica_dir = TempDir::new("replica").unwrap();
+
+        let mut store = KiviStore::with_config(
+            Config::new()
+                .set_db_path(primary_dir.path().to_path_buf())
+                .build(),
+        )
+        .unwrap();
+        store.set("a".to_string(), "snapshot".to_string()).unwrap();
+
+        let primary = start(KiviServer::with_store(store));
+
+        let replica = start(
+            KiviServer::replica(
+                &primary.to_string(),
+                Config::new()
+                    .set_db_path(replica_dir.path().to_path_buf())
+                    .build(),
+            )
+            .unwrap(),
+        );
+
+        // Data from the snapshot
+        assert_eq!(request(replica, "get a"), "Key: a, Value: snapshot");
+
+        // Data written after the snapshot
+        assert_eq!(request(primary, "incr counter"), "Value: 1");
+        wait_for(replica, "get counter", |r| r == "Key: counter, Value: 1");
+
+        // The primary knows how far the replica got
+        wait_for(primary, "role", |r| {
+            r.starts_with("primary, last seq 2") && r.ends_with(" at 2")
+        });
+
+        // Replicas are read only
+        assert!(request(replica, "incr counter").starts_with("Error: read-only replica"));
+        assert!(request(replica, "role").starts_with("replica of"));
+    }
+
+    #[test]
+    fn test_replica_survives_primary_restart() {
+        let primary_dir = TempDir::new("primary").unwrap();
+        let replica_dir = TempDir::new("replica").unwrap();
+        let config = || {
+            Config::new()
+                .set_db_path(primary_dir.path().to_path_buf())
+                .build()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary = listener.local_addr().unwrap();
+        let server = start_on(&listener, KiviStore::with_config(config()).unwrap());
+
+        let replica = start(
+            KiviServer::replica(
+                &primary.to_string(),
+                Config::new()
+                    .set_db_path(replica_dir.path().to_path_buf())
+                    .build(),
+            )
+            .unwrap(),
+        );
+
+        request(primary, "set a 1");
+        assert_eq!(request(primary, "incr counter"), "Value: 1");
+        wait_for(replica, "get counter", |r| r == "Key: counter, Value: 1");
+
+        // Compacted before the replica fell behind, so it can resume
+        assert_eq!(request(primary, "compact"), "OK");
+        stop(server);
+
+        let mut store = KiviStore::with_config(config()).unwrap();
+        store.incr("counter".to_string()).unwrap();
+        let server = start_on(&listener, store);
+
+        wait_for(replica, "get counter", |r| r == "Key: counter, Value: 2");
+        assert_eq!(request(primary, "incr counter"), "Value: 3");
+        wait_for(replica, "get counter", |r| r == "Key: counter, Value: 3");
+
+        // Writes the replica has not seen are compacted away, a new snapshot is needed
+        stop(server);
+
+        let mut store = KiviStore::with_config(config()).unwrap();
+        store.delete("a".to_string()).unwrap();
+        store.incr("counter".to_string()).unwrap();
+        store.compact().unwrap();
+        let _server = start_on(&listener, store);
+
+        wait_for(replica, "get counter", |r| r == "Key: counter, Value: 4");
+        assert!(request(replica, "get a").contains("not found"));
+
+        // The downloaded files were moved into the data directory
+        let replica_config = Config::new()
+            .set_db_path(replica_dir.path().to_path_buf())
+            .build();
+        assert!(!Path::new(&replica_config.get_full_path())
+            .join(SNAPSHOT_STAGING_DIR)
+            .exists());
+        wait_for(primary, "role", |r| {
+            r.starts_with("primary, last seq 6") && r.ends_with(" at 6")
+        });
+    }
+}
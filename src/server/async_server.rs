@@ -0,0 +1,387 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::Shutdown;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinSet;
+use tokio_util::codec::{Framed, FramedParts};
+
+use super::{error_message, log_served, KiviServer, Login, Reply, SUBSCRIBER_WRITE_TIMEOUT};
+use crate::core::{
+    changes::Subscription,
+    error::{KiviError, Result},
+};
+use crate::protocol::{
+    codec,
+    framed::{self, ServerCodec},
+    Request, Response,
+};
+use crate::stream::Stream;
+
+/// Serves connections as tasks of a tokio runtime instead of worker threads, so idle
+/// connections cost no thread. Requests run on the blocking pool of the runtime, since
+/// the store does file IO while holding its lock.
+///
+/// Binary clients are served by the runtime. Text and Redis connections, replicas
+/// syncing included, are handed to a blocking thread running the synchronous server.
+#[derive(Clone)]
+pub struct AsyncKiviServer {
+    server: KiviServer,
+}
+
+impl AsyncKiviServer {
+    /// Serves the engine, role and limits of `server`. Its number of workers is not
+    /// used.
+    pub fn new(server: KiviServer) -> Self {
+        Self { server }
+    }
+
+    pub async fn run<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+
+        self.run_with_listener(listener).await
+    }
+
+    /// Serves connections until `ServerHandle::shutdown` is called on a handle of the
+    /// wrapped server.
+    pub async fn run_with_listener(&self, listener: TcpListener) -> Result<()> {
+        let shared = &self.server.shared;
+        let mut connections = JoinSet::new();
+
+        if shared.listening_on(listener.local_addr()?) {
+            loop {
+                let accepted = listener.accept().await;
+                if shared.is_stopping() {
+                    break;
+                }
+
+                match accepted {
+                    Ok((stream, _)) => {
+                        let server = self.server.clone();
+
+                        connections.spawn(async move { log_served(serve(server, stream).await) });
+                    }
+                    Err(e) => {
+                        log::error!("Error: {}", e);
+                    }
+                }
+
+                // Forget the connections that are done
+                while connections.try_join_next().is_some() {}
+            }
+        }
+
+        drop(listener);
+
+        let timeout = self.server.config().shutdown_timeout;
+        log::info!("Shutting down, waiting up to {:?} for connections", timeout);
+
+        // Only reaches the connections served by blocking threads, binary clients are
+        // cancelled at the deadline
+        shared.close_connections(Shutdown::Read);
+
+        let drain = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(timeout, drain).await.is_err() {
+            log::warn!("Closing the connections still open after {:?}", timeout);
+            shared.close_connections(Shutdown::Both);
+            connections.shutdown().await;
+        }
+
+        let server = self.server.clone();
+        blocking(move || server.close()).await?
+    }
+}
+
+async fn serve(server: KiviServer, mut stream: TcpStream) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let idle_timeout = server.idle_timeout();
+
+    // TLS connections are served by blocking threads, handshake included
+    #[cfg(feature = "tls")]
+    if server.tls.is_some() {
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+
+        return blocking(move || match server.shared.register(&stream) {
+            Some(id) => {
+                let res = server.serve(stream);
+                server.shared.unregister(id);
+                res
+            }
+            None => Ok(()),
+        })
+        .await?;
+    }
+
+    // Read until the whole greeting of a binary client is there, or it is not one
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while head.len() <= codec::MAGIC.len()
+        && codec::MAGIC.starts_with(&head[..head.len().min(codec::MAGIC.len())])
+    {
+        let bytes_read = idle(idle_timeout, stream.read(&mut buf)).await??;
+        if bytes_read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..bytes_read]);
+    }
+
+    if !head.starts_with(codec::MAGIC) || head.len() <= codec::MAGIC.len() {
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(idle_timeout)?;
+
+        return blocking(move || match server.shared.register(&stream) {
+            Some(id) => {
+                let res = server.serve_from(head, Stream::Plain(stream));
+                server.shared.unregister(id);
+                res
+            }
+            None => Ok(()),
+        })
+        .await?;
+    }
+
+    let greeting: [u8; 5] = head[..5].try_into().unwrap();
+    framed::accept_handshake(&greeting, &mut stream).await?;
+
+    // Requests may have been sent right after the handshake
+    let mut parts = FramedParts::new(stream, ServerCodec::new(server.config().max_request_size));
+    parts.read_buf.extend_from_slice(&head[5..]);
+    let mut connection = Framed::from_parts(parts);
+    let mut login = Login::default();
+
+    loop {
+        let request = match idle(idle_timeout, connection.next()).await? {
+            Some(Ok(request)) => request,
+            None => return Ok(()),
+            Some(Err(e @ KiviError::Protocol(_))) => {
+                connection.send(Response::Error(error_message(&e))).await?;
+                return Err(e);
+            }
+            Some(Err(e)) => return Err(e),
+        };
+
+        match handle(&server, &mut login, request).await? {
+            Reply::Response(response) => connection.feed(response).await?,
+            Reply::Events(subscription) => {
+                connection.flush().await?;
+                return stream_events(connection, subscription).await;
+            }
+        }
+
+        // Responses to pipelined requests go out together
+        if connection.read_buffer().is_empty() {
+            connection.flush().await?;
+        }
+    }
+}
+
+async fn handle(server: &KiviServer, login: &mut Login, request: Request) -> Result<Reply> {
+    let server = server.clone();
+    let mut moved = std::mem::take(login);
+
+    let (reply, moved) = blocking(move || (server.handle_as(&mut moved, request), moved)).await?;
+    *login = moved;
+
+    Ok(reply)
+}
+
+/// Runs `f` on the blocking pool, out of the way of the reactor.
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| KiviError::Generic(format!("request failed: {}", e)))
+}
+
+/// Waits for `f`, failing like a socket read timeout once `limit` passed.
+async fn idle<F: Future>(limit: Option<Duration>, f: F) -> Result<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, f)
+            .await
+            .map_err(|_| KiviError::Io(ErrorKind::TimedOut.into())),
+        None => Ok(f.await),
+    }
+}
+
+/// Writes every event until the client disconnects. Subscriptions block while waiting,
+/// so they are read on a thread of their own.
+async fn stream_events(
+    mut connection: Framed<TcpStream, ServerCodec>,
+    subscription: Subscription,
+) -> Result<()> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(64);
+
+    std::thread::spawn(move || {
+        for event in subscription {
+            if sender.blocking_send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    // A subscriber that stops reading is dropped instead of holding its events
+    while let Some(event) = receiver.recv().await {
+        let sent = idle(
+            Some(SUBSCRIBER_WRITE_TIMEOUT),
+            connection.send(Response::Event(event)),
+        )
+        .await;
+
+        if let Err(e) = sent.and_then(|res| res) {
+            log::info!("Subscriber disconnected: {}", e);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{AsyncKiviClient, KiviClient};
+    use crate::core::{config::Config, kv::KiviStore};
+    use crate::server::ServerConfig;
+    use tempdir::TempDir;
+
+    async fn start_server(config: ServerConfig) -> (String, TempDir) {
+        let dir = TempDir::new("async_server").unwrap();
+        let store =
+            KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build())
+                .unwrap();
+
+        let mut server = KiviServer::with_store(store);
+        server.set_config(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = AsyncKiviServer::new(server);
+        tokio::spawn(async move { server.run_with_listener(listener).await });
+
+        (addr, dir)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_server() {
+        let (addr, _dir) = start_server(ServerConfig::default()).await;
+
+        // Many more connections than the default number of workers
+        let mut tasks = Vec::new();
+        for t in 0..100 {
+            let addr = addr.clone();
+
+            tasks.push(tokio::spawn(async move {
+                let mut client = AsyncKiviClient::connect(&addr).await.unwrap();
+                for i in 0..10 {
+                    client.set(&format!("t{}:{}", t, i), "x").await.unwrap();
+                }
+
+                client
+            }));
+        }
+
+        let mut clients = Vec::new();
+        for task in tasks {
+            clients.push(task.await.unwrap());
+        }
+
+        let client = &mut clients[0];
+        assert_eq!(client.scan("t").await.unwrap().len(), 1000);
+        assert_eq!(client.get("t3:4").await.unwrap().as_deref(), Some("x"));
+        client.delete("t3:4").await.unwrap();
+        assert_eq!(client.get("t3:4").await.unwrap(), None);
+        assert!(matches!(
+            client.delete("t3:4").await,
+            Err(KiviError::KeyNotFound(_))
+        ));
+
+        // Text commands and blocking clients are still served
+        let sync_client = KiviClient::new(&addr);
+        let res = tokio::task::spawn_blocking(move || {
+            (
+                sync_client.request("get t1:1").unwrap(),
+                sync_client.get("t2:2").unwrap(),
+            )
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            res,
+            ("Key: t1:1, Value: x".to_string(), Some("x".to_string()))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_limits() {
+        let (addr, _dir) = start_server(ServerConfig {
+            idle_timeout: Duration::from_millis(100),
+            max_request_size: 1024,
+            ..ServerConfig::default()
+        })
+        .await;
+
+        let mut client = AsyncKiviClient::connect(&addr).await.unwrap();
+        let requests = vec![
+            Request::IncrBy {
+                key: "n".to_string(),
+                delta: 2,
+            },
+            Request::Get {
+                key: "n".to_string(),
+            },
+        ];
+        assert_eq!(
+            client.pipeline(&requests).await.unwrap(),
+            vec![Response::Integer(2), Response::Value("2".to_string())]
+        );
+
+        match client.set("big", &"x".repeat(2048)).await {
+            Err(KiviError::Generic(message)) => assert!(message.contains("over the limit")),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let mut idle_client = AsyncKiviClient::connect(&addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(idle_client.get("n").await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_shutdown() {
+        let dir = TempDir::new("async_server").unwrap();
+        let config = || Config::new().set_db_path(dir.path().to_path_buf()).build();
+
+        let server = KiviServer::with_store(KiviStore::with_config(config()).unwrap());
+        let handle = server.server_handle();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = AsyncKiviServer::new(server);
+        let running = tokio::spawn(async move { server.run_with_listener(listener).await });
+
+        let mut client = AsyncKiviClient::connect(&addr).await.unwrap();
+        client.set("a", "1").await.unwrap();
+
+        // Text connections are served by blocking threads, closed as well
+        let idle_text = std::net::TcpStream::connect(&addr).unwrap();
+
+        tokio::task::spawn_blocking(move || handle.shutdown())
+            .await
+            .unwrap();
+
+        // Binary connections are only cancelled at the deadline, this one ends first
+        drop(client);
+        running.await.unwrap().unwrap();
+        drop(idle_text);
+
+        let store = KiviStore::with_config(config()).unwrap();
+        assert_eq!(store.get("a".to_string()).unwrap().value, "1");
+    }
+}
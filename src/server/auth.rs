@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::core::error::{self, KiviError};
+use crate::protocol::{canonical_command, Request, Response, AUTH_REQUIRED};
+
+/// A user of the server, from a `[[users]]` table of the settings file. Secrets are
+/// kept as the hex SHA-256 of their value, like `printf %s secret | sha256sum` prints.
+///
+/// ```toml
+/// [[users]]
+/// name = "reports"
+/// password_sha256 = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+/// read_only = true
+/// key_prefixes = ["report:"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+
+    /// Logs in with `AUTH name password`
+    pub password_sha256: Option<String>,
+
+    /// Logs in with `AUTH token`, without a name
+    pub token_sha256: Option<String>,
+
+    /// Refuses every write
+    pub read_only: bool,
+
+    /// Commands of the text protocol the user cannot run, like `compact` or `backup`.
+    /// Denying an alias denies the whole command: `incr` denies `decr` and `incrby`.
+    pub denied_commands: Vec<String>,
+
+    /// Keys the user can read and write, all of them when empty. Scans only return
+    /// the keys under these prefixes, commands about the whole store are refused.
+    pub key_prefixes: Vec<String>,
+}
+
+impl User {
+    /// Replaces the aliases of `denied_commands` by the command they name, fails on
+    /// unknown commands so a typo does not leave them allowed.
+    pub fn normalize(&mut self) -> error::Result<()> {
+        for command in &mut self.denied_commands {
+            match canonical_command(command) {
+                Some(name) => *command = name.to_string(),
+                None => {
+                    return Err(KiviError::Generic(format!(
+                        "unknown command {} in denied_commands of user {}",
+                        command, self.name
+                    )))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the user may run the request, or returns why not.
+    pub fn authorize(&self, request: &Request) -> Result<(), String> {
+        let command = request.name();
+
+        if self
+            .denied_commands
+            .iter()
+            .any(|c| canonical_command(c) == Some(command))
+        {
+            return Err(format!("user {} cannot run {}", self.name, command));
+        }
+
+        // Compaction and backups write files, even if no key changes
+        let writes_files = matches!(request, Request::Compact | Request::Backup { .. });
+        if self.read_only && (request.is_write() || writes_files) {
+            return Err(format!("user {} is read-only", self.name));
+        }
+
+        let allowed = match request {
+            Request::Scan { .. } => true,
+            Request::Watch { pattern } => self.can_access(pattern.trim_end_matches('*')),
+
+            // Stream the writes to every key, or read or rewrite the whole store
+            Request::Subscribe { .. }
+            | Request::Backup { .. }
+            | Request::Compact
+            | Request::Info => self.key_prefixes.is_empty(),
+            request => match request.key() {
+                Some(key) => self.can_access(key),
+                None => true,
+            },
+        };
+
+        match allowed {
+            true => Ok(()),
+            false => Err(format!("user {} cannot access this key", self.name)),
+        }
+    }
+
+    pub fn can_access(&self, key: &str) -> bool {
+        self.key_prefixes.is_empty() || self.key_prefixes.iter().any(|p| key.starts_with(p))
+    }
+
+    fn matches(&self, name: Option<&str>, secret: &str) -> bool {
+        let expected = match name {
+            Some(name) if name == self.name => &self.password_sha256,
+            Some(_) => return false,
+            None => &self.token_sha256,
+        };
+
+        match expected {
+            Some(expected) => same_digest(&hex::encode(Sha256::digest(secret)), expected),
+            None => false,
+        }
+    }
+}
+
+/// Who a connection is logged in as. Only the name is kept, so reloaded settings
+/// apply to connections already open.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Login {
+    user: Option<String>,
+}
+
+impl Login {
+    /// Answers an AUTH request. A failed attempt keeps the previous login.
+    pub(super) fn authenticate(
+        &mut self,
+        users: &[User],
+        name: Option<&str>,
+        secret: &str,
+    ) -> Response {
+        if users.is_empty() {
+            return Response::Error("authentication is not enabled".to_string());
+        }
+
+        match users.iter().find(|u| u.matches(name, secret)) {
+            Some(user) => {
+                self.user = Some(user.name.clone());
+
+                Response::Ok
+            }
+            None => Response::Unauthorized("invalid user name, password or token".to_string()),
+        }
+    }
+
+    /// The user running the request, None when no users are configured and everyone
+    /// may run anything. Fails with the response to send back otherwise.
+    pub(super) fn authorize<'a>(
+        &self,
+        users: &'a [User],
+        request: &Request,
+    ) -> Result<Option<&'a User>, Response> {
+        if users.is_empty() {
+            return Ok(None);
+        }
+
+        let user = self
+            .user
+            .as_ref()
+            .and_then(|name| users.iter().find(|u| &u.name == name))
+            .ok_or_else(|| Response::Unauthorized(AUTH_REQUIRED.to_string()))?;
+
+        user.authorize(request).map_err(Response::Unauthorized)?;
+
+        Ok(Some(user))
+    }
+}
+
+/// Compares hex digests in constant time, case insensitively.
+fn same_digest(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| {
+            diff | (x.to_ascii_lowercase() ^ y.to_ascii_lowercase())
+        }) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(secret: &str) -> Option<String> {
+        Some(hex::encode(Sha256::digest(secret)))
+    }
+
+    fn users() -> Vec<User> {
+        vec![
+            User {
+                name: "admin".to_string(),
+                password_sha256: sha256("admin secret"),
+                ..Default::default()
+            },
+            User {
+                name: "reports".to_string(),
+                token_sha256: sha256("token"),
+                read_only: true,
+                denied_commands: vec!["COMPACT".to_string()],
+                key_prefixes: vec!["report:".to_string()],
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn get(key: &str) -> Request {
+        Request::Get {
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let users = users();
+        let mut login = Login::default();
+
+        assert_eq!(
+            login.authorize(&users, &get("a")),
+            Err(Response::Unauthorized(AUTH_REQUIRED.to_string()))
+        );
+
+        for (name, secret) in [
+            (Some("admin"), "wrong"),
+            (Some("reports"), "token"),
+            (None, "admin secret"),
+            (Some("nobody"), "admin secret"),
+        ] {
+            assert!(matches!(
+                login.authenticate(&users, name, secret),
+                Response::Unauthorized(_)
+            ));
+        }
+
+        assert_eq!(
+            login.authenticate(&users, Some("admin"), "admin secret"),
+            Response::Ok
+        );
+        assert_eq!(
+            login.authorize(&users, &Request::Compact),
+            Ok(Some(&users[0]))
+        );
+
+        // A failed attempt keeps the current user
+        login.authenticate(&users, None, "wrong");
+        assert_eq!(login.authorize(&users, &get("a")), Ok(Some(&users[0])));
+
+        // Everything is allowed without users
+        assert_eq!(Login::default().authorize(&[], &get("a")), Ok(None));
+        assert!(matches!(
+            Login::default().authenticate(&[], None, "token"),
+            Response::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_denied_aliases() {
+        let mut user = User {
+            name: "counter".to_string(),
+            denied_commands: vec!["incr".to_string(), "DEL".to_string()],
+            ..Default::default()
+        };
+        user.normalize().unwrap();
+        assert_eq!(user.denied_commands, vec!["incrby", "delete"]);
+
+        for command in ["incr a", "decr a", "incrby a 2", "del a", "delete a"] {
+            let words: Vec<String> = command.split(' ').map(|w| w.to_string()).collect();
+            assert!(user.authorize(&Request::parse(&words).unwrap()).is_err());
+        }
+        assert!(user.authorize(&get("a")).is_ok());
+
+        user.denied_commands = vec!["compcat".to_string()];
+        assert!(user.normalize().is_err());
+    }
+
+    #[test]
+    fn test_whole_store_commands() {
+        let backup = Request::Backup {
+            dir: "b".to_string(),
+        };
+
+        let restricted = User {
+            name: "restricted".to_string(),
+            key_prefixes: vec!["report:".to_string()],
+            ..Default::default()
+        };
+        for request in [&backup, &Request::Compact, &Request::Info] {
+            assert!(restricted.authorize(request).is_err());
+        }
+
+        let read_only = User {
+            name: "read_only".to_string(),
+            read_only: true,
+            ..Default::default()
+        };
+        assert!(read_only.authorize(&backup).is_err());
+        assert!(read_only.authorize(&Request::Compact).is_err());
+        assert!(read_only.authorize(&Request::Info).is_ok());
+    }
+
+    #[test]
+    fn test_acl() {
+        let users = users();
+        let reports = &users[1];
+
+        assert!(reports.authorize(&get("report:1")).is_ok());
+        assert!(reports.authorize(&get("secret")).is_err());
+        assert!(reports
+            .authorize(&Request::Set {
+                key: "report:1".to_string(),
+                value: "x".to_string()
+            })
+            .is_err());
+        assert!(reports.authorize(&Request::Compact).is_err());
+        assert!(reports.authorize(&Request::Role).is_ok());
+        assert!(reports
+            .authorize(&Request::Scan {
+                prefix: String::new()
+            })
+            .is_ok());
+        assert!(reports
+            .authorize(&Request::Watch {
+                pattern: "report:*".to_string()
+            })
+            .is_ok());
+        assert!(reports
+            .authorize(&Request::Watch {
+                pattern: "*".to_string()
+            })
+            .is_err());
+        assert!(reports
+            .authorize(&Request::Subscribe { from_seq: None })
+            .is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use super::{lock_engine, Engine, ServerConfig, ServerSettings};
+use crate::core::error::Result;
+
+/// State of a server shared by its clones, its workers and its handles.
+pub(super) struct Shared {
+    config: RwLock<ServerConfig>,
+    connections: Mutex<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    /// Set once the server is shutting down, no connection is served after that
+    stopping: bool,
+
+    /// Where the server listens, to wake the accept loop up
+    addr: Option<SocketAddr>,
+
+    next_id: u64,
+    open: HashMap<u64, TcpStream>,
+}
+
+impl Shared {
+    pub(super) fn new(config: ServerConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            connections: Mutex::new(Connections::default()),
+        }
+    }
+
+    pub(super) fn config(&self) -> ServerConfig {
+        match self.config.read() {
+            Ok(config) => config.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub(super) fn set_config(&self, config: ServerConfig) {
+        match self.config.write() {
+            Ok(mut c) => *c = config,
+            Err(poisoned) => *poisoned.into_inner() = config,
+        }
+    }
+
+    pub(super) fn is_stopping(&self) -> bool {
+        self.lock_connections().stopping
+    }
+
+    /// Remembers the address of the listener, or returns false if the server was
+    /// already asked to stop.
+    pub(super) fn listening_on(&self, addr: SocketAddr) -> bool {
+        let mut connections = self.lock_connections();
+        connections.addr = Some(addr);
+
+        !connections.stopping
+    }
+
+    /// Tracks a connection about to be served, so shutdown can close it. Returns None
+    /// once the server is stopping, the connection is then dropped unanswered.
+    pub(super) fn register(&self, stream: &TcpStream) -> Option<u64> {
+        let mut connections = self.lock_connections();
+        if connections.stopping {
+            return None;
+        }
+
+        let id = connections.next_id;
+        connections.next_id += 1;
+
+        // Without a clone it cannot be closed early, but it is still served
+        if let Ok(s) = stream.try_clone() {
+            connections.open.insert(id, s);
+        }
+
+        Some(id)
+    }
+
+    pub(super) fn unregister(&self, id: u64) {
+        self.lock_connections().open.remove(&id);
+    }
+
+    /// Shuts down the connections being served. Closing their read half lets them
+    /// answer the request they are on and end at the next read.
+    pub(super) fn close_connections(&self, how: Shutdown) {
+        for stream in self.lock_connections().open.values() {
+            let _ = stream.shutdown(how);
+        }
+    }
+
+    fn stop(&self) {
+        let addr = {
+            let mut connections = self.lock_connections();
+            connections.stopping = true;
+            connections.addr
+        };
+
+        // Wake the listener up so it notices
+        if let Some(mut addr) = addr {
+            if addr.ip().is_unspecified() {
+                addr.set_ip([127, 0, 0, 1].into());
+            }
+
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// Nothing is left inconsistent by a panicking holder, the map is only a registry.
+    fn lock_connections(&self) -> MutexGuard<'_, Connections> {
+        match self.connections.lock() {
+            Ok(c) => c,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Controls a running `KiviServer` from another thread, the server binary drives it
+/// from signals. Cloning gives another handle to the same server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    pub(super) shared: Arc<Shared>,
+    pub(super) engine: Engine,
+}
+
+impl ServerHandle {
+    /// Asks the server to stop. It stops accepting connections, lets the ones being
+    /// served finish their request for up to `ServerConfig::shutdown_timeout`, then
+    /// flushes the store to disk and returns from `run`.
+    pub fn shutdown(&self) {
+        self.shared.stop();
+    }
+
+    /// Applies the settings that can change while running: connection limits, the
+    /// shutdown timeout and the sync policy. The number of workers, the address and the
+    /// store directory only change with a restart. A connection waiting for its next
+    /// request gets the new limits from the one after.
+    pub fn reload(&self, settings: &ServerSettings) -> Result<()> {
+        let mut config = settings.server_config();
+        config.workers = self.shared.config().workers;
+        self.shared.set_config(config);
+
+        lock_engine(&self.engine)?.set_sync_policy(settings.sync_policy);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1139 @@
+#[cfg(feature = "async")]
+mod async_server;
+
+mod auth;
+mod handle;
+mod settings;
+
+#[cfg(feature = "async")]
+pub use async_server::AsyncKiviServer;
+pub use auth::User;
+pub use handle::ServerHandle;
+pub use settings::ServerSettings;
+
+use std::io::{BufReader, BufWriter, Chain, Cursor, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::str;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::cluster::{ClusterConfig, NodeRole, RaftNode};
+use crate::core::{
+    backup,
+    changes::{KeyPattern, Subscription},
+    config::Config,
+    error::{KiviError, Result},
+    kv::{KiviCommand, KiviStore, DEFAULT_BUCKET},
+};
+use crate::protocol::{codec, resp, Request, Response, AUTH_REQUIRED};
+use crate::replication::{self, ReplicaOffsets};
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::tls::ServerTls;
+use auth::Login;
+use handle::Shared;
+
+/// How often a subscription waiting for events checks its subscriber is still there.
+const SUBSCRIBER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an event write may wait on a subscriber that stopped reading.
+const SUBSCRIBER_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a worker waits for the next request of a connection before parking it.
+const LINGER: Duration = Duration::from_millis(10);
+
+/// How often the parked connections are checked for a request.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Store handle shared between the server and its background threads.
+pub type Engine = Arc<Mutex<KiviStore>>;
+
+/// What a request produced: a single response, or a stream of events that keeps the
+/// connection open.
+enum Reply {
+    Response(Response),
+    Events(Subscription),
+}
+
+#[derive(Clone)]
+enum Role {
+    /// Accepts writes and streams them to its replicas
+    Primary { replicas: ReplicaOffsets },
+
+    /// Follows a primary and only serves reads
+    Replica { primary: String },
+
+    /// Member of a Raft cluster, writes go through the leader
+    Cluster { node: RaftNode },
+}
+
+/// Connection limits of a server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// Number of requests served at the same time. A connection only holds a worker
+    /// while it sends requests, idle ones do not count.
+    pub workers: usize,
+
+    /// Connections that send nothing for this long are closed, zero disables it
+    pub idle_timeout: Duration,
+
+    /// Largest request accepted, in bytes. A bigger request gets an error and its
+    /// connection is closed.
+    pub max_request_size: usize,
+
+    /// How long a shutdown waits for connections to finish their request before
+    /// closing them
+    pub shutdown_timeout: Duration,
+
+    /// Users allowed to connect. Without any, connections need no login and may run
+    /// every request.
+    pub users: Vec<User>,
+
+    /// Directory the `backup` command writes under, the command is refused without one
+    pub backup_root: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 32,
+            idle_timeout: Duration::from_secs(300),
+            max_request_size: 16 * 1024 * 1024,
+            shutdown_timeout: Duration::from_secs(30),
+            users: Vec::new(),
+            backup_root: None,
+        }
+    }
+}
+
+/// Serves connections on a pool of worker threads sharing the engine. Connections of
+/// the binary and Redis protocols stay open for any number of requests, text commands
+/// get one each.
+#[derive(Clone)]
+pub struct KiviServer {
+    engine: Engine,
+    role: Role,
+    shared: Arc<Shared>,
+
+    /// Connections are served over TLS once set
+    #[cfg(feature = "tls")]
+    tls: Option<ServerTls>,
+}
+
+impl KiviServer {
+    pub fn new() -> Result<Self> {
+        let engine = KiviStore::new()?;
+
+        Ok(Self::with_store(engine))
+    }
+
+    pub fn with_store(engine: KiviStore) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+            role: Role::Primary {
+                replicas: ReplicaOffsets::default(),
+            },
+            shared: Arc::new(Shared::new(ServerConfig::default())),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Creates a read-only replica. The data files in the directories of `config` are
+    /// replaced by a snapshot of the primary, then new writes are followed in the
+    /// background.
+    pub fn replica(primary: &str, config: Config) -> Result<Self> {
+        let (store, link) = replication::sync_from_primary(primary, config)?;
+        let engine = Arc::new(Mutex::new(store));
+
+        link.follow(engine.clone());
+
+        Ok(Self {
+            engine,
+            role: Role::Replica {
+                primary: primary.to_string(),
+            },
+            shared: Arc::new(Shared::new(ServerConfig::default())),
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Creates a member of a Raft cluster. Nodes other than the leader redirect their
+    /// clients to it.
+    pub fn cluster(store: KiviStore, config: ClusterConfig) -> Result<Self> {
+        let engine = Arc::new(Mutex::new(store));
+        let node = RaftNode::start(config, engine.clone())?;
+
+        Ok(Self::cluster_node(engine, node))
+    }
+
+    /// Same as `cluster`, with a listener already bound to the Raft address of the node.
+    pub fn cluster_with_listener(
+        store: KiviStore,
+        config: ClusterConfig,
+        raft_listener: TcpListener,
+    ) -> Result<Self> {
+        let engine = Arc::new(Mutex::new(store));
+        let node = RaftNode::start_with_listener(config, engine.clone(), raft_listener)?;
+
+        Ok(Self::cluster_node(engine, node))
+    }
+
+    fn cluster_node(engine: Engine, node: RaftNode) -> Self {
+        Self {
+            engine,
+            role: Role::Cluster { node },
+            shared: Arc::new(Shared::new(ServerConfig::default())),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: ServerConfig) -> &mut Self {
+        self.shared.set_config(config);
+        self
+    }
+
+    /// Serves every connection over TLS, whichever protocol it speaks. Replicas cannot
+    /// sync from a TLS server.
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, tls: ServerTls) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Handle to shut the server down or reload its settings while it runs.
+    pub fn server_handle(&self) -> ServerHandle {
+        ServerHandle {
+            shared: self.shared.clone(),
+            engine: self.engine.clone(),
+        }
+    }
+
+    fn config(&self) -> ServerConfig {
+        self.shared.config()
+    }
+
+    pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+
+        self.run_with_listener(listener)
+    }
+
+    /// Serves connections until `ServerHandle::shutdown` is called.
+    pub fn run_with_listener(&mut self, listener: TcpListener) -> Result<()> {
+        let mut pool = WorkerPool::start(self, self.config().workers)?;
+
+        if self.shared.listening_on(listener.local_addr()?) {
+            for stream in listener.incoming() {
+                if self.shared.is_stopping() {
+                    break;
+                }
+
+                match stream {
+                    Ok(s) => pool.execute(s),
+                    Err(e) => {
+                        log::error!("Error: {}", e);
+                    }
+                }
+            }
+        }
+
+        drop(listener);
+
+        let timeout = self.config().shutdown_timeout;
+        log::info!("Shutting down, waiting up to {:?} for connections", timeout);
+
+        // Blocked reads see the end of the stream, responses can still be written
+        self.shared.close_connections(Shutdown::Read);
+
+        if !pool.finish(Instant::now() + timeout) {
+            log::warn!("Closing the connections still open after {:?}", timeout);
+            self.shared.close_connections(Shutdown::Both);
+        }
+        drop(pool);
+
+        self.close()
+    }
+
+    /// Flushes the store to disk and, in a cluster, asks the Raft threads to stop. Only
+    /// the request connections were drained before: the Raft threads are not joined,
+    /// and the link of a replica to its primary, the streams to replicas and the ones to
+    /// subscribers are not stopped, they end with the process.
+    fn close(&self) -> Result<()> {
+        if let Role::Cluster { node } = &self.role {
+            node.shutdown();
+        }
+
+        lock_engine(&self.engine)?.sync()?;
+        log::info!("Store flushed to disk, server stopped");
+
+        Ok(())
+    }
+
+    /// Serves a connection until it is closed.
+    #[cfg(all(feature = "async", feature = "tls"))]
+    fn serve(&self, socket: TcpStream) -> Result<()> {
+        if let Some(mut connection) = self.open(socket)? {
+            while self.serve_next(&mut connection)? {}
+        }
+
+        Ok(())
+    }
+
+    /// Serves a connection whose first bytes were already read, until it is closed.
+    #[cfg(feature = "async")]
+    fn serve_from(&self, head: Vec<u8>, stream: Stream) -> Result<()> {
+        if let Some(mut connection) = self.open_from(head, stream)? {
+            while self.serve_next(&mut connection)? {}
+        }
+
+        Ok(())
+    }
+
+    /// Reads the start of a new connection. Text commands are answered right away, the
+    /// connections of other protocols are returned to serve their requests.
+    fn open(&self, socket: TcpStream) -> Result<Option<OpenConnection>> {
+        socket.set_read_timeout(self.idle_timeout())?;
+
+        let mut stream = self.secure(socket)?;
+        let head = read_head(&mut stream)?;
+
+        self.open_from(head, stream)
+    }
+
+    /// Runs the TLS handshake when the server has TLS set.
+    fn secure(&self, socket: TcpStream) -> Result<Stream> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.accept(socket);
+        }
+
+        Ok(Stream::Plain(socket))
+    }
+
+    fn open_from(&self, head: Vec<u8>, mut stream: Stream) -> Result<Option<OpenConnection>> {
+        let binary = head.starts_with(codec::MAGIC);
+
+        // Redis clients send every command as an array
+        let redis = head.first() == Some(&b'*');
+
+        if !binary && !redis {
+            self.serve_text(&head, &mut stream)?;
+            return Ok(None);
+        }
+
+        let mut connection = OpenConnection {
+            reader: BufReader::new(Cursor::new(head).chain(stream.try_clone()?)),
+            writer: BufWriter::new(stream.try_clone()?),
+            stream,
+            login: Login::default(),
+            resp: redis.then(resp::Session::default),
+        };
+
+        if binary {
+            codec::accept_handshake(&mut connection.reader, &mut connection.writer)?;
+            connection.writer.flush()?;
+        }
+
+        Ok(Some(connection))
+    }
+
+    /// Serves the next request of a connection, waiting for it as long as the idle
+    /// timeout allows. Returns false once the connection is over.
+    fn serve_next(&self, connection: &mut OpenConnection) -> Result<bool> {
+        match connection.resp {
+            Some(_) => self.serve_resp(connection),
+            None => self.serve_binary(connection),
+        }
+    }
+
+    /// A request of the framed protocol.
+    fn serve_binary(&self, connection: &mut OpenConnection) -> Result<bool> {
+        let OpenConnection {
+            stream,
+            reader,
+            writer,
+            login,
+            ..
+        } = connection;
+
+        let request = match codec::read_request(reader, self.config().max_request_size) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(false),
+            Err(e @ KiviError::Protocol(_)) => {
+                codec::write_response(writer, &Response::Error(error_message(&e)))?;
+                writer.flush()?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        match self.handle_as(login, request) {
+            Reply::Response(response) => codec::write_response(writer, &response)?,
+            Reply::Events(subscription) => {
+                writer.flush()?;
+                stream_events(stream, subscription, true)?;
+                return Ok(false);
+            }
+        }
+
+        // Responses to pipelined requests go out together
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        Ok(true)
+    }
+
+    /// A RESP command, the connection ends when the client quits.
+    fn serve_resp(&self, connection: &mut OpenConnection) -> Result<bool> {
+        let OpenConnection {
+            reader,
+            writer,
+            login,
+            resp: Some(session),
+            ..
+        } = connection
+        else {
+            return Ok(false);
+        };
+
+        let args = match resp::read_command(reader, self.config().max_request_size) {
+            Ok(Some(args)) => args,
+            Ok(None) => {
+                writer.flush()?;
+                return Ok(false);
+            }
+            Err(e @ KiviError::Protocol(_)) => {
+                let reply = resp::Value::Error(format!("ERR {}", e));
+                resp::write_value(writer, &reply, session.version)?;
+                writer.flush()?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let reply = resp::execute(args, session, |request| {
+            match self.handle_as(login, request) {
+                Reply::Response(response) => response,
+                Reply::Events(_) => Response::Error("not supported over RESP".to_string()),
+            }
+        });
+        resp::write_value(writer, &reply, session.version)?;
+
+        if session.closed || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        Ok(!session.closed)
+    }
+
+    /// Serves a connection of the worker pool as long as requests keep coming. Returns
+    /// the connection once it has been idle for `LINGER`, so the worker can serve
+    /// others while it waits, or None when it is over.
+    fn serve_until_idle(&self, connection: Connection) -> Result<Option<Connection>> {
+        let mut connection = match connection {
+            Connection::Accepted(socket) => {
+                if !self.wait_readable(&socket)? {
+                    return Ok(Some(Connection::Accepted(socket)));
+                }
+
+                match self.open(socket)? {
+                    Some(connection) => connection,
+                    None => return Ok(None),
+                }
+            }
+            Connection::Open(connection) => *connection,
+        };
+
+        loop {
+            if !connection.has_pending() && !self.wait_readable(connection.stream.socket())? {
+                return Ok(Some(Connection::Open(Box::new(connection))));
+            }
+
+            if !self.serve_next(&mut connection)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Waits up to `LINGER` for something to read, the end of the stream included.
+    fn wait_readable(&self, socket: &TcpStream) -> Result<bool> {
+        socket.set_read_timeout(Some(LINGER))?;
+        let res = socket.peek(&mut [0]);
+        socket.set_read_timeout(self.idle_timeout())?;
+
+        match res {
+            Err(e) if is_timeout(&e) => Ok(false),
+            // Errors show up on the next read
+            _ => Ok(true),
+        }
+    }
+
+    /// A command of the text protocol: words separated by spaces, in a single read.
+    /// There is no way to log in first, so once users are configured every command is
+    /// refused.
+    fn serve_text(&self, buf: &[u8], stream: &mut Stream) -> Result<()> {
+        // Closed without sending anything
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let words = match stream_to_vec(buf) {
+            Ok(words) => words,
+            Err(e) => return Ok(stream.write_all(format!("Error: {}", e).as_bytes())?),
+        };
+
+        if words[0].eq_ignore_ascii_case("sync") {
+            if !self.config().users.is_empty() {
+                let e = KiviError::Unauthorized(AUTH_REQUIRED.to_string());
+                return Ok(stream.write_all(format!("Error: {}", e).as_bytes())?);
+            }
+
+            let after = match words.get(1).map(|w| w.parse::<u64>()) {
+                Some(Ok(seq)) => Some(seq),
+                Some(Err(_)) => return Ok(stream.write_all(b"Error: invalid sequence number")?),
+                None => None,
+            };
+
+            // Replication reads acks while it writes, which a TLS session cannot do
+            return match stream {
+                Stream::Plain(socket) => self.sync(socket, after),
+                #[cfg(feature = "tls")]
+                Stream::Tls { .. } => {
+                    Ok(stream.write_all(b"Error: replicas cannot sync over TLS")?)
+                }
+            };
+        }
+
+        let request = match Request::parse(&words) {
+            Ok(request) => request,
+            Err(e) => return Ok(stream.write_all(format!("Error: {}", e).as_bytes())?),
+        };
+
+        match self.handle_as(&mut Login::default(), request.clone()) {
+            Reply::Response(response) => {
+                stream.write_all(text_response(&request, response).as_bytes())?;
+            }
+            Reply::Events(subscription) => stream_events(stream, subscription, false)?,
+        }
+
+        Ok(())
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        Some(self.config().idle_timeout).filter(|t| !t.is_zero())
+    }
+
+    /// Runs a request of a client connection logged in as `login`, once the users of
+    /// the config allow it.
+    fn handle_as(&self, login: &mut Login, request: Request) -> Reply {
+        let users = self.config().users;
+
+        if let Request::Auth { user, secret } = &request {
+            return Reply::Response(login.authenticate(&users, user.as_deref(), secret));
+        }
+
+        let user = match login.authorize(&users, &request) {
+            Ok(user) => user,
+            Err(response) => return Reply::Response(response),
+        };
+
+        match (self.handle(request), user) {
+            (Reply::Response(Response::Pairs(pairs)), Some(user)) => {
+                Reply::Response(Response::Pairs(
+                    pairs
+                        .into_iter()
+                        .filter(|kv| user.can_access(&kv.key))
+                        .collect(),
+                ))
+            }
+            (reply, _) => reply,
+        }
+    }
+
+    /// Runs a request, whichever protocol it came from.
+    fn handle(&self, request: Request) -> Reply {
+        if request.is_write() {
+            if let Role::Replica { primary } = &self.role {
+                return Reply::Response(Response::Error(format!(
+                    "read-only replica of {}",
+                    primary
+                )));
+            }
+        }
+
+        // Has to happen before locking the engine, the node locks it while applying
+        if let Role::Cluster { node } = &self.role {
+            if let Some(response) = cluster_response(node, &request) {
+                return Reply::Response(response);
+            }
+        }
+
+        match self.execute(request) {
+            Ok(reply) => reply,
+            Err(e) => Reply::Response(error_response(e)),
+        }
+    }
+
+    fn execute(&self, request: Request) -> Result<Reply> {
+        let mut engine = lock_engine(&self.engine)?;
+
+        let response = match request {
+            Request::Get { key } => match engine.get(key) {
+                Some(item) => Response::Value(item.value),
+                None => Response::NotFound,
+            },
+            Request::Set { key, value } => {
+                engine.set(key, value)?;
+
+                Response::Ok
+            }
+            Request::Delete { key } => {
+                engine.delete(key)?;
+
+                Response::Ok
+            }
+            Request::Scan { prefix } => Response::Pairs(engine.scan(&prefix)?),
+            Request::CompareAndSwap { key, expected, new } => {
+                Response::Applied(engine.compare_and_swap(key, expected, new)?)
+            }
+            Request::SetIfAbsent { key, value } => {
+                Response::Applied(engine.set_if_absent(key, value)?)
+            }
+            Request::DeleteIfEquals { key, expected } => {
+                Response::Applied(engine.delete_if_equals(key, expected)?)
+            }
+            Request::IncrBy { key, delta } => Response::Integer(engine.incr_by(key, delta)?),
+            Request::Expire { key, seconds } => {
+                Response::Applied(engine.expire(key, Duration::from_secs(seconds))?)
+            }
+            Request::Ttl { key } => Response::Integer(match engine.ttl(&key)? {
+                // Rounded like Redis does
+                Some(ttl) => ((ttl.as_millis() + 500) / 1000) as i64,
+                None => -1,
+            }),
+            Request::Subscribe { from_seq } => {
+                let subscription = match from_seq {
+                    Some(seq) => engine.subscribe_from(seq)?,
+                    None => engine.subscribe(),
+                };
+
+                return Ok(Reply::Events(subscription));
+            }
+            Request::Watch { pattern } => {
+                return Ok(Reply::Events(engine.watch(KeyPattern::parse(&pattern))));
+            }
+            Request::Backup { dir } => {
+                let dir = self.backup_dir(&dir)?;
+                let prepared = backup::prepare(&mut engine)?;
+
+                // Writes go on while the pinned files are copied
+                drop(engine);
+
+                let manifest = prepared.write_to(&dir)?;
+
+                Response::Text(format!(
+                    "Backup: {} files, last seq {}",
+                    manifest.files.len(),
+                    manifest.last_seq
+                ))
+            }
+            Request::Compact => {
+                engine.compact()?;
+
+                Response::Ok
+            }
+            Request::Role => {
+                let role = match &self.role {
+                    Role::Primary { replicas } => {
+                        let mut res = format!("primary, last seq {}", engine.last_seq());
+
+                        for (addr, offset) in replication::lock_offsets(replicas)?.iter() {
+                            res.push_str(&format!("\nreplica {} at {}", addr, offset));
+                        }
+
+                        res
+                    }
+                    Role::Replica { primary } => {
+                        format!("replica of {}, last seq {}", primary, engine.last_seq())
+                    }
+                    Role::Cluster { node: _ } => unreachable!("answered by cluster_response"),
+                };
+
+                Response::Text(role)
+            }
+            Request::Info => Response::Text(engine.stats()?.to_string()),
+            Request::Auth { .. } => unreachable!("answered by handle_as"),
+        };
+
+        Ok(Reply::Response(response))
+    }
+
+    /// Where a backup asked by a client goes: `dir` must be a relative path under the
+    /// backup root, without `..`, so clients cannot write anywhere else.
+    fn backup_dir(&self, dir: &str) -> Result<PathBuf> {
+        let root = self.config().backup_root.ok_or_else(|| {
+            KiviError::Generic("backups are disabled, the server has no backup root".to_string())
+        })?;
+
+        let dir = Path::new(dir);
+        let relative = dir.components().all(|c| matches!(c, Component::Normal(_)));
+        if dir.as_os_str().is_empty() || !relative {
+            return Err(KiviError::Generic(
+                "backup directory must be a relative path under the backup root".to_string(),
+            ));
+        }
+
+        Ok(root.join(dir))
+    }
+
+    /// Streams a snapshot then every write to a replica, or only the writes after
+    /// `after` to a replica that reconnects. Only part of the text protocol.
+    fn sync(&self, stream: &mut TcpStream, after: Option<u64>) -> Result<()> {
+        match &self.role {
+            Role::Primary { replicas } => {
+                // Acks of the replica are read from this socket, however long it waits
+                stream.set_read_timeout(None)?;
+
+                replication::serve_replica(stream, &self.engine, replicas.clone(), after)
+            }
+            Role::Replica { primary: _ } => {
+                Ok(stream.write_all(b"Error: replicas cannot be synced from")?)
+            }
+            Role::Cluster { node: _ } => {
+                Ok(stream.write_all(b"Error: cluster nodes cannot be synced from")?)
+            }
+        }
+    }
+}
+
+/// A connection waiting in the queue of the worker pool.
+enum Connection {
+    /// Nothing read from it yet
+    Accepted(TcpStream),
+
+    /// Served before, it went idle between two requests
+    Open(Box<OpenConnection>),
+}
+
+impl Connection {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Connection::Accepted(socket) => socket,
+            Connection::Open(connection) => connection.stream.socket(),
+        }
+    }
+}
+
+/// A connection of the binary or RESP protocol, with what it keeps between requests.
+struct OpenConnection {
+    stream: Stream,
+    reader: BufReader<Chain<Cursor<Vec<u8>>, Stream>>,
+    writer: BufWriter<Stream>,
+    login: Login,
+
+    /// Set for RESP connections
+    resp: Option<resp::Session>,
+}
+
+impl OpenConnection {
+    /// Returns true if some of the next request was already received: in the first
+    /// bytes read, in the read buffer or in the TLS session.
+    fn has_pending(&self) -> bool {
+        let (head, _) = self.reader.get_ref().get_ref();
+
+        !self.reader.buffer().is_empty()
+            || head.position() < head.get_ref().len() as u64
+            || self.stream.has_buffered()
+    }
+}
+
+/// What a worker is asked to do.
+enum Job {
+    /// Serve a connection just accepted
+    Accept(TcpStream),
+
+    /// Serve a parked connection that has a request to read
+    Resume(u64, Connection),
+}
+
+/// Fixed set of threads serving connections, in the order they arrive. A connection
+/// only holds a worker while it sends requests: once idle it is parked with a watcher
+/// thread, which hands it back to the workers when its next request comes in.
+struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    fn start(server: &KiviServer, size: usize) -> Result<Self> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (parker, parked) = channel::<(u64, Connection)>();
+
+        let mut workers = Vec::new();
+        for i in 0..size.max(1) {
+            let server = server.clone();
+            let receiver = receiver.clone();
+            let parker = parker.clone();
+
+            let worker = std::thread::Builder::new()
+                .name(format!("kivi-worker-{}", i))
+                .spawn(move || loop {
+                    // The lock is only held while waiting, not while serving
+                    let job = match receiver.lock() {
+                        Ok(r) => r.recv(),
+                        Err(_) => break,
+                    };
+
+                    let (id, connection) = match job {
+                        Ok(Job::Accept(stream)) => match server.shared.register(&stream) {
+                            Some(id) => (id, Connection::Accepted(stream)),
+                            // Connections still queued at shutdown are closed unanswered
+                            None => continue,
+                        },
+                        Ok(Job::Resume(id, connection)) => (id, connection),
+                        Err(_) => break,
+                    };
+
+                    match server.serve_until_idle(connection) {
+                        Ok(Some(connection)) => {
+                            if parker.send((id, connection)).is_err() {
+                                server.shared.unregister(id);
+                            }
+                        }
+                        res => {
+                            log_served(res.map(|_| ()));
+                            server.shared.unregister(id);
+                        }
+                    }
+                })?;
+            workers.push(worker);
+        }
+
+        let watcher = {
+            let server = server.clone();
+            let sender = sender.clone();
+
+            std::thread::Builder::new()
+                .name("kivi-idle-watcher".to_string())
+                .spawn(move || watch_idle(&server, &parked, &sender))?
+        };
+
+        Ok(Self {
+            sender: Some(sender),
+            workers,
+            watcher: Some(watcher),
+        })
+    }
+
+    fn execute(&self, stream: TcpStream) {
+        if let Some(sender) = &self.sender {
+            if sender.send(Job::Accept(stream)).is_err() {
+                log::error!("Error: no worker left to serve the connection");
+            }
+        }
+    }
+
+    /// Lets the workers exit once the queue is empty, returns whether they all did
+    /// before the deadline.
+    fn finish(&mut self, deadline: Instant) -> bool {
+        drop(self.sender.take());
+
+        while Instant::now() < deadline {
+            if self.workers.iter().all(|w| w.is_finished()) {
+                return true;
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        self.workers.iter().all(|w| w.is_finished())
+    }
+}
+
+/// Lets the workers finish their connections.
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..).chain(self.watcher.take()) {
+            if worker.join().is_err() {
+                log::error!("Error: worker thread panicked");
+            }
+        }
+    }
+}
+
+/// Keeps the idle connections of the worker pool until they become readable, then
+/// queues them for a worker. Closes the ones idle for longer than the idle timeout,
+/// and all of them once the server stops.
+fn watch_idle(server: &KiviServer, parked: &Receiver<(u64, Connection)>, pool: &Sender<Job>) {
+    let mut idle: Vec<(u64, Connection, Instant)> = Vec::new();
+
+    loop {
+        for (id, connection) in parked.try_iter() {
+            match connection.socket().set_nonblocking(true) {
+                Ok(()) => idle.push((id, connection, Instant::now())),
+                Err(e) => {
+                    log::error!("Error: {}", e);
+                    server.shared.unregister(id);
+                }
+            }
+        }
+
+        if server.shared.is_stopping() {
+            for (id, _, _) in idle.drain(..) {
+                server.shared.unregister(id);
+            }
+            return;
+        }
+
+        let timeout = server.idle_timeout();
+
+        for (id, connection, since) in std::mem::take(&mut idle) {
+            match connection.socket().peek(&mut [0]) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if timeout.is_some_and(|t| since.elapsed() >= t) {
+                        log::debug!("Closing idle connection");
+                        server.shared.unregister(id);
+                    } else {
+                        idle.push((id, connection, since));
+                    }
+                }
+                // A request, the end of the stream or an error, the worker reads it
+                _ => {
+                    let resumed = connection.socket().set_nonblocking(false).is_ok()
+                        && pool.send(Job::Resume(id, connection)).is_ok();
+
+                    if !resumed {
+                        server.shared.unregister(id);
+                    }
+                }
+            }
+        }
+
+        std::thread::sleep(IDLE_POLL_INTERVAL);
+    }
+}
+
+/// Answers the requests cluster nodes handle differently. Returns None for requests
+/// served from the local store.
+///
+/// - Sets and deletes go through Raft, on the leader only.
+/// - Conditional writes, increments and expiries are refused: their outcome depends on
+///   the store they run against, and only plain commands are replayed on every node.
+/// - Reads are only served by a leader that confirms it still leads and has applied
+///   everything committed, so they never miss an acknowledged write.
+/// - Subscriptions, watches, backups, compactions and the rest concern the local node.
+///
+/// Followers redirect whatever the leader has to serve.
+fn cluster_response(node: &RaftNode, request: &Request) -> Option<Response> {
+    match request {
+        Request::Set { key, value } => {
+            let res = node.propose(KiviCommand::Set {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: key.clone(),
+                value: value.clone(),
+            });
+
+            Some(res.map(|_| Response::Ok).unwrap_or_else(error_response))
+        }
+        Request::Delete { key } => {
+            let res = node.propose(KiviCommand::Delete {
+                bucket: DEFAULT_BUCKET.to_string(),
+                key: key.clone(),
+            });
+
+            Some(res.map(|_| Response::Ok).unwrap_or_else(error_response))
+        }
+        Request::CompareAndSwap { .. }
+        | Request::SetIfAbsent { .. }
+        | Request::DeleteIfEquals { .. }
+        | Request::IncrBy { .. }
+        | Request::Expire { .. } => {
+            Some(Response::Error("not supported in cluster mode".to_string()))
+        }
+        Request::Get { .. } | Request::Scan { .. } | Request::Ttl { .. } => {
+            node.read_index().err().map(error_response)
+        }
+        Request::Role => Some(match node.status() {
+            Ok(status) => {
+                let role = match status.role {
+                    NodeRole::Leader => "leader",
+                    NodeRole::Candidate => "candidate",
+                    NodeRole::Follower => "follower",
+                };
+
+                Response::Text(format!(
+                    "cluster node {}, {} of term {}, commit index {}",
+                    status.id, role, status.term, status.commit_index
+                ))
+            }
+            Err(e) => error_response(e),
+        }),
+        Request::Subscribe { .. }
+        | Request::Watch { .. }
+        | Request::Backup { .. }
+        | Request::Compact
+        | Request::Info
+        | Request::Auth { .. } => None,
+    }
+}
+
+/// Renders a response the way the text protocol always answered.
+fn text_response(request: &Request, response: Response) -> String {
+    match response {
+        Response::Ok => "OK".to_string(),
+        Response::Value(value) => match request {
+            Request::Get { key } => format!("Key: {}, Value: {}", key, value),
+            _ => value,
+        },
+        Response::Integer(value) => format!("Value: {}", value),
+        Response::Applied(applied) => format!("Applied: {}", applied),
+        Response::Pairs(pairs) => pairs
+            .iter()
+            .map(|kv| format!("Key: {}, Value: {}", kv.key, kv.value))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        Response::Text(text) => text,
+        Response::Event(event) => serde_json::to_string(&event).unwrap_or_default(),
+        Response::NotFound => match request {
+            Request::Get { key } | Request::Delete { key } | Request::Ttl { key } => {
+                format!("Error: {}", KiviError::KeyNotFound(key.clone()))
+            }
+            _ => "Error: not found".to_string(),
+        },
+        Response::Error(message) => format!("Error: {}", message),
+        Response::Redirect(addr) => format!("Redirect: {}", addr),
+        Response::Unauthorized(message) => {
+            format!("Error: {}", KiviError::Unauthorized(message))
+        }
+    }
+}
+
+/// How a failed request is answered, whichever protocol it came from.
+fn error_response(e: KiviError) -> Response {
+    match e {
+        KiviError::KeyNotFound(_) => Response::NotFound,
+        KiviError::NotLeader(Some(leader)) => Response::Redirect(leader),
+        KiviError::NotLeader(None) => Response::Error("no leader elected".to_string()),
+        e => Response::Error(error_message(&e)),
+    }
+}
+
+/// `KiviError::Generic` already starts with `Error: `, which responses add themselves.
+fn error_message(e: &KiviError) -> String {
+    match e {
+        KiviError::Generic(message) => message.clone(),
+        e => e.to_string(),
+    }
+}
+
+pub(crate) fn lock_engine(engine: &Engine) -> Result<MutexGuard<'_, KiviStore>> {
+    engine
+        .lock()
+        .map_err(|_| KiviError::Generic("engine lock poisoned".to_string()))
+}
+
+/// Keeps the connection open and writes every event, as a line of JSON for text
+/// clients and as a frame for binary ones. This runs on its own thread, so other
+/// clients keep being served and writers never wait for slow readers. Subscribers that
+/// stop reading are dropped, by the write timeout or once their queue is full.
+fn stream_events(stream: &Stream, subscription: Subscription, binary: bool) -> Result<()> {
+    let mut out = stream.try_clone()?;
+    out.socket()
+        .set_write_timeout(Some(SUBSCRIBER_WRITE_TIMEOUT))?;
+
+    std::thread::spawn(move || loop {
+        // A subscriber may leave while no event comes, which has to end the
+        // subscription as well
+        let event = match subscription.recv_timeout(SUBSCRIBER_CHECK_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if subscriber_left(out.socket()) => {
+                log::info!("Subscriber disconnected");
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        let res = match binary {
+            true => codec::write_response(&mut out, &Response::Event(event)),
+            false => serde_json::to_string(&event)
+                .map_err(KiviError::from)
+                .and_then(|line| Ok(writeln!(out, "{}", line)?)),
+        };
+
+        if let Err(e) = res {
+            log::info!("Subscriber disconnected: {}", e);
+            break;
+        }
+    });
+
+    Ok(())
+}
+
+/// Subscribers send nothing once subscribed, so anything to read, or the end of the
+/// stream, means they are gone.
+fn subscriber_left(socket: &TcpStream) -> bool {
+    if socket.set_nonblocking(true).is_err() {
+        return true;
+    }
+
+    let idle = matches!(socket.peek(&mut [0]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+
+    socket.set_nonblocking(false).is_err() || !idle
+}
+
+/// Reads the first bytes of a connection, at least enough to tell the handshake of a
+/// binary client from a text command.
+fn read_head(stream: &mut Stream) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+
+    loop {
+        let bytes_read = stream.read(&mut buf)?;
+        head.extend_from_slice(&buf[..bytes_read]);
+
+        if bytes_read == 0 || head.len() >= codec::MAGIC.len() || !codec::MAGIC.starts_with(&head) {
+            return Ok(head);
+        }
+    }
+}
+
+/// Errors ending a connection are only worth a debug line for idle ones.
+fn log_served(res: Result<()>) {
+    match res {
+        Err(KiviError::Io(e)) if is_timeout(&e) => log::debug!("Closing idle connection"),
+        // TLS clients that leave without saying so
+        Err(KiviError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            log::debug!("Connection closed by the client")
+        }
+        Err(e) => log::error!("Error: {}", e),
+        Ok(()) => {}
+    }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+fn stream_to_vec(buf: &[u8]) -> Result<Vec<String>> {
+    let s = str::from_utf8(buf)
+        .map_err(|_| KiviError::Protocol("command is not valid UTF-8".to_string()))?;
+
+    let v = s.split(' ').collect::<Vec<&str>>();
+
+    Ok(v.iter().map(|x| x.to_string()).collect::<Vec<String>>())
+}
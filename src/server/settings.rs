@@ -0,0 +1,379 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::core::{
+    config::{Config, SyncPolicy},
+    crypto::EncryptionKey,
+    error::Result,
+};
+use crate::server::{ServerConfig, User};
+#[cfg(feature = "tls")]
+use crate::{core::error::KiviError, tls::ServerTls};
+
+/// Settings of the server binary. Read from a TOML file where every key is optional,
+/// for example:
+///
+/// ```toml
+/// addr = "127.0.0.1:7878"
+/// db_path = "/var/lib/kivi"
+/// sync_policy = "1s"
+/// workers = 8
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// Address to listen on
+    pub addr: String,
+
+    /// Directory of the store
+    pub db_path: PathBuf,
+
+    /// Extension of the data files
+    pub data_extension: String,
+
+    /// Size in bytes after which the active file is rotated
+    pub max_file_size: u64,
+
+    /// When writes are flushed to disk
+    pub sync_policy: SyncPolicy,
+
+    /// Number of connections served at the same time
+    pub workers: usize,
+
+    /// Seconds after which idle connections are closed, 0 to keep them open
+    pub idle_timeout: u64,
+
+    /// Largest request accepted, in bytes
+    pub max_request_size: usize,
+
+    /// Seconds a shutdown waits for connections to finish their request
+    pub shutdown_timeout: u64,
+
+    /// Filter of the logger, like `info` or `kivi=debug`
+    pub log_level: String,
+
+    /// Directory the `backup` command writes under, backups over the network are
+    /// disabled without it
+    pub backup_root: Option<PathBuf>,
+
+    /// File with the key the store is encrypted with, as 32 raw bytes or 64 hex
+    /// characters
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// Files with keys that are only used to read records, until compaction
+    /// re-encrypts them with the current key
+    pub previous_encryption_key_files: Vec<PathBuf>,
+
+    /// PEM file of the certificate chain of the server, connections use TLS when set
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of `tls_cert`
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file of the authority clients need a certificate from, for mutual TLS
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Users allowed to connect, as `[[users]]` tables. Everyone may run anything
+    /// when there are none.
+    pub users: Vec<User>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        let store = Config::default();
+        let server = ServerConfig::default();
+
+        Self {
+            addr: "0.0.0.0:7878".to_string(),
+            db_path: store.get_db_path().clone(),
+            data_extension: store.get_data_extension().clone(),
+            max_file_size: store.get_max_file_size(),
+            sync_policy: store.get_sync_policy(),
+            workers: server.workers,
+            idle_timeout: server.idle_timeout.as_secs(),
+            max_request_size: server.max_request_size,
+            shutdown_timeout: server.shutdown_timeout.as_secs(),
+            log_level: "info".to_string(),
+            backup_root: server.backup_root,
+            encryption_key_file: None,
+            previous_encryption_key_files: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            users: server.users,
+        }
+    }
+}
+
+impl ServerSettings {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut settings: Self = toml::from_str(s)?;
+        for user in &mut settings.users {
+            user.normalize()?;
+        }
+
+        Ok(settings)
+    }
+
+    /// Configuration of the store opened by the server. Fails if a key file can't be
+    /// read.
+    pub fn store_config(&self) -> Result<Config> {
+        let mut config = Config::new();
+        config
+            .set_db_path(self.db_path.clone())
+            .set_data_extension(self.data_extension.clone())
+            .set_max_file_size(self.max_file_size)
+            .set_sync_policy(self.sync_policy);
+
+        if let Some(path) = &self.encryption_key_file {
+            config.set_encryption_key(EncryptionKey::from_file(path)?);
+        }
+        config.set_previous_encryption_keys(
+            self.previous_encryption_key_files
+                .iter()
+                .map(EncryptionKey::from_file)
+                .collect::<Result<_>>()?,
+        );
+
+        Ok(config.build())
+    }
+
+    /// TLS of the server, None when no certificate is set.
+    #[cfg(feature = "tls")]
+    pub fn server_tls(&self) -> Result<Option<ServerTls>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(ServerTls::from_pem_files(
+                cert,
+                key,
+                self.tls_client_ca.as_deref(),
+            )?)),
+            (None, None) if self.tls_client_ca.is_none() => Ok(None),
+            _ => Err(KiviError::Tls(
+                "tls_cert and tls_key must be set together, and with tls_client_ca".to_string(),
+            )),
+        }
+    }
+
+    /// Returns these settings with the ones that can change while running taken from
+    /// `new`: the sync policy, connection limits, shutdown timeout, backup root and
+    /// users. Everything else keeps its current value until a restart.
+    pub fn reloaded(&self, new: &ServerSettings) -> ServerSettings {
+        ServerSettings {
+            sync_policy: new.sync_policy,
+            idle_timeout: new.idle_timeout,
+            max_request_size: new.max_request_size,
+            shutdown_timeout: new.shutdown_timeout,
+            backup_root: new.backup_root.clone(),
+            users: new.users.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Names of the settings that differ in `new` but only change with a restart.
+    pub fn restart_only_changes(&self, new: &ServerSettings) -> Vec<&'static str> {
+        let reloaded = self.reloaded(new);
+
+        [
+            ("addr", reloaded.addr != new.addr),
+            ("db_path", reloaded.db_path != new.db_path),
+            (
+                "data_extension",
+                reloaded.data_extension != new.data_extension,
+            ),
+            ("max_file_size", reloaded.max_file_size != new.max_file_size),
+            ("workers", reloaded.workers != new.workers),
+            ("log_level", reloaded.log_level != new.log_level),
+            (
+                "encryption_key_file",
+                reloaded.encryption_key_file != new.encryption_key_file,
+            ),
+            (
+                "previous_encryption_key_files",
+                reloaded.previous_encryption_key_files != new.previous_encryption_key_files,
+            ),
+            ("tls_cert", reloaded.tls_cert != new.tls_cert),
+            ("tls_key", reloaded.tls_key != new.tls_key),
+            ("tls_client_ca", reloaded.tls_client_ca != new.tls_client_ca),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            workers: self.workers,
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            max_request_size: self.max_request_size,
+            shutdown_timeout: Duration::from_secs(self.shutdown_timeout),
+            users: self.users.clone(),
+            backup_root: self.backup_root.clone(),
+        }
+    }
+}
+
+/// Renders the settings as a TOML file that `ServerSettings::parse` reads back.
+impl fmt::Display for ServerSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", toml::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_partial() {
+        let s = ServerSettings::parse(
+            r#"
+            addr = "127.0.0.1:9000"
+            db_path = "/tmp/kivi"
+            sync_policy = "200ms"
+            workers = 4
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(s.addr, "127.0.0.1:9000");
+        assert_eq!(s.workers, 4);
+        assert_eq!(s.sync_policy, SyncPolicy::Every(Duration::from_millis(200)));
+
+        // Missing keys keep their defaults
+        assert_eq!(s.data_extension, "log");
+        assert_eq!(s.idle_timeout, 300);
+
+        let c = s.store_config().unwrap();
+        assert_eq!(c.get_db_path(), &PathBuf::from("/tmp/kivi"));
+        assert_eq!(c.get_sync_policy(), s.sync_policy);
+        assert_eq!(s.server_config().workers, 4);
+    }
+
+    #[test]
+    fn test_parse_users() {
+        let s = ServerSettings::parse(
+            r#"
+            addr = "127.0.0.1:9000"
+
+            [[users]]
+            name = "admin"
+            password_sha256 = "abc"
+
+            [[users]]
+            name = "reports"
+            token_sha256 = "def"
+            read_only = true
+            denied_commands = ["compact"]
+            key_prefixes = ["report:"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(s.users.len(), 2);
+        assert_eq!(s.users[1].key_prefixes, vec!["report:"]);
+        assert!(s.users[1].read_only);
+        assert_eq!(s.server_config().users, s.users);
+        assert_eq!(ServerSettings::parse(&s.to_string()).unwrap(), s);
+
+        assert!(ServerSettings::parse("[[users]]\nname = \"a\"\nadmin = true").is_err());
+        assert!(
+            ServerSettings::parse("[[users]]\nname = \"a\"\ndenied_commands = [\"flushall\"]")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_tls() {
+        let s = ServerSettings::parse(
+            r#"
+            tls_cert = "/etc/kivi/cert.pem"
+            tls_key = "/etc/kivi/key.pem"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(s.tls_cert, Some(PathBuf::from("/etc/kivi/cert.pem")));
+        assert_eq!(s.tls_client_ca, None);
+        assert_eq!(ServerSettings::parse(&s.to_string()).unwrap(), s);
+
+        #[cfg(feature = "tls")]
+        {
+            assert!(ServerSettings::default().server_tls().unwrap().is_none());
+
+            let key_only = ServerSettings {
+                tls_cert: None,
+                ..s
+            };
+            assert!(key_only.server_tls().is_err());
+        }
+    }
+
+    #[test]
+    fn test_encryption_key_files() {
+        let dir = tempdir::TempDir::new("settings").unwrap();
+        let key_file = dir.path().join("kivi.key");
+        std::fs::write(&key_file, "ab".repeat(32)).unwrap();
+
+        let s = ServerSettings::parse(&format!(
+            "encryption_key_file = {:?}\nprevious_encryption_key_files = [{:?}]",
+            key_file, key_file
+        ))
+        .unwrap();
+        assert_eq!(s.encryption_key_file, Some(key_file.clone()));
+        assert!(s.store_config().unwrap().get_keyring().is_enabled());
+        assert_eq!(ServerSettings::parse(&s.to_string()).unwrap(), s);
+
+        let missing = ServerSettings {
+            encryption_key_file: Some(dir.path().join("missing.key")),
+            ..Default::default()
+        };
+        assert!(missing.store_config().is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ServerSettings::parse("port = 7878").is_err());
+        assert!(ServerSettings::parse("sync_policy = \"sometimes\"").is_err());
+        assert!(ServerSettings::parse("workers = \"many\"").is_err());
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let s = ServerSettings {
+            sync_policy: SyncPolicy::Always,
+            max_file_size: 1024,
+            ..Default::default()
+        };
+
+        assert_eq!(ServerSettings::parse(&s.to_string()).unwrap(), s);
+    }
+
+    #[test]
+    fn test_reloaded() {
+        let old = ServerSettings::default();
+        let new = ServerSettings {
+            addr: "127.0.0.1:9999".to_string(),
+            workers: 2,
+            idle_timeout: 5,
+            sync_policy: SyncPolicy::Always,
+            ..Default::default()
+        };
+
+        let reloaded = old.reloaded(&new);
+        assert_eq!(reloaded.addr, old.addr);
+        assert_eq!(reloaded.workers, old.workers);
+        assert_eq!(reloaded.idle_timeout, 5);
+        assert_eq!(reloaded.sync_policy, SyncPolicy::Always);
+
+        assert_eq!(old.restart_only_changes(&new), vec!["addr", "workers"]);
+        assert!(old.restart_only_changes(&reloaded).is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+//! Connections between clients and servers, in the clear or over TLS.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(feature = "tls")]
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Both ends of a TLS session, which reads and writes through the same state.
+#[cfg(feature = "tls")]
+pub trait Session: Read + Write + Send {
+    /// Returns true if data already received can be read without waiting for the
+    /// socket.
+    fn has_buffered(&mut self) -> bool;
+}
+
+/// A connection of the client or the server. Clones share it, like clones of a
+/// `TcpStream` do. A TLS session is locked while reading, so its clones must not read
+/// and write at the same time.
+pub enum Stream {
+    Plain(TcpStream),
+
+    #[cfg(feature = "tls")]
+    Tls {
+        socket: TcpStream,
+        session: Arc<Mutex<Box<dyn Session>>>,
+    },
+}
+
+impl Stream {
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls<S: Session + 'static>(socket: TcpStream, session: S) -> Self {
+        Stream::Tls {
+            socket,
+            session: Arc::new(Mutex::new(Box::new(session))),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Plain(s) => Ok(Stream::Plain(s.try_clone()?)),
+            #[cfg(feature = "tls")]
+            Stream::Tls { socket, session } => Ok(Stream::Tls {
+                socket: socket.try_clone()?,
+                session: session.clone(),
+            }),
+        }
+    }
+
+    /// The TCP connection underneath, for timeouts and addresses.
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            #[cfg(feature = "tls")]
+            Stream::Tls { socket, .. } => socket,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, Stream::Plain(_))
+    }
+
+    /// Returns true if a TLS session holds received data the socket no longer shows.
+    pub(crate) fn has_buffered(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            #[cfg(feature = "tls")]
+            Stream::Tls { session, .. } => lock(session).map_or(true, |mut s| s.has_buffered()),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn lock(session: &Mutex<Box<dyn Session>>) -> io::Result<MutexGuard<'_, Box<dyn Session>>> {
+    session
+        .lock()
+        .map_err(|_| io::Error::other("TLS session lock poisoned"))
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls { session, .. } => lock(session)?.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls { session, .. } => lock(session)?.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls { session, .. } => lock(session)?.flush(),
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! TLS for the connections of `KiviServer` and `KiviClient`, with rustls. Certificates
+//! and keys are read from PEM files, like the ones `openssl` or `certbot` write.
+
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig};
+use rustls::{ServerConnection, Stream as TlsStream};
+
+use crate::core::error::{KiviError, Result};
+use crate::stream::Stream;
+
+/// TLS of a server: its certificate chain and key, and for mutual TLS the authority
+/// that signed the certificates of the clients.
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Without `client_ca` any client may connect. With it, clients must present a
+    /// certificate it signed.
+    pub fn from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> Result<Self> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+
+        let builder = match client_ca {
+            Some(ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider())
+                        .build()
+                        .map_err(tls_error)?;
+
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(certs(cert)?, private_key(key)?)
+            .map_err(tls_error)?;
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn from_pem_files(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let client_ca = client_ca.map(fs::read).transpose()?;
+
+        Self::from_pem(&fs::read(cert)?, &fs::read(key)?, client_ca.as_deref())
+    }
+
+    /// Runs the handshake of an accepted connection, within its read timeout.
+    pub fn accept(&self, socket: TcpStream) -> Result<Stream> {
+        let conn = ServerConnection::new(self.config.clone()).map_err(tls_error)?;
+
+        Session::start(conn.into(), socket)
+    }
+}
+
+/// TLS of a client: the authority that signed the certificate of the server, and for
+/// mutual TLS the certificate chain and key of the client.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    /// `identity` is the certificate chain and key of the client, both as PEM.
+    pub fn from_pem(ca: &[u8], identity: Option<(&[u8], &[u8])>) -> Result<Self> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots(ca)?);
+
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(certs(cert)?, private_key(key)?)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    pub fn from_pem_files(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Self> {
+        let identity = match identity {
+            Some((cert, key)) => Some((fs::read(cert)?, fs::read(key)?)),
+            None => None,
+        };
+
+        Self::from_pem(
+            &fs::read(ca)?,
+            identity.as_ref().map(|(c, k)| (c.as_slice(), k.as_slice())),
+        )
+    }
+
+    /// Runs the handshake over a connection to `addr`. The certificate of the server
+    /// must be valid for the host part of `addr`, a name or an IP address.
+    pub fn connect(&self, addr: &str, socket: TcpStream) -> Result<Stream> {
+        let conn =
+            ClientConnection::new(self.config.clone(), server_name(addr)?).map_err(tls_error)?;
+
+        Session::start(conn.into(), socket)
+    }
+}
+
+/// An established TLS connection. Dropping it tells the peer the connection is over,
+/// so it reads a clean end of stream.
+struct Session {
+    conn: Connection,
+    socket: TcpStream,
+}
+
+impl Session {
+    fn start(conn: Connection, socket: TcpStream) -> Result<Stream> {
+        let mut session = Session {
+            conn,
+            socket: socket.try_clone()?,
+        };
+
+        while session.conn.is_handshaking() {
+            session.conn.complete_io(&mut session.socket)?;
+        }
+
+        Ok(Stream::tls(socket, session))
+    }
+}
+
+impl crate::stream::Session for Session {
+    /// Decrypts what rustls already holds, a failure is left for the next read.
+    fn has_buffered(&mut self) -> bool {
+        self.conn
+            .process_new_packets()
+            .map_or(true, |state| state.plaintext_bytes_to_read() > 0)
+    }
+}
+
+impl Read for Session {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.conn {
+            Connection::Client(c) => TlsStream::new(c, &mut self.socket).read(buf),
+            Connection::Server(c) => TlsStream::new(c, &mut self.socket).read(buf),
+        }
+    }
+}
+
+impl Write for Session {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.conn {
+            Connection::Client(c) => TlsStream::new(c, &mut self.socket).write(buf),
+            Connection::Server(c) => TlsStream::new(c, &mut self.socket).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.conn {
+            Connection::Client(c) => TlsStream::new(c, &mut self.socket).flush(),
+            Connection::Server(c) => TlsStream::new(c, &mut self.socket).flush(),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.conn.send_close_notify();
+
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut self.socket).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem)).collect::<io::Result<Vec<_>>>()?;
+
+    match certs.is_empty() {
+        true => Err(KiviError::Tls("no certificate found in PEM".to_string())),
+        false => Ok(certs),
+    }
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(pem))?
+        .ok_or_else(|| KiviError::Tls("no private key found in PEM".to_string()))
+}
+
+fn roots(pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(pem)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+
+    Ok(roots)
+}
+
+/// Host part of `host:port`, `[::1]:port` for IPv6.
+fn server_name(addr: &str) -> Result<ServerName<'static>> {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(ServerName::IpAddress(ip.into()));
+    }
+
+    ServerName::try_from(host.to_string()).map_err(tls_error)
+}
+
+fn tls_error(e: impl Display) -> KiviError {
+    KiviError::Tls(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::KiviClient;
+    use crate::core::{config::Config, kv::KiviStore};
+    use crate::server::KiviServer;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use std::net::TcpListener;
+    use tempdir::TempDir;
+
+    /// A self-signed authority, which signs the certificates of servers and clients.
+    struct Authority {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new(name: &str) -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+
+            Self { cert, key }
+        }
+
+        fn pem(&self) -> Vec<u8> {
+            self.cert.pem().into_bytes()
+        }
+
+        /// Certificate and key, as PEM, valid for `names`.
+        fn issue(&self, common_name: &str, names: &[&str]) -> (Vec<u8>, Vec<u8>) {
+            let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+            let mut params = CertificateParams::new(names).unwrap();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+            (cert.pem().into_bytes(), key.serialize_pem().into_bytes())
+        }
+    }
+
+    fn server_tls(ca: &Authority, client_ca: Option<&Authority>) -> ServerTls {
+        let (cert, key) = ca.issue("kivi server", &["localhost", "127.0.0.1"]);
+        let client_ca = client_ca.map(|ca| ca.pem());
+
+        ServerTls::from_pem(&cert, &key, client_ca.as_deref()).unwrap()
+    }
+
+    /// Starts a server on a random port, the directory has to outlive it.
+    fn start_server(tls: ServerTls) -> (String, TempDir) {
+        let dir = TempDir::new("tls").unwrap();
+        let store =
+            KiviStore::with_config(Config::new().set_db_path(dir.path().to_path_buf()).build())
+                .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let mut server = KiviServer::with_store(store);
+        server.set_tls(tls);
+        std::thread::spawn(move || server.run_with_listener(listener));
+
+        (addr, dir)
+    }
+
+    fn client(addr: &str, tls: ClientTls) -> KiviClient {
+        let mut client = KiviClient::new(addr);
+        client.set_tls(tls);
+        client
+    }
+
+    #[test]
+    fn test_tls_connections() {
+        let ca = Authority::new("kivi test CA");
+        let (addr, _dir) = start_server(server_tls(&ca, None));
+
+        let secure = client(&addr, ClientTls::from_pem(&ca.pem(), None).unwrap());
+        secure.set("a", "1").unwrap();
+        assert_eq!(secure.get("a").unwrap().as_deref(), Some("1"));
+
+        // Text commands go over TLS as well, but replicas cannot sync
+        assert_eq!(secure.request("get a").unwrap(), "Key: a, Value: 1");
+        assert!(secure.request("sync").is_err());
+
+        // The name of the server is checked
+        let localhost = addr.replace("127.0.0.1", "localhost");
+        let by_name = client(&localhost, ClientTls::from_pem(&ca.pem(), None).unwrap());
+        assert_eq!(by_name.get("a").unwrap().as_deref(), Some("1"));
+
+        let (cert, key) = ca.issue("kivi server", &["example.com"]);
+        let (other_addr, _other_dir) =
+            start_server(ServerTls::from_pem(&cert, &key, None).unwrap());
+        let wrong_name = client(&other_addr, ClientTls::from_pem(&ca.pem(), None).unwrap());
+        assert!(matches!(wrong_name.get("a"), Err(KiviError::Io(_))));
+
+        // Certificates of another authority are not trusted
+        let other = Authority::new("other CA");
+        let untrusted = client(&addr, ClientTls::from_pem(&other.pem(), None).unwrap());
+        assert!(untrusted.get("a").is_err());
+
+        // Nor are clients speaking in the clear
+        assert!(KiviClient::new(&addr).get("a").is_err());
+        assert_eq!(secure.get("a").unwrap().as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let ca = Authority::new("kivi test CA");
+        let (addr, _dir) = start_server(server_tls(&ca, Some(&ca)));
+
+        let (cert, key) = ca.issue("kivi client", &["client"]);
+        let identified = client(
+            &addr,
+            ClientTls::from_pem(&ca.pem(), Some((&cert, &key))).unwrap(),
+        );
+        identified.set("a", "1").unwrap();
+        assert_eq!(identified.get("a").unwrap().as_deref(), Some("1"));
+
+        let anonymous = client(&addr, ClientTls::from_pem(&ca.pem(), None).unwrap());
+        assert!(anonymous.get("a").is_err());
+
+        let other = Authority::new("other CA");
+        let (cert, key) = other.issue("kivi client", &["client"]);
+        let untrusted = client(
+            &addr,
+            ClientTls::from_pem(&ca.pem(), Some((&cert, &key))).unwrap(),
+        );
+        assert!(untrusted.get("a").is_err());
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(
+            server_name("localhost:7878").unwrap(),
+            ServerName::try_from("localhost").unwrap()
+        );
+        assert_eq!(
+            server_name("127.0.0.1:7878").unwrap(),
+            ServerName::IpAddress(IpAddr::from([127, 0, 0, 1]).into())
+        );
+        assert!(matches!(
+            server_name("[::1]:7878").unwrap(),
+            ServerName::IpAddress(_)
+        ));
+        assert!(server_name("not a host:1").is_err());
+    }
+
+    #[test]
+    fn test_invalid_pem() {
+        assert!(matches!(
+            ServerTls::from_pem(b"", b"", None),
+            Err(KiviError::Tls(_))
+        ));
+        assert!(ClientTls::from_pem(b"not a certificate", None).is_err());
+    }
+}